@@ -47,6 +47,8 @@
 use craftping::{Chat as CraftPingChat, Player as CraftPingPlayer, Response as CraftPingResponse};
 use serde::{Deserialize, Serialize};
 
+use crate::probe::ProbeTiming;
+
 #[derive(Debug, Clone, Serialize)]
 /// A ping response returned from server.
 pub struct CCheckResponse {
@@ -67,6 +69,11 @@ pub struct CCheckResponse {
     pub description: CCheckComponent,
     /// The favicon of the server in PNG format.
     pub favicon: Option<Vec<u8>>,
+    /// When the server was probed and how long it took.
+    /// `None` until filled in by [`Prober::probe`](crate::probe::Prober::probe).
+    pub timing: Option<ProbeTiming>,
+    /// Whether the probe started with a PROXY protocol header
+    pub proxy_protocol: bool,
     // Disabled for now. Not currently interested in adding support for checking for forge protocol info
 
     // The mod information object used in FML protocol (version 1.7 - 1.12).
@@ -102,6 +109,8 @@ impl From<CraftPingResponse> for CCheckResponse {
             },
             description: res.description.into(),
             favicon: res.favicon,
+            timing: None,
+            proxy_protocol: false,
         }
     }
 }
@@ -115,6 +124,10 @@ pub struct CCheckPlayer {
     /// Normally used to identify a player.
     pub id: String,
 }
+/// Whether `uuid` is empty or the nil uuid, which servers use for sample lines that aren't players
+pub fn is_placeholder_uuid(uuid: &str) -> bool {
+    uuid.chars().all(|c| c == '0' || c == '-')
+}
 impl From<CraftPingPlayer> for CCheckPlayer {
     fn from(p: CraftPingPlayer) -> Self {
         Self {
@@ -154,6 +167,30 @@ pub struct CCheckComponent {
     /// They should inherit this chat component's properties (bold, italic, etc.) but can also override the properties.
     pub extra: Vec<CCheckComponent>,
 }
+impl CCheckComponent {
+    /// Flattens this component and its extras into plain text, dropping any
+    /// legacy `§` formatting codes.
+    pub fn to_plain(&self) -> String {
+        let mut out = String::new();
+        self.push_plain(&mut out);
+        let mut plain = String::with_capacity(out.len());
+        let mut chars = out.chars();
+        while let Some(c) = chars.next() {
+            if c == '§' {
+                chars.next();
+            } else {
+                plain.push(c);
+            }
+        }
+        plain
+    }
+    fn push_plain(&self, out: &mut String) {
+        out.push_str(&self.text);
+        for extra in &self.extra {
+            extra.push_plain(out);
+        }
+    }
+}
 impl From<CraftPingChat> for CCheckComponent {
     fn from(chat: CraftPingChat) -> Self {
         let mut new_extra: Vec<CCheckComponent> = vec![];
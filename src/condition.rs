@@ -13,9 +13,11 @@
 
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use std::fmt::Display;
+
 use regex::Regex;
 use serde::{Deserialize, Serialize};
-use strum_macros::EnumString;
+use strum_macros::{Display as StrumDisplay, EnumString};
 
 use crate::adapters::CCheckResponse;
 #[derive(Debug, Clone)]
@@ -33,6 +35,17 @@ impl Conditions {
         true
     }
 }
+/// All conditions joined with `, `, empty if there are none
+impl Display for Conditions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let conditions = self
+            .conditions
+            .iter()
+            .map(|c| c.to_string())
+            .collect::<Vec<String>>();
+        write!(f, "{}", conditions.join(", "))
+    }
+}
 #[derive(Debug, Clone)]
 
 pub struct Condition {
@@ -65,6 +78,9 @@ impl Condition {
                 if self.values.contains(&format!("{}", &resp.protocol)) {
                     return !is_match;
                 }
+                if self.check_numeric_match(resp.protocol as f64) {
+                    return !is_match;
+                }
             }
             Actor::ConnectedPlayers => {
                 if self.check_regex_match(&format!("{}", &resp.online_players)) {
@@ -73,6 +89,9 @@ impl Condition {
                 if self.values.contains(&format!("{}", &resp.online_players)) {
                     return !is_match;
                 }
+                if self.check_numeric_match(resp.online_players as f64) {
+                    return !is_match;
+                }
             }
             Actor::MaxPlayers => {
                 if self.check_regex_match(&format!("{}", &resp.max_players)) {
@@ -81,6 +100,9 @@ impl Condition {
                 if self.values.contains(&format!("{}", &resp.max_players)) {
                     return !is_match;
                 }
+                if self.check_numeric_match(resp.max_players as f64) {
+                    return !is_match;
+                }
             }
             Actor::Description => {
                 if self.check_regex_match(&resp.description.text) {
@@ -107,6 +129,13 @@ impl Condition {
                     }
                 }
             }
+            Actor::Latency => {
+                if let Some(latency) = resp.timing.and_then(|t| t.latency_ms) {
+                    if self.check_numeric_match(latency) {
+                        return !is_match;
+                    }
+                }
+            }
             Actor::PlayerUuid => {
                 for p in &resp.sample.unwrap_or_default() {
                     if self.check_regex_match(&p.id) {
@@ -123,6 +152,43 @@ impl Condition {
     fn check_regex_match(&self, str: &str) -> bool {
         self.values_regex.iter().any(|r| r.is_match(str))
     }
+    /// Checks values of the form `<N`, `<=N`, `>N` and `>=N`
+    fn check_numeric_match(&self, n: f64) -> bool {
+        self.values.iter().any(|v| {
+            let (cmp, rhs): (fn(&f64, &f64) -> bool, &str) = if let Some(r) = v.strip_prefix("<=") {
+                (f64::le, r)
+            } else if let Some(r) = v.strip_prefix(">=") {
+                (f64::ge, r)
+            } else if let Some(r) = v.strip_prefix('<') {
+                (f64::lt, r)
+            } else if let Some(r) = v.strip_prefix('>') {
+                (f64::gt, r)
+            } else {
+                return false;
+            };
+            rhs.trim().parse::<f64>().is_ok_and(|rhs| cmp(&n, &rhs))
+        })
+    }
+}
+/// Formats the condition the way it is passed on the command line,
+/// e.g. `include:PlayerName:CCheck` or `exclude_regex:Version:^1\.8`
+impl Display for Condition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let kind = match self.conditon_type {
+            ConditionType::Exclude => "exclude",
+            ConditionType::Include => "include",
+        };
+        if self.values_regex.is_empty() {
+            write!(f, "{kind}:{}:{}", self.actor, self.values.join(","))
+        } else {
+            let regexes = self
+                .values_regex
+                .iter()
+                .map(|r| r.as_str())
+                .collect::<Vec<&str>>();
+            write!(f, "{kind}_regex:{}:{}", self.actor, regexes.join(","))
+        }
+    }
 }
 #[derive(Debug, Clone, Deserialize, Serialize)]
 
@@ -130,7 +196,7 @@ pub enum ConditionType {
     Exclude,
     Include,
 }
-#[derive(Debug, Clone, Deserialize, Serialize, EnumString)]
+#[derive(Debug, Clone, Deserialize, Serialize, EnumString, StrumDisplay)]
 
 pub enum Actor {
     #[strum(ascii_case_insensitive)]
@@ -149,4 +215,7 @@ pub enum Actor {
     Description,
     #[strum(ascii_case_insensitive)]
     Favicon,
+    /// Ping/pong round trip in milliseconds
+    #[strum(ascii_case_insensitive)]
+    Latency,
 }
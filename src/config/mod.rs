@@ -18,47 +18,225 @@ use std::{net::IpAddr, sync::Arc};
 use std::time::Duration as StdDuration;
 use tokio::sync::RwLock;
 
-use crate::format::ccheck::CCheckFileHandler;
+use anyhow::bail;
+use owo_colors::OwoColorize;
+
+use crate::format::{
+    checkpoint::Checkpoint, failures::FailureFileHandler, metadata::ScanMetadata, Output,
+};
 use crate::{
     condition::Conditions,
-    mode::{monitor::Monitor, scanner::Scanner, Mode},
+    dial::Dialer,
+    exclude::ExcludeList,
+    limit::{RateLimit, RateLimiter},
+    mode::{adaptive::Concurrency, exit::ExitPolicy, monitor::Monitor, scanner::Scanner, Mode},
+    notify::{queue::DeliveryQueue, template::Templates},
+    probe::{Prober, ProxyHeader, Timeouts},
+    retry::RetryPolicy,
 };
+/// Probes in flight an adaptive scan starts with
+const ADAPTIVE_START: usize = 32;
+
 pub struct Config {
     pub mode: Mode,
     pub addrs: Vec<(IpAddr, u16)>,
     pub conditions: Conditions,
-    pub timeout: StdDuration,
+    pub timeouts: Timeouts,
+    pub dialer: Arc<Dialer>,
+    pub proxy_header: ProxyHeader,
+    pub rate_limit: RateLimit,
+    pub retry: RetryPolicy,
+    /// Targets that must never be connected to
+    pub exclude: ExcludeList,
 }
 impl Config {
     pub async fn run(&self) -> anyhow::Result<()> {
+        let limiter = Arc::new(RateLimiter::new(&self.rate_limit)?);
+        let prober = Prober {
+            timeouts: self.timeouts,
+            dialer: self.dialer.clone(),
+            proxy_header: self.proxy_header,
+        };
         match &self.mode {
             Mode::Scanner {
                 workers,
+                input,
                 output,
+                output_format,
+                columns,
+                failures,
                 progress_bar,
+                resume,
+                checkpoint_interval,
+                adaptive,
             } => {
-                let scanner = Scanner {
-                    timeout: self.timeout,
-                    addrs: Arc::new(RwLock::new(self.addrs.clone())),
-                    conditions: self.conditions.clone(),
-                    progress_bar: *progress_bar,
+                let checkpoint_path = Checkpoint::path(output);
+                let mut metadata = ScanMetadata::new(
+                    input,
+                    &self.timeouts,
+                    *workers,
+                    &self.conditions,
+                    self.addrs.len(),
+                )?;
+                let checkpoint = if *resume {
+                    let checkpoint = Checkpoint::load(&checkpoint_path)?;
+                    if checkpoint.metadata.input_sha256 != metadata.input_sha256 {
+                        bail!(
+                            "{} was modified since the checkpoint was written, refusing to resume",
+                            input.display()
+                        );
+                    }
+                    Some(checkpoint)
+                } else {
+                    None
                 };
-                let file_handler = CCheckFileHandler::new(output.to_path_buf()).await?;
-                scanner.run(*workers, file_handler).await?;
+                let mut scanner = Scanner::new(
+                    self.addrs.clone(),
+                    self.conditions.clone(),
+                    prober,
+                    *progress_bar,
+                    limiter,
+                    self.retry.clone(),
+                    checkpoint.as_ref(),
+                )?;
+                let skipped = scanner.exclude(&self.exclude);
+                if *adaptive {
+                    scanner.concurrency =
+                        Some(Arc::new(Concurrency::new(ADAPTIVE_START, 1, *workers + 1)));
+                }
+                let (file_handler, failures_handler) = match &checkpoint {
+                    Some(checkpoint) => {
+                        metadata = checkpoint.metadata.clone();
+                        let file_handler = Output::resume(
+                            output.to_path_buf(),
+                            *output_format,
+                            columns.clone(),
+                            checkpoint,
+                        )
+                        .await?;
+                        let failures_handler = match (failures, checkpoint.failures_len) {
+                            (Some(path), Some(len)) => Some(
+                                FailureFileHandler::resume(
+                                    path.to_path_buf(),
+                                    len,
+                                    checkpoint.failures_count,
+                                )
+                                .await?,
+                            ),
+                            (Some(path), None) => {
+                                Some(FailureFileHandler::new(path.to_path_buf()).await?)
+                            }
+                            (None, _) => None,
+                        };
+                        (file_handler, failures_handler)
+                    }
+                    None => {
+                        let file_handler =
+                            Output::new(output.to_path_buf(), *output_format, columns.clone())
+                                .await?;
+                        let failures_handler = match failures {
+                            Some(path) => Some(FailureFileHandler::new(path.to_path_buf()).await?),
+                            None => None,
+                        };
+                        (file_handler, failures_handler)
+                    }
+                };
+                metadata.skipped = skipped;
+                let checkpoint_interval = match checkpoint_interval {
+                    0 => None,
+                    secs => Some(StdDuration::from_secs(*secs)),
+                };
+                scanner
+                    .run(
+                        *workers,
+                        file_handler,
+                        failures_handler,
+                        metadata,
+                        checkpoint_path,
+                        checkpoint_interval,
+                    )
+                    .await?;
             }
             Mode::Monitor {
-                workers: rate,
+                workers,
+                schedule,
                 webhook_url,
+                notify,
                 exit_on_success,
+                exit,
+                track,
+                stats,
+                events,
+                events_file,
+                player_thresholds,
+                alerts,
+                templates,
+                delivery,
+                state,
+                fresh_state,
             } => {
+                // `--workers` used to be the only way to pace monitor, keep honouring it
+                let limiter = match self.rate_limit.rate {
+                    None if *workers > 0 => Arc::new(RateLimiter::new(&RateLimit {
+                        rate: Some(*workers as f64),
+                        ..self.rate_limit.clone()
+                    })?),
+                    _ => limiter,
+                };
+                let addrs = self
+                    .addrs
+                    .iter()
+                    .filter(|addr| !self.exclude.contains(addr.0))
+                    .copied()
+                    .collect::<Vec<_>>();
+                let skipped = self.addrs.len() - addrs.len();
+                if addrs.is_empty() && skipped > 0 {
+                    bail!("No servers left to monitor, all of them are excluded");
+                }
+                schedule.validate()?;
+                exit.validate()?;
+                let specs = webhook_url
+                    .iter()
+                    .map(|url| format!("discord:{url}"))
+                    .chain(notify.iter().cloned())
+                    .collect::<Vec<_>>();
+                let queue = DeliveryQueue::start(&specs, delivery)?;
+                let templates = Templates::new(templates)?;
                 let monitor = Monitor {
-                    rate: *rate,
-                    timeout: self.timeout,
+                    limiter,
+                    workers: (*workers).max(1),
+                    retry: self.retry.clone(),
+                    prober,
                     conditions: self.conditions.clone(),
-                    addrs: Arc::new(RwLock::new(self.addrs.clone())),
-                    webhook_url: webhook_url.clone(),
+                    addrs: Arc::new(RwLock::new(addrs)),
+                    queue: Arc::new(queue),
+                    events: events.clone(),
+                    events_file: events_file.clone(),
+                    schedule: schedule.clone(),
+                    state_file: state.clone(),
+                    fresh_state: *fresh_state,
+                    player_thresholds: player_thresholds.clone(),
+                    alerts: *alerts,
+                    templates: Arc::new(templates),
+                    exit: ExitPolicy {
+                        matches: if *exit_on_success { exit.matches } else { 0 },
+                        ..*exit.clone()
+                    },
+                    track: (**track).clone(),
+                    stats: *stats,
                 };
-                monitor.run(*exit_on_success).await?;
+                if skipped > 0 {
+                    monitor.say(format!(
+                        "{} Skipping {} excluded servers",
+                        "::".yellow().bold(),
+                        skipped.cyan()
+                    ));
+                }
+                let reason = monitor.run().await?;
+                // scripts tell the outcomes apart by the exit code
+                if reason.code() != 0 {
+                    std::process::exit(reason.code());
+                }
             }
         }
         Ok(())
@@ -0,0 +1,165 @@
+// CCheck: utility for scanning and probing minecraft servers
+// Copyright (C) 2022 cleonyc
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//! Client side of an HTTP CONNECT tunnel
+use std::{io, net::SocketAddr};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Longest response header accepted from the proxy
+const MAX_HEADER: usize = 8192;
+
+/// Asks the HTTP proxy on the other end of `stream` to open a tunnel to `target`
+pub async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    target: SocketAddr,
+    auth: Option<&(String, String)>,
+) -> io::Result<()> {
+    let mut request = format!("CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n");
+    if let Some((user, pass)) = auth {
+        request.push_str(&format!(
+            "Proxy-Authorization: Basic {}\r\n",
+            base64::encode(format!("{user}:{pass}"))
+        ));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    // read byte by byte, anything after the header already belongs to the server
+    let mut header = Vec::new();
+    while !header.ends_with(b"\r\n\r\n") {
+        if header.len() == MAX_HEADER {
+            return Err(invalid("HTTP proxy response header too long"));
+        }
+        header.push(stream.read_u8().await?);
+    }
+    let header = String::from_utf8_lossy(&header);
+    let status_line = header.lines().next().unwrap_or_default();
+    let status = match status_line.split_whitespace().collect::<Vec<_>>()[..] {
+        [version, status, ..] if version.starts_with("HTTP/1.") => status
+            .parse::<u16>()
+            .map_err(|_| invalid("invalid HTTP proxy response"))?,
+        _ => return Err(invalid("invalid HTTP proxy response")),
+    };
+    let kind = match status {
+        200..=299 => return Ok(()),
+        403 | 407 => io::ErrorKind::PermissionDenied,
+        // what squid and most other proxies answer when the target refuses or can't be reached
+        502 | 503 => io::ErrorKind::ConnectionRefused,
+        _ => io::ErrorKind::Other,
+    };
+    Err(io::Error::new(kind, format!("HTTP proxy: {status_line}")))
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use tokio::io::{duplex, DuplexStream};
+
+    use super::*;
+
+    const TARGET: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)), 25565);
+
+    /// Reads the CONNECT request the same way a proxy would, up to the blank line
+    async fn read_request(stream: &mut DuplexStream) -> String {
+        let mut request = Vec::new();
+        while !request.ends_with(b"\r\n\r\n") {
+            request.push(stream.read_u8().await.unwrap());
+        }
+        String::from_utf8(request).unwrap()
+    }
+
+    /// Runs the handshake against a proxy that answers with `response`, returning the request it got
+    async fn exchange(
+        auth: Option<&(String, String)>,
+        response: &[u8],
+    ) -> (io::Result<()>, String, DuplexStream) {
+        // big enough for the whole response, a proxy that goes on sending mustn't block the test
+        let (mut client, mut proxy) = duplex(64 * 1024);
+        let server = async {
+            let request = read_request(&mut proxy).await;
+            proxy.write_all(response).await.unwrap();
+            request
+        };
+        let (result, request) = tokio::join!(handshake(&mut client, TARGET, auth), server);
+        (result, request, client)
+    }
+
+    #[tokio::test]
+    async fn established() {
+        let (result, request, mut client) =
+            exchange(None, b"HTTP/1.1 200 Connection established\r\n\r\nstatus").await;
+        result.unwrap();
+        assert_eq!(
+            request,
+            "CONNECT 192.0.2.1:25565 HTTP/1.1\r\nHost: 192.0.2.1:25565\r\n\r\n"
+        );
+        // whatever follows the header belongs to the target
+        let mut rest = [0; 6];
+        client.read_exact(&mut rest).await.unwrap();
+        assert_eq!(&rest, b"status");
+    }
+
+    #[tokio::test]
+    async fn basic_auth() {
+        let auth = ("user".to_string(), "secret".to_string());
+        let (result, request, _) = exchange(Some(&auth), b"HTTP/1.0 200 OK\r\n\r\n").await;
+        result.unwrap();
+        assert!(request.contains("\r\nProxy-Authorization: Basic dXNlcjpzZWNyZXQ=\r\n"));
+    }
+
+    #[tokio::test]
+    async fn auth_required() {
+        let (result, _, _) = exchange(
+            None,
+            b"HTTP/1.1 407 Proxy Authentication Required\r\nProxy-Authenticate: Basic\r\n\r\n",
+        )
+        .await;
+        let err = result.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(
+            err.to_string(),
+            "HTTP proxy: HTTP/1.1 407 Proxy Authentication Required"
+        );
+    }
+
+    #[tokio::test]
+    async fn bad_gateway() {
+        let (result, _, _) = exchange(None, b"HTTP/1.1 502 Bad Gateway\r\n\r\n").await;
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::ConnectionRefused);
+    }
+
+    #[tokio::test]
+    async fn header_too_long() {
+        let mut response = b"HTTP/1.1 200 OK\r\nX-Padding: ".to_vec();
+        response.extend(vec![b'a'; MAX_HEADER]);
+        response.extend_from_slice(b"\r\n\r\n");
+        let (result, _, _) = exchange(None, &response).await;
+        let err = result.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "HTTP proxy response header too long");
+    }
+
+    #[tokio::test]
+    async fn not_http() {
+        let (result, _, _) = exchange(None, b"\x05\x00garbage\r\n\r\n").await;
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
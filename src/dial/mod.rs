@@ -0,0 +1,265 @@
+// CCheck: utility for scanning and probing minecraft servers
+// Copyright (C) 2022 cleonyc
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use std::{
+    fmt::Display,
+    fs, io,
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    str::FromStr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use tokio::net::{lookup_host, TcpSocket, TcpStream};
+
+pub mod http;
+pub mod proxy_protocol;
+pub mod socks5;
+
+/// Opens the connections probes are made over, either directly or through a proxy
+#[derive(Debug, Default)]
+pub struct Dialer {
+    /// Local addresses to connect from, used in turn to spread ephemeral port usage
+    source_v4: Vec<IpAddr>,
+    source_v6: Vec<IpAddr>,
+    next: AtomicUsize,
+    /// Network interface to bind sockets to
+    interface: Option<String>,
+    /// Proxies to connect through, used in turn
+    proxies: Vec<Proxy>,
+    next_proxy: AtomicUsize,
+}
+impl Dialer {
+    pub fn new(
+        source_ips: &[IpAddr],
+        interface: Option<String>,
+        proxies: Vec<Proxy>,
+    ) -> anyhow::Result<Self> {
+        if interface.is_some() && !cfg!(any(target_os = "android", target_os = "linux")) {
+            anyhow::bail!("--interface is only supported on Linux");
+        }
+        Ok(Self {
+            source_v4: source_ips.iter().copied().filter(IpAddr::is_ipv4).collect(),
+            source_v6: source_ips.iter().copied().filter(IpAddr::is_ipv6).collect(),
+            next: AtomicUsize::new(0),
+            interface,
+            proxies,
+            next_proxy: AtomicUsize::new(0),
+        })
+    }
+    /// Opens a TCP connection to `addr`, through the next proxy if there are any
+    pub async fn connect(&self, addr: SocketAddr) -> io::Result<TcpStream> {
+        if self.proxies.is_empty() {
+            return self.connect_direct(addr).await;
+        }
+        let proxy =
+            &self.proxies[self.next_proxy.fetch_add(1, Ordering::Relaxed) % self.proxies.len()];
+        let mut stream = self.connect_proxy(proxy).await?;
+        let auth = proxy.auth.as_ref();
+        match proxy.kind {
+            ProxyKind::Socks5 => socks5::handshake(&mut stream, addr, auth).await?,
+            ProxyKind::Http => http::handshake(&mut stream, addr, auth).await?,
+        }
+        Ok(stream)
+    }
+    async fn connect_proxy(&self, proxy: &Proxy) -> io::Result<TcpStream> {
+        let mut last_err = None;
+        for addr in lookup_host((proxy.host.as_str(), proxy.port)).await? {
+            match self.connect_direct(addr).await {
+                Ok(stream) => return Ok(stream),
+                Err(err) => last_err = Some(err),
+            }
+        }
+        let err = last_err
+            .unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no addresses found"));
+        Err(match err.kind() {
+            // the target was never contacted, don't let a dead proxy look like a refusing server
+            io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::HostUnreachable
+            | io::ErrorKind::NetworkUnreachable
+            | io::ErrorKind::NotFound => io::Error::other(format!("proxy {proxy}: {err}")),
+            _ => err,
+        })
+    }
+    /// Opens a TCP connection to `addr`, bound to the configured source address and interface
+    async fn connect_direct(&self, addr: SocketAddr) -> io::Result<TcpStream> {
+        let (socket, sources) = match addr {
+            SocketAddr::V4(_) => (TcpSocket::new_v4()?, &self.source_v4),
+            SocketAddr::V6(_) => (TcpSocket::new_v6()?, &self.source_v6),
+        };
+        #[cfg(any(target_os = "android", target_os = "linux"))]
+        if let Some(interface) = &self.interface {
+            socket.bind_device(Some(interface.as_bytes()))?;
+        }
+        if !sources.is_empty() {
+            let source = sources[self.next.fetch_add(1, Ordering::Relaxed) % sources.len()];
+            socket.bind(SocketAddr::new(source, 0))?;
+        } else if !self.source_v4.is_empty() || !self.source_v6.is_empty() {
+            // connecting from the OS default address would defeat the point of `--source-ip`
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("no --source-ip given for {} targets", family(addr.ip())),
+            ));
+        }
+        socket.connect(addr).await
+    }
+}
+fn family(ip: IpAddr) -> &'static str {
+    match ip {
+        IpAddr::V4(_) => "IPv4",
+        IpAddr::V6(_) => "IPv6",
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyKind {
+    /// SOCKS5
+    Socks5,
+    /// HTTP CONNECT
+    Http,
+}
+
+/// A proxy given as `socks5://` or `http://[user:pass@]host:port`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Proxy {
+    pub kind: ProxyKind,
+    pub host: String,
+    pub port: u16,
+    pub auth: Option<(String, String)>,
+}
+impl Proxy {
+    /// `proxies` followed by the ones listed in `files`
+    pub fn list<P: AsRef<Path>>(
+        mut proxies: Vec<Proxy>,
+        files: &[P],
+    ) -> anyhow::Result<Vec<Proxy>> {
+        for file in files {
+            Proxy::read_file(file.as_ref(), &mut proxies)?;
+        }
+        Ok(proxies)
+    }
+    /// Reads proxy URLs, one per line, with `#` starting a comment
+    fn read_file(path: &Path, proxies: &mut Vec<Proxy>) -> anyhow::Result<()> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("failed to read {}: {e}", path.display()))?;
+        for (n, line) in content.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if !line.is_empty() {
+                proxies.push(
+                    line.parse()
+                        .map_err(|e| anyhow::anyhow!("{}:{}: {e}", path.display(), n + 1))?,
+                );
+            }
+        }
+        Ok(())
+    }
+}
+impl FromStr for Proxy {
+    type Err = ParseProxyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || ParseProxyError::Invalid(s.to_string());
+        let (scheme, rest) = s.split_once("://").ok_or_else(err)?;
+        let kind = match scheme.to_ascii_lowercase().as_str() {
+            "socks5" => ProxyKind::Socks5,
+            "socks5h" => return Err(ParseProxyError::RemoteDns(s.to_string())),
+            "http" => ProxyKind::Http,
+            _ => return Err(err()),
+        };
+        let rest = rest.trim_end_matches('/');
+        let (auth, host_port) = match rest.rsplit_once('@') {
+            Some((auth, host_port)) => {
+                let (user, pass) = auth.split_once(':').unwrap_or((auth, ""));
+                (Some((user.to_string(), pass.to_string())), host_port)
+            }
+            None => (None, rest),
+        };
+        let (host, port) = host_port.rsplit_once(':').ok_or_else(err)?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return Err(err());
+        }
+        Ok(Proxy {
+            kind,
+            host: host.to_string(),
+            port: port.parse().map_err(|_| err())?,
+            auth,
+        })
+    }
+}
+impl Display for Proxy {
+    /// Leaves out the credentials, so it is safe to print
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let scheme = match self.kind {
+            ProxyKind::Socks5 => "socks5",
+            ProxyKind::Http => "http",
+        };
+        match self.host.contains(':') {
+            true => write!(f, "{scheme}://[{}]:{}", self.host, self.port),
+            false => write!(f, "{scheme}://{}:{}", self.host, self.port),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ParseProxyError {
+    Invalid(String),
+    /// `socks5h://`, targets are IP addresses so there is never a name for the proxy to resolve
+    RemoteDns(String),
+}
+impl Display for ParseProxyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseProxyError::Invalid(proxy) => write!(
+                f,
+                "invalid proxy `{proxy}`, expected socks5:// or http://[user:pass@]host:port"
+            ),
+            ParseProxyError::RemoteDns(proxy) => write!(
+                f,
+                "unsupported proxy `{proxy}`: remote DNS (socks5h://) isn't supported, \
+                 targets are IP addresses, use socks5:// instead"
+            ),
+        }
+    }
+}
+impl std::error::Error for ParseProxyError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_proxy() {
+        let proxy = "socks5://user:pass@[::1]:1080".parse::<Proxy>().unwrap();
+        assert_eq!(proxy.kind, ProxyKind::Socks5);
+        assert_eq!(proxy.host, "::1");
+        assert_eq!(proxy.port, 1080);
+        assert_eq!(proxy.auth, Some(("user".to_string(), "pass".to_string())));
+        assert_eq!(proxy.to_string(), "socks5://[::1]:1080");
+        let proxy = "HTTP://gateway.example:3128/".parse::<Proxy>().unwrap();
+        assert_eq!(proxy.kind, ProxyKind::Http);
+        assert_eq!(proxy.auth, None);
+        assert!(matches!(
+            "socks5h://proxy.example:1080".parse::<Proxy>(),
+            Err(ParseProxyError::RemoteDns(_))
+        ));
+        assert!(matches!(
+            "socks4://proxy.example:1080".parse::<Proxy>(),
+            Err(ParseProxyError::Invalid(_))
+        ));
+        assert!("socks5://proxy.example".parse::<Proxy>().is_err());
+    }
+}
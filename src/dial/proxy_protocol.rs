@@ -0,0 +1,83 @@
+// CCheck: utility for scanning and probing minecraft servers
+// Copyright (C) 2022 cleonyc
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//! HAProxy PROXY protocol headers, for servers that only accept connections through a proxy
+use std::net::SocketAddr;
+
+use serde::{Deserialize, Serialize};
+use strum_macros::{Display, EnumString};
+
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Version of the PROXY protocol header to send
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, EnumString, Display)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase", ascii_case_insensitive)]
+pub enum ProxyProtocol {
+    /// Human readable, `PROXY TCP4 ...\r\n`
+    V1,
+    /// Binary
+    V2,
+}
+impl ProxyProtocol {
+    /// Header announcing a connection from `source` to `destination`
+    pub fn header(self, source: SocketAddr, destination: SocketAddr) -> Vec<u8> {
+        // can't describe a connection between address families (e.g. when going through a
+        // SOCKS proxy), so let the server use the address it sees instead
+        let same_family = source.is_ipv4() == destination.is_ipv4();
+        match self {
+            ProxyProtocol::V1 if same_family => format!(
+                "PROXY {} {} {} {} {}\r\n",
+                if source.is_ipv4() { "TCP4" } else { "TCP6" },
+                source.ip(),
+                destination.ip(),
+                source.port(),
+                destination.port()
+            )
+            .into_bytes(),
+            ProxyProtocol::V1 => b"PROXY UNKNOWN\r\n".to_vec(),
+            ProxyProtocol::V2 => {
+                let mut header = V2_SIGNATURE.to_vec();
+                let mut addrs = vec![];
+                match (source, destination) {
+                    (SocketAddr::V4(source), SocketAddr::V4(destination)) => {
+                        addrs.extend_from_slice(&source.ip().octets());
+                        addrs.extend_from_slice(&destination.ip().octets());
+                    }
+                    (SocketAddr::V6(source), SocketAddr::V6(destination)) => {
+                        addrs.extend_from_slice(&source.ip().octets());
+                        addrs.extend_from_slice(&destination.ip().octets());
+                    }
+                    _ => {}
+                }
+                if same_family {
+                    addrs.extend_from_slice(&source.port().to_be_bytes());
+                    addrs.extend_from_slice(&destination.port().to_be_bytes());
+                    // version 2, PROXY command
+                    header.push(0x21);
+                    // TCP over IPv4 or IPv6
+                    header.push(if source.is_ipv4() { 0x11 } else { 0x21 });
+                } else {
+                    // version 2, LOCAL command, unspecified family
+                    header.push(0x20);
+                    header.push(0x00);
+                }
+                header.extend_from_slice(&(addrs.len() as u16).to_be_bytes());
+                header.extend_from_slice(&addrs);
+                header
+            }
+        }
+    }
+}
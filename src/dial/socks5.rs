@@ -0,0 +1,308 @@
+// CCheck: utility for scanning and probing minecraft servers
+// Copyright (C) 2022 cleonyc
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//! Client side of the SOCKS5 CONNECT handshake (RFC 1928) with username/password auth (RFC 1929)
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+const VERSION: u8 = 5;
+const NO_AUTH: u8 = 0;
+const USER_PASS: u8 = 2;
+const NO_ACCEPTABLE_METHOD: u8 = 0xff;
+const CONNECT: u8 = 1;
+const ATYP_V4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_V6: u8 = 4;
+
+/// Asks the proxy on the other end of `stream` to connect to `target`
+pub async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    target: SocketAddr,
+    auth: Option<&(String, String)>,
+) -> io::Result<()> {
+    let methods: &[u8] = match auth {
+        Some(_) => &[NO_AUTH, USER_PASS],
+        None => &[NO_AUTH],
+    };
+    let mut greeting = vec![VERSION, methods.len() as u8];
+    greeting.extend_from_slice(methods);
+    stream.write_all(&greeting).await?;
+    let mut choice = [0; 2];
+    stream.read_exact(&mut choice).await?;
+    if choice[0] != VERSION {
+        return Err(invalid("not a SOCKS5 proxy"));
+    }
+    match (choice[1], auth) {
+        (NO_AUTH, _) => {}
+        (USER_PASS, Some((user, pass))) => authenticate(stream, user, pass).await?,
+        (NO_ACCEPTABLE_METHOD, _) | (USER_PASS, None) => {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "SOCKS5 proxy requires authentication",
+            ))
+        }
+        (method, _) => {
+            return Err(invalid(&format!(
+                "SOCKS5 proxy chose unsupported method {method}"
+            )))
+        }
+    }
+
+    let mut request = vec![VERSION, CONNECT, 0];
+    match target.ip() {
+        IpAddr::V4(ip) => {
+            request.push(ATYP_V4);
+            request.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            request.push(ATYP_V6);
+            request.extend_from_slice(&ip.octets());
+        }
+    }
+    request.extend_from_slice(&target.port().to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut reply = [0; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != VERSION {
+        return Err(invalid("invalid SOCKS5 reply"));
+    }
+    if reply[1] != 0 {
+        return Err(reply_error(reply[1]));
+    }
+    // the address the proxy connected from is of no use to us, but has to be read past
+    let bound = match reply[3] {
+        ATYP_V4 => 4,
+        ATYP_V6 => 16,
+        ATYP_DOMAIN => stream.read_u8().await? as usize,
+        _ => return Err(invalid("invalid SOCKS5 reply")),
+    };
+    let mut rest = vec![0; bound + 2];
+    stream.read_exact(&mut rest).await?;
+    Ok(())
+}
+
+async fn authenticate<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    user: &str,
+    pass: &str,
+) -> io::Result<()> {
+    if user.len() > 255 || pass.len() > 255 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "SOCKS5 username and password can't be longer than 255 bytes",
+        ));
+    }
+    let mut request = vec![1, user.len() as u8];
+    request.extend_from_slice(user.as_bytes());
+    request.push(pass.len() as u8);
+    request.extend_from_slice(pass.as_bytes());
+    stream.write_all(&request).await?;
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply).await?;
+    if reply[1] != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "SOCKS5 proxy rejected the username or password",
+        ));
+    }
+    Ok(())
+}
+
+/// Maps a SOCKS5 reply code to the error connecting directly would have given
+fn reply_error(code: u8) -> io::Error {
+    let (kind, message) = match code {
+        2 => (
+            io::ErrorKind::PermissionDenied,
+            "connection not allowed by ruleset",
+        ),
+        3 => (io::ErrorKind::NetworkUnreachable, "network unreachable"),
+        4 | 6 => (io::ErrorKind::HostUnreachable, "host unreachable"),
+        5 => (io::ErrorKind::ConnectionRefused, "connection refused"),
+        7 | 8 => (io::ErrorKind::Unsupported, "request not supported by proxy"),
+        _ => (io::ErrorKind::Other, "general proxy failure"),
+    };
+    io::Error::new(kind, format!("SOCKS5 proxy: {message}"))
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use tokio::io::{duplex, DuplexStream};
+
+    use super::*;
+
+    const TARGET: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)), 25565);
+    const SUCCESS: [u8; 10] = [VERSION, 0, 0, ATYP_V4, 0, 0, 0, 0, 0, 0];
+
+    async fn expect(stream: &mut DuplexStream, expected: &[u8]) {
+        let mut buf = vec![0; expected.len()];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, expected);
+    }
+
+    fn connect_request(target: SocketAddr) -> Vec<u8> {
+        let mut request = vec![VERSION, CONNECT, 0];
+        match target.ip() {
+            IpAddr::V4(ip) => {
+                request.push(ATYP_V4);
+                request.extend_from_slice(&ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                request.push(ATYP_V6);
+                request.extend_from_slice(&ip.octets());
+            }
+        }
+        request.extend_from_slice(&target.port().to_be_bytes());
+        request
+    }
+
+    #[tokio::test]
+    async fn no_auth() {
+        let (mut client, mut proxy) = duplex(1024);
+        let server = async {
+            expect(&mut proxy, &[VERSION, 1, NO_AUTH]).await;
+            proxy.write_all(&[VERSION, NO_AUTH]).await.unwrap();
+            expect(&mut proxy, &connect_request(TARGET)).await;
+            proxy.write_all(&SUCCESS).await.unwrap();
+            proxy.write_all(b"status").await.unwrap();
+        };
+        let (result, _) = tokio::join!(handshake(&mut client, TARGET, None), server);
+        result.unwrap();
+        // whatever follows the reply belongs to the target
+        let mut rest = [0; 6];
+        client.read_exact(&mut rest).await.unwrap();
+        assert_eq!(&rest, b"status");
+    }
+
+    #[tokio::test]
+    async fn ipv6_target_and_domain_bound_address() {
+        let target = SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 25565);
+        let (mut client, mut proxy) = duplex(1024);
+        let server = async {
+            expect(&mut proxy, &[VERSION, 1, NO_AUTH]).await;
+            proxy.write_all(&[VERSION, NO_AUTH]).await.unwrap();
+            expect(&mut proxy, &connect_request(target)).await;
+            proxy
+                .write_all(&[
+                    VERSION,
+                    0,
+                    0,
+                    ATYP_DOMAIN,
+                    5,
+                    b'p',
+                    b'r',
+                    b'o',
+                    b'x',
+                    b'y',
+                    0,
+                    80,
+                ])
+                .await
+                .unwrap();
+        };
+        let (result, _) = tokio::join!(handshake(&mut client, target, None), server);
+        result.unwrap();
+    }
+
+    #[tokio::test]
+    async fn user_pass_accepted() {
+        let auth = ("user".to_string(), "secret".to_string());
+        let (mut client, mut proxy) = duplex(1024);
+        let server = async {
+            expect(&mut proxy, &[VERSION, 2, NO_AUTH, USER_PASS]).await;
+            proxy.write_all(&[VERSION, USER_PASS]).await.unwrap();
+            expect(&mut proxy, b"\x01\x04user\x06secret").await;
+            proxy.write_all(&[1, 0]).await.unwrap();
+            expect(&mut proxy, &connect_request(TARGET)).await;
+            proxy.write_all(&SUCCESS).await.unwrap();
+        };
+        let (result, _) = tokio::join!(handshake(&mut client, TARGET, Some(&auth)), server);
+        result.unwrap();
+    }
+
+    #[tokio::test]
+    async fn user_pass_rejected() {
+        let auth = ("user".to_string(), "wrong".to_string());
+        let (mut client, mut proxy) = duplex(1024);
+        let server = async {
+            expect(&mut proxy, &[VERSION, 2, NO_AUTH, USER_PASS]).await;
+            proxy.write_all(&[VERSION, USER_PASS]).await.unwrap();
+            expect(&mut proxy, b"\x01\x04user\x05wrong").await;
+            proxy.write_all(&[1, 1]).await.unwrap();
+        };
+        let (result, _) = tokio::join!(handshake(&mut client, TARGET, Some(&auth)), server);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[tokio::test]
+    async fn auth_required_without_credentials() {
+        let (mut client, mut proxy) = duplex(1024);
+        let server = async {
+            expect(&mut proxy, &[VERSION, 1, NO_AUTH]).await;
+            proxy
+                .write_all(&[VERSION, NO_ACCEPTABLE_METHOD])
+                .await
+                .unwrap();
+        };
+        let (result, _) = tokio::join!(handshake(&mut client, TARGET, None), server);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[tokio::test]
+    async fn reply_codes() {
+        for (code, kind) in [
+            (1, io::ErrorKind::Other),
+            (2, io::ErrorKind::PermissionDenied),
+            (3, io::ErrorKind::NetworkUnreachable),
+            (4, io::ErrorKind::HostUnreachable),
+            (5, io::ErrorKind::ConnectionRefused),
+            (6, io::ErrorKind::HostUnreachable),
+            (7, io::ErrorKind::Unsupported),
+        ] {
+            let (mut client, mut proxy) = duplex(1024);
+            let server = async {
+                expect(&mut proxy, &[VERSION, 1, NO_AUTH]).await;
+                proxy.write_all(&[VERSION, NO_AUTH]).await.unwrap();
+                expect(&mut proxy, &connect_request(TARGET)).await;
+                let mut reply = SUCCESS;
+                reply[1] = code;
+                proxy.write_all(&reply).await.unwrap();
+            };
+            let (result, _) = tokio::join!(handshake(&mut client, TARGET, None), server);
+            assert_eq!(result.unwrap_err().kind(), kind, "reply code {code}");
+        }
+    }
+
+    #[tokio::test]
+    async fn not_socks5() {
+        let (mut client, mut proxy) = duplex(1024);
+        let server = async {
+            expect(&mut proxy, &[VERSION, 1, NO_AUTH]).await;
+            proxy.write_all(b"HT").await.unwrap();
+        };
+        let (result, _) = tokio::join!(handshake(&mut client, TARGET, None), server);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,215 @@
+// CCheck: utility for scanning and probing minecraft servers
+// Copyright (C) 2022 cleonyc
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use std::{
+    fmt::Display,
+    fs,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::Path,
+    str::FromStr,
+};
+
+/// Reserved, private and otherwise non-routable ranges that are excluded unless
+/// `--no-default-excludes` is given
+pub const BOGONS: &[&str] = &[
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "100.64.0.0/10",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.0.0.0/24",
+    "192.0.2.0/24",
+    "192.168.0.0/16",
+    "198.18.0.0/15",
+    "198.51.100.0/24",
+    "203.0.113.0/24",
+    "224.0.0.0/4",
+    "240.0.0.0/4",
+    "::/128",
+    "::1/128",
+    "::ffff:0:0/96",
+    "100::/64",
+    "2001:db8::/32",
+    "fc00::/7",
+    "fe80::/10",
+    "ff00::/8",
+];
+
+#[derive(Debug)]
+pub struct ParseRangeError(String);
+impl Display for ParseRangeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid IP range `{}`", self.0)
+    }
+}
+impl std::error::Error for ParseRangeError {}
+
+/// An inclusive range of addresses: a single IP, `a.b.c.d/n` or `first-last`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpRange {
+    V4(u32, u32),
+    V6(u128, u128),
+}
+impl FromStr for IpRange {
+    type Err = ParseRangeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || ParseRangeError(s.to_string());
+        if let Some((ip, bits)) = s.split_once('/') {
+            let bits = bits.parse::<u32>().map_err(|_| err())?;
+            return match ip.parse::<IpAddr>().map_err(|_| err())? {
+                IpAddr::V4(ip) if bits <= 32 => {
+                    let mask = u32::MAX.checked_shl(32 - bits).unwrap_or(0);
+                    let start = u32::from(ip) & mask;
+                    Ok(IpRange::V4(start, start | !mask))
+                }
+                IpAddr::V6(ip) if bits <= 128 => {
+                    let mask = u128::MAX.checked_shl(128 - bits).unwrap_or(0);
+                    let start = u128::from(ip) & mask;
+                    Ok(IpRange::V6(start, start | !mask))
+                }
+                _ => Err(err()),
+            };
+        }
+        let (first, last) = s.split_once('-').unwrap_or((s, s));
+        match (
+            first.trim().parse::<IpAddr>().map_err(|_| err())?,
+            last.trim().parse::<IpAddr>().map_err(|_| err())?,
+        ) {
+            (IpAddr::V4(first), IpAddr::V4(last)) if first <= last => {
+                Ok(IpRange::V4(first.into(), last.into()))
+            }
+            (IpAddr::V6(first), IpAddr::V6(last)) if first <= last => {
+                Ok(IpRange::V6(first.into(), last.into()))
+            }
+            _ => Err(err()),
+        }
+    }
+}
+
+impl IpRange {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (*self, ip) {
+            (IpRange::V4(start, end), IpAddr::V4(ip)) => (start..=end).contains(&u32::from(ip)),
+            (IpRange::V6(start, end), IpAddr::V6(ip)) => (start..=end).contains(&u128::from(ip)),
+            _ => false,
+        }
+    }
+    /// Number of addresses in the range, minus one
+    pub fn span(&self) -> u128 {
+        match *self {
+            IpRange::V4(start, end) => (end - start) as u128,
+            IpRange::V6(start, end) => end - start,
+        }
+    }
+}
+impl Display for IpRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (first, last): (IpAddr, IpAddr) = match *self {
+            IpRange::V4(start, end) => (Ipv4Addr::from(start).into(), Ipv4Addr::from(end).into()),
+            IpRange::V6(start, end) => (Ipv6Addr::from(start).into(), Ipv6Addr::from(end).into()),
+        };
+        match first == last {
+            true => write!(f, "{first}"),
+            false => write!(f, "{first}-{last}"),
+        }
+    }
+}
+
+/// Addresses that must never be connected to
+#[derive(Debug, Clone, Default)]
+pub struct ExcludeList {
+    /// Sorted, non-overlapping ranges
+    v4: Vec<(u32, u32)>,
+    v6: Vec<(u128, u128)>,
+}
+impl ExcludeList {
+    /// Builds the list from the built-in bogons (if `bogons` is set) and masscan style exclude files
+    pub fn new<P: AsRef<Path>>(bogons: bool, files: &[P]) -> anyhow::Result<Self> {
+        let mut list = ExcludeList::default();
+        if bogons {
+            for range in BOGONS {
+                list.add(range.parse()?);
+            }
+        }
+        for file in files {
+            list.add_file(file.as_ref())?;
+        }
+        list.merge();
+        Ok(list)
+    }
+    /// Reads a masscan `excludefile`: IPs, CIDRs and ranges separated by newlines or commas,
+    /// with `#` starting a comment
+    fn add_file(&mut self, path: &Path) -> anyhow::Result<()> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("failed to read {}: {e}", path.display()))?;
+        for (n, line) in content.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default();
+            for entry in line
+                .split(|c: char| c == ',' || c.is_whitespace())
+                .filter(|e| !e.is_empty())
+            {
+                let range = entry
+                    .parse()
+                    .map_err(|e| anyhow::anyhow!("{}:{}: {e}", path.display(), n + 1))?;
+                self.add(range);
+            }
+        }
+        Ok(())
+    }
+    fn add(&mut self, range: IpRange) {
+        match range {
+            IpRange::V4(start, end) => self.v4.push((start, end)),
+            IpRange::V6(start, end) => self.v6.push((start, end)),
+        }
+    }
+    fn merge(&mut self) {
+        merge_ranges(&mut self.v4, |n| n.saturating_add(1));
+        merge_ranges(&mut self.v6, |n| n.saturating_add(1));
+    }
+    pub fn is_empty(&self) -> bool {
+        self.v4.is_empty() && self.v6.is_empty()
+    }
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match ip {
+            IpAddr::V4(ip) => contains(&self.v4, u32::from(ip)),
+            // IPv4-mapped addresses are checked against the IPv4 list too
+            IpAddr::V6(ip) => {
+                contains(&self.v6, u128::from(ip))
+                    || ip
+                        .to_ipv4_mapped()
+                        .is_some_and(|ip| contains(&self.v4, u32::from(ip)))
+            }
+        }
+    }
+}
+/// Sorts `ranges` and merges overlapping and adjacent ones, `next` being `n + 1` saturating
+fn merge_ranges<T: Ord + Copy>(ranges: &mut Vec<(T, T)>, next: fn(T) -> T) {
+    ranges.sort_unstable();
+    let mut merged: Vec<(T, T)> = Vec::with_capacity(ranges.len());
+    for &(start, end) in ranges.iter() {
+        match merged.last_mut() {
+            Some(last) if start <= next(last.1) => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    *ranges = merged;
+}
+fn contains<T: Ord + Copy>(ranges: &[(T, T)], ip: T) -> bool {
+    // index of the first range starting after `ip`, the one before it is the only candidate
+    let i = ranges.partition_point(|(start, _)| *start <= ip);
+    i > 0 && ranges[i - 1].1 >= ip
+}
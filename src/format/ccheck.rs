@@ -14,27 +14,41 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 use std::{
-    fs::File,
-    io::{BufReader, BufWriter, Read, Write},
+    fs::{File, OpenOptions},
+    io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write},
     net::IpAddr,
     path::PathBuf,
 };
 
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use time::OffsetDateTime;
 
-use crate::adapters::{CCheckComponent, CCheckPlayer, CCheckResponse};
+use crate::{
+    adapters::{CCheckComponent, CCheckPlayer, CCheckResponse},
+    format::metadata::{ScanMetadata, FORMAT_VERSION},
+    probe::ProbeTiming,
+};
 
 pub struct CCheckFormat {
     pub servers: Vec<Server>,
+    /// `None` for files written before the output envelope existed
+    pub metadata: Option<ScanMetadata>,
 }
 impl CCheckFormat {
     pub fn new(servers: Vec<Server>) -> Self {
-        CCheckFormat { servers }
+        CCheckFormat {
+            servers,
+            metadata: None,
+        }
     }
     pub fn save(&self, path: PathBuf) -> anyhow::Result<()> {
         let mut file = std::fs::File::create(path)?;
-        let json = serde_json::to_string_pretty(&self.servers)?;
+        let json = serde_json::to_string_pretty(&CCheckEnvelope {
+            format_version: FORMAT_VERSION,
+            servers: self.servers.clone(),
+            metadata: self.metadata.clone(),
+        })?;
         let mut buf_write = BufWriter::new(&mut file);
         buf_write.write_all(json.as_bytes())?;
         Ok(())
@@ -46,44 +60,141 @@ impl TryFrom<File> for CCheckFormat {
         let mut reader = BufReader::new(file);
         let mut content = String::new();
         reader.read_to_string(&mut content)?;
-        let servers: Vec<Server> = serde_json::from_str(&content)?;
-        Ok(CCheckFormat { servers })
+        Ok(match serde_json::from_str(&content)? {
+            CCheckFile::Envelope(envelope) => CCheckFormat {
+                servers: envelope.servers,
+                metadata: envelope.metadata,
+            },
+            CCheckFile::Bare(servers) => CCheckFormat::new(servers),
+        })
     }
 }
+/// On-disk layout of `ccheck scan` output
+#[derive(Serialize, Deserialize)]
+struct CCheckEnvelope {
+    format_version: usize,
+    servers: Vec<Server>,
+    metadata: Option<ScanMetadata>,
+}
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum CCheckFile {
+    Envelope(Box<CCheckEnvelope>),
+    /// Output of ccheck 0.2.3 and older
+    Bare(Vec<Server>),
+}
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Server {
-    version: String,
-    protocol: usize,
+    pub version: String,
+    pub protocol: usize,
     pub ip: (IpAddr, u16),
-    players: Vec<Player>,
-    favicon: String,
-    motd: CCheckComponent,
+    #[serde(default)]
+    pub online_players: usize,
+    #[serde(default)]
+    pub max_players: usize,
+    pub players: Vec<Player>,
+    pub favicon: String,
+    pub motd: CCheckComponent,
+    /// Unix timestamp of when the server was probed
+    #[serde(default)]
+    pub timestamp: Option<usize>,
+    /// TCP connect time in milliseconds
+    #[serde(default)]
+    pub connect_ms: Option<f64>,
+    /// Status request round trip in milliseconds
+    #[serde(default)]
+    pub status_ms: Option<f64>,
+    /// Ping/pong round trip in milliseconds
+    #[serde(default)]
+    pub latency_ms: Option<f64>,
+    /// Number of failed attempts before the server responded
+    #[serde(default)]
+    pub retries: u32,
+    /// Whether the server was probed with a PROXY protocol header,
+    /// with `--proxy-protocol-auto` only servers that required one are
+    #[serde(default)]
+    pub proxy_protocol: bool,
 }
 impl Server {
     pub fn from_resp(resp: CCheckResponse, ip: (IpAddr, u16)) -> Self {
+        let timestamp = resp
+            .timing
+            .map(|t| t.probed_at)
+            .unwrap_or_else(|| OffsetDateTime::now_utc().unix_timestamp() as usize);
         let players = resp
             .sample
             .unwrap_or_default()
             .iter()
-            .map(|p| p.clone().into())
+            .map(|p| Player {
+                last_online: timestamp,
+                username: p.name.clone(),
+                uuid: p.id.clone(),
+            })
             .collect();
         let motd = resp.description;
         Server {
             version: resp.version,
             protocol: resp.protocol as usize,
             ip,
+            online_players: resp.online_players,
+            max_players: resp.max_players,
             players,
             favicon: base64::encode(resp.favicon.unwrap_or_else(|| b"".to_vec())),
             motd,
+            timestamp: Some(timestamp),
+            connect_ms: resp.timing.map(|t| t.connect_ms),
+            status_ms: resp.timing.map(|t| t.status_ms),
+            latency_ms: resp.timing.and_then(|t| t.latency_ms),
+            retries: 0,
+            proxy_protocol: resp.proxy_protocol,
         }
     }
+    /// Turns a stored server back into the response it was made from, as far as it can be
+    pub fn into_resp(self) -> CCheckResponse {
+        let favicon = base64::decode(&self.favicon).unwrap_or_default();
+        let sample = self
+            .players
+            .into_iter()
+            .map(|p| CCheckPlayer {
+                name: p.username,
+                id: p.uuid,
+            })
+            .collect::<Vec<_>>();
+        CCheckResponse {
+            version: self.version,
+            protocol: self.protocol as i32,
+            max_players: self.max_players,
+            online_players: self.online_players,
+            sample: (!sample.is_empty()).then_some(sample),
+            description: self.motd,
+            favicon: (!favicon.is_empty()).then_some(favicon),
+            timing: self.timestamp.map(|probed_at| ProbeTiming {
+                probed_at,
+                connect_ms: self.connect_ms.unwrap_or_default(),
+                status_ms: self.status_ms.unwrap_or_default(),
+                latency_ms: self.latency_ms,
+            }),
+            proxy_protocol: self.proxy_protocol,
+        }
+    }
+    /// Hex encoded SHA-256 of the favicon PNG, empty if the server has none
+    pub fn favicon_hash(&self) -> String {
+        favicon_hash(&base64::decode(&self.favicon).unwrap_or_default())
+    }
+}
+/// Hex encoded SHA-256 of a favicon PNG, empty for no favicon
+pub fn favicon_hash(bytes: &[u8]) -> String {
+    match bytes.is_empty() {
+        true => String::new(),
+        false => format!("{:x}", Sha256::digest(bytes)),
+    }
 }
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Player {
-    last_online: usize,
-    username: String,
-    uuid: String,
+    pub last_online: usize,
+    pub username: String,
+    pub uuid: String,
 }
 impl From<CCheckPlayer> for Player {
     fn from(p: CCheckPlayer) -> Self {
@@ -104,10 +215,30 @@ impl CCheckFileHandler {
     pub async fn new(path: PathBuf) -> anyhow::Result<Self> {
         let mut file = File::create(path.clone())?;
         // i've heard a 1mb buffer makes io go vrooom
-        file.write_all(b"[")?;
+        write!(file, "{{\"format_version\":{FORMAT_VERSION},\"servers\":[")?;
         file.flush()?;
         Ok(Self { file, count: 0, writer: None , path})
     }
+    /// Reopens a partially written output, dropping anything past `len` bytes
+    /// (including the closing brackets written by `done`) so writing can continue
+    pub async fn resume(path: PathBuf, len: u64, count: usize) -> anyhow::Result<Self> {
+        let mut file = OpenOptions::new().write(true).open(path.clone())?;
+        file.set_len(len)?;
+        file.seek(SeekFrom::End(0))?;
+        Ok(Self {
+            file,
+            count,
+            writer: None,
+            path,
+        })
+    }
+    /// Flushes buffered servers to disk, returning the length of the file
+    pub async fn flush(&mut self) -> anyhow::Result<u64> {
+        if let Some(writer) = self.writer.as_mut() {
+            writer.flush()?;
+        }
+        Ok(self.file.metadata()?.len())
+    }
     pub async fn write_resp(&mut self, resp: Server) -> anyhow::Result<()> {
         if self.writer.is_none() {
             self.writer = Some(BufWriter::with_capacity(1024 * 1000, self.file.try_clone()?))
@@ -121,11 +252,13 @@ impl CCheckFileHandler {
         self.count += 1;
         Ok(())
     }
-    pub async fn done(&mut self) -> anyhow::Result<()> {
+    pub async fn done(&mut self, metadata: Option<&ScanMetadata>) -> anyhow::Result<()> {
         if let Some(writer) = self.writer.as_mut() {
             writer.flush()?;
         }
-        self.file.write_all(b"]")?;
+        self.file.write_all(b"],\"metadata\":")?;
+        serde_json::to_writer(&mut self.file, &metadata)?;
+        self.file.write_all(b"}")?;
         self.file.flush()?;
         Ok(())
     }
@@ -0,0 +1,107 @@
+// CCheck: utility for scanning and probing minecraft servers
+// Copyright (C) 2022 cleonyc
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::format::metadata::ScanMetadata;
+
+/// Progress of an unfinished scan, written periodically so it can be resumed
+#[derive(Serialize, Deserialize)]
+pub struct Checkpoint {
+    /// Metadata of the original run, including failure counts so far
+    pub metadata: ScanMetadata,
+    /// Base64 encoded little endian bitmap, bit `i` is set once target `i` has been probed
+    pub completed: String,
+    /// Number of servers written to the output
+    pub count: usize,
+    /// Length of the output file, anything after it was written after the checkpoint
+    pub output_len: u64,
+    /// Scan id when writing to a SQLite database
+    pub scan_id: Option<i64>,
+    /// Number of failures written to the failures file
+    pub failures_count: usize,
+    /// Length of the failures file
+    pub failures_len: Option<u64>,
+}
+impl Checkpoint {
+    /// Where the checkpoint for `output` is kept
+    pub fn path(output: &Path) -> PathBuf {
+        let mut path = output.as_os_str().to_owned();
+        path.push(".checkpoint");
+        path.into()
+    }
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        Ok(serde_json::from_reader(BufReader::new(File::open(path)?))?)
+    }
+    /// Writes to a temporary file first so a crash mid-write never leaves a broken checkpoint
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        serde_json::to_writer(&mut writer, self)?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        std::fs::rename(tmp, path)?;
+        Ok(())
+    }
+}
+
+/// Lock free bitmap of which targets have been probed
+#[derive(Debug)]
+pub struct Completed(Vec<AtomicU64>);
+impl Completed {
+    pub fn new(targets: usize) -> Self {
+        Self(
+            (0..targets.div_ceil(64))
+                .map(|_| AtomicU64::new(0))
+                .collect(),
+        )
+    }
+    pub fn set(&self, i: usize) {
+        self.0[i / 64].fetch_or(1 << (i % 64), Ordering::Release);
+    }
+    pub fn is_set(&self, i: usize) -> bool {
+        self.0[i / 64].load(Ordering::Acquire) & (1 << (i % 64)) != 0
+    }
+    pub fn encode(&self) -> String {
+        let bytes = self
+            .0
+            .iter()
+            .flat_map(|word| word.load(Ordering::Acquire).to_le_bytes())
+            .collect::<Vec<u8>>();
+        base64::encode(bytes)
+    }
+    pub fn decode(targets: usize, encoded: &str) -> anyhow::Result<Self> {
+        let bytes = base64::decode(encoded)?;
+        let completed = Self::new(targets);
+        if bytes.len() != completed.0.len() * 8 {
+            anyhow::bail!("checkpoint bitmap does not match the number of targets");
+        }
+        for (word, chunk) in completed.0.iter().zip(bytes.chunks_exact(8)) {
+            word.store(
+                u64::from_le_bytes(chunk.try_into().expect("chunk of 8")),
+                Ordering::Relaxed,
+            );
+        }
+        Ok(completed)
+    }
+}
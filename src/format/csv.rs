@@ -0,0 +1,171 @@
+// CCheck: utility for scanning and probing minecraft servers
+// Copyright (C) 2022 cleonyc
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use std::{
+    fs::{File, OpenOptions},
+    io::{Seek, SeekFrom},
+    path::PathBuf,
+};
+
+use serde::{Deserialize, Serialize};
+use strum_macros::{Display, EnumString};
+
+use crate::format::{ccheck::Server, metadata::ScanMetadata};
+
+/// A column of the flattened CSV/TSV output.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, EnumString, Display)]
+#[strum(serialize_all = "snake_case", ascii_case_insensitive)]
+pub enum Column {
+    /// IP address of the server
+    Ip,
+    /// Port of the server
+    Port,
+    /// Version name reported by the server
+    Version,
+    /// Protocol number reported by the server
+    Protocol,
+    /// Number of players online
+    Online,
+    /// Maximum number of players
+    Max,
+    /// MOTD as plain text, with formatting codes stripped
+    Motd,
+    /// Names of the sampled players, joined with `;`
+    Players,
+    /// Hex encoded SHA-256 of the favicon PNG, empty if the server has none
+    FaviconHash,
+    /// Unix timestamp of when the server was probed
+    Timestamp,
+    /// TCP connect time in milliseconds
+    ConnectMs,
+    /// Status request round trip in milliseconds
+    StatusMs,
+    /// Ping/pong round trip in milliseconds
+    LatencyMs,
+    /// Number of failed attempts before the server responded
+    Retries,
+    /// Whether the server was probed with a PROXY protocol header
+    ProxyProtocol,
+}
+impl Column {
+    pub const ALL: [Column; 15] = [
+        Column::Ip,
+        Column::Port,
+        Column::Version,
+        Column::Protocol,
+        Column::Online,
+        Column::Max,
+        Column::Motd,
+        Column::Players,
+        Column::FaviconHash,
+        Column::Timestamp,
+        Column::ConnectMs,
+        Column::StatusMs,
+        Column::LatencyMs,
+        Column::Retries,
+        Column::ProxyProtocol,
+    ];
+    pub fn value(&self, server: &Server) -> String {
+        match self {
+            Column::Ip => server.ip.0.to_string(),
+            Column::Port => server.ip.1.to_string(),
+            Column::Version => server.version.clone(),
+            Column::Protocol => server.protocol.to_string(),
+            Column::Online => server.online_players.to_string(),
+            Column::Max => server.max_players.to_string(),
+            Column::Motd => server.motd.to_plain(),
+            Column::Players => server
+                .players
+                .iter()
+                .map(|p| p.username.as_str())
+                .collect::<Vec<&str>>()
+                .join(";"),
+            Column::FaviconHash => server.favicon_hash(),
+            Column::Timestamp => server.timestamp.map(|t| t.to_string()).unwrap_or_default(),
+            Column::ConnectMs => format_ms(server.connect_ms),
+            Column::StatusMs => format_ms(server.status_ms),
+            Column::LatencyMs => format_ms(server.latency_ms),
+            Column::Retries => server.retries.to_string(),
+            Column::ProxyProtocol => server.proxy_protocol.to_string(),
+        }
+    }
+}
+fn format_ms(ms: Option<f64>) -> String {
+    ms.map(|ms| format!("{ms:.3}")).unwrap_or_default()
+}
+
+pub struct CsvFileHandler {
+    pub path: PathBuf,
+    pub count: usize,
+    pub columns: Vec<Column>,
+    writer: csv::Writer<File>,
+}
+impl CsvFileHandler {
+    /// Creates the output file and writes the header row.
+    /// Use `b','` as the delimiter for CSV and `b'\t'` for TSV.
+    pub async fn new(path: PathBuf, delimiter: u8, columns: Vec<Column>) -> anyhow::Result<Self> {
+        let mut writer = csv::WriterBuilder::new()
+            .delimiter(delimiter)
+            .buffer_capacity(1024 * 1000)
+            .from_path(path.clone())?;
+        writer.write_record(columns.iter().map(|c| c.to_string()))?;
+        Ok(Self {
+            path,
+            count: 0,
+            columns,
+            writer,
+        })
+    }
+    /// Reopens a partially written output, dropping anything past `len` bytes
+    pub async fn resume(
+        path: PathBuf,
+        delimiter: u8,
+        columns: Vec<Column>,
+        len: u64,
+        count: usize,
+    ) -> anyhow::Result<Self> {
+        let mut file = OpenOptions::new().write(true).open(path.clone())?;
+        file.set_len(len)?;
+        file.seek(SeekFrom::End(0))?;
+        let writer = csv::WriterBuilder::new()
+            .delimiter(delimiter)
+            .buffer_capacity(1024 * 1000)
+            .from_writer(file);
+        Ok(Self {
+            path,
+            count,
+            columns,
+            writer,
+        })
+    }
+    /// Flushes buffered rows to disk, returning the length of the file
+    pub async fn flush(&mut self) -> anyhow::Result<u64> {
+        self.writer.flush()?;
+        Ok(self.writer.get_ref().metadata()?.len())
+    }
+    pub async fn write_resp(&mut self, resp: Server) -> anyhow::Result<()> {
+        self.writer
+            .write_record(self.columns.iter().map(|c| c.value(&resp)))?;
+        self.count += 1;
+        Ok(())
+    }
+    pub async fn done(&mut self, metadata: Option<&ScanMetadata>) -> anyhow::Result<()> {
+        self.writer.flush()?;
+        if let Some(metadata) = metadata {
+            metadata.save_sidecar(&self.path)?;
+        }
+        Ok(())
+    }
+}
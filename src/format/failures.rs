@@ -0,0 +1,88 @@
+// CCheck: utility for scanning and probing minecraft servers
+// Copyright (C) 2022 cleonyc
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufWriter, Seek, SeekFrom, Write},
+    net::IpAddr,
+    path::PathBuf,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::probe::{ProbeError, ProbeErrorKind};
+
+/// A single failed probe, written as one JSON object per line
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Failure {
+    pub ip: (IpAddr, u16),
+    pub error: ProbeErrorKind,
+    pub message: String,
+    /// Number of attempts made before giving up
+    #[serde(default)]
+    pub attempts: u32,
+}
+pub struct FailureFileHandler {
+    pub path: PathBuf,
+    pub count: usize,
+    writer: BufWriter<File>,
+}
+impl FailureFileHandler {
+    pub async fn new(path: PathBuf) -> anyhow::Result<Self> {
+        let file = File::create(path.clone())?;
+        Ok(Self {
+            path,
+            count: 0,
+            writer: BufWriter::with_capacity(1024 * 1000, file),
+        })
+    }
+    /// Reopens a partially written failures file, dropping anything past `len` bytes
+    pub async fn resume(path: PathBuf, len: u64, count: usize) -> anyhow::Result<Self> {
+        let mut file = OpenOptions::new().write(true).open(path.clone())?;
+        file.set_len(len)?;
+        file.seek(SeekFrom::End(0))?;
+        Ok(Self {
+            path,
+            count,
+            writer: BufWriter::with_capacity(1024 * 1000, file),
+        })
+    }
+    /// Flushes buffered failures to disk, returning the length of the file
+    pub async fn flush(&mut self) -> anyhow::Result<u64> {
+        self.writer.flush()?;
+        Ok(self.writer.get_ref().metadata()?.len())
+    }
+    pub async fn write_failure(
+        &mut self,
+        ip: (IpAddr, u16),
+        err: &ProbeError,
+        attempts: u32,
+    ) -> anyhow::Result<()> {
+        let failure = Failure {
+            ip,
+            error: err.kind(),
+            message: err.to_string(),
+            attempts,
+        };
+        serde_json::to_writer(&mut self.writer, &failure)?;
+        self.writer.write_all(b"\n")?;
+        self.count += 1;
+        Ok(())
+    }
+    pub async fn done(&mut self) -> anyhow::Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,124 @@
+// CCheck: utility for scanning and probing minecraft servers
+// Copyright (C) 2022 cleonyc
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fs::File,
+    io::{self, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use time::OffsetDateTime;
+
+use crate::{
+    condition::Conditions,
+    probe::{ProbeErrorKind, Timeouts},
+};
+
+/// Version of the `ccheck scan` output envelope.
+/// Files without an envelope (a bare array of servers) are version 1.
+pub const FORMAT_VERSION: usize = 2;
+
+/// Provenance of a scan: when it ran, what it was run on and with which settings
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScanMetadata {
+    pub ccheck_version: String,
+    /// Unix timestamp of when the scan started
+    pub started_at: usize,
+    /// Unix timestamp of when the scan finished
+    #[serde(default)]
+    pub finished_at: Option<usize>,
+    /// Command line ccheck was invoked with
+    pub arguments: Vec<String>,
+    pub input: PathBuf,
+    /// Hex encoded SHA-256 of the input file
+    pub input_sha256: String,
+    /// Overall timeout per server
+    pub timeout_ms: u64,
+    /// Timeouts for each step of a probe
+    #[serde(default)]
+    pub timeouts: Option<Timeouts>,
+    pub workers: usize,
+    pub conditions: Vec<String>,
+    /// Number of targets read from the input
+    pub targets: usize,
+    /// Number of targets that were not scanned because they are excluded
+    #[serde(default)]
+    pub skipped: usize,
+    /// Number of servers written to the output
+    #[serde(default)]
+    pub found: usize,
+    /// Failed probes per category
+    #[serde(default)]
+    pub failures: HashMap<ProbeErrorKind, usize>,
+    /// Number of probes that were retried
+    #[serde(default)]
+    pub retries: usize,
+}
+impl ScanMetadata {
+    pub fn new(
+        input: &Path,
+        timeouts: &Timeouts,
+        workers: usize,
+        conditions: &Conditions,
+        targets: usize,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            ccheck_version: env!("CARGO_PKG_VERSION").to_string(),
+            started_at: OffsetDateTime::now_utc().unix_timestamp() as usize,
+            finished_at: None,
+            arguments: std::env::args_os()
+                .map(|a: OsString| a.to_string_lossy().into_owned())
+                .collect(),
+            input: input.to_path_buf(),
+            input_sha256: hash_file(input)?,
+            timeout_ms: timeouts.total.as_millis() as u64,
+            timeouts: Some(*timeouts),
+            workers,
+            conditions: conditions
+                .conditions
+                .iter()
+                .map(|c| c.to_string())
+                .collect(),
+            targets,
+            skipped: 0,
+            found: 0,
+            failures: HashMap::new(),
+            retries: 0,
+        })
+    }
+    pub fn finish(&mut self, found: usize, failures: HashMap<ProbeErrorKind, usize>) {
+        self.finished_at = Some(OffsetDateTime::now_utc().unix_timestamp() as usize);
+        self.found = found;
+        self.failures = failures;
+    }
+    /// Writes the metadata next to an output file that has no room for it, as `<output>.meta.json`
+    pub fn save_sidecar(&self, output: &Path) -> anyhow::Result<()> {
+        let mut path = output.as_os_str().to_owned();
+        path.push(".meta.json");
+        let mut writer = BufWriter::new(File::create(path)?);
+        serde_json::to_writer_pretty(&mut writer, self)?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+fn hash_file(path: &Path) -> anyhow::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut BufReader::new(File::open(path)?), &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
@@ -13,17 +13,126 @@
 
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use std::path::PathBuf;
+
 use serde::{Deserialize, Serialize};
 use strum_macros::EnumString;
 
+use self::{
+    ccheck::{CCheckFileHandler, Server},
+    checkpoint::Checkpoint,
+    csv::{Column, CsvFileHandler},
+    metadata::ScanMetadata,
+    sqlite::SqliteFileHandler,
+};
+
 pub mod ccheck;
+pub mod checkpoint;
+pub mod csv;
+pub mod failures;
 pub mod masscan;
+pub mod metadata;
+pub mod sqlite;
 #[derive(Serialize, Deserialize, Clone, EnumString)]
 pub enum Format {
     Masscan,
     CCheck,
 }
-// #[derive(Serialize, Deserialize, Clone, EnumString)]
-// pub enum Ouput {
-//     CCheckJson
-// }
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, EnumString)]
+pub enum OutputFormat {
+    #[strum(ascii_case_insensitive)]
+    Json,
+    #[strum(ascii_case_insensitive)]
+    Csv,
+    #[strum(ascii_case_insensitive)]
+    Tsv,
+    #[strum(ascii_case_insensitive)]
+    Sqlite,
+}
+pub enum Output {
+    CCheckJson(CCheckFileHandler),
+    Csv(Box<CsvFileHandler>),
+    Sqlite(SqliteFileHandler),
+}
+impl Output {
+    /// `columns` is ignored for JSON and SQLite output
+    pub async fn new(
+        path: PathBuf,
+        format: OutputFormat,
+        columns: Vec<Column>,
+    ) -> anyhow::Result<Self> {
+        Ok(match format {
+            OutputFormat::Json => Output::CCheckJson(CCheckFileHandler::new(path).await?),
+            OutputFormat::Csv => {
+                Output::Csv(Box::new(CsvFileHandler::new(path, b',', columns).await?))
+            }
+            OutputFormat::Tsv => {
+                Output::Csv(Box::new(CsvFileHandler::new(path, b'\t', columns).await?))
+            }
+            OutputFormat::Sqlite => Output::Sqlite(SqliteFileHandler::new(path).await?),
+        })
+    }
+    /// Reopens an output that was interrupted at `checkpoint`
+    pub async fn resume(
+        path: PathBuf,
+        format: OutputFormat,
+        columns: Vec<Column>,
+        checkpoint: &Checkpoint,
+    ) -> anyhow::Result<Self> {
+        let (len, count) = (checkpoint.output_len, checkpoint.count);
+        Ok(match format {
+            OutputFormat::Json => {
+                Output::CCheckJson(CCheckFileHandler::resume(path, len, count).await?)
+            }
+            OutputFormat::Csv => Output::Csv(Box::new(
+                CsvFileHandler::resume(path, b',', columns, len, count).await?,
+            )),
+            OutputFormat::Tsv => Output::Csv(Box::new(
+                CsvFileHandler::resume(path, b'\t', columns, len, count).await?,
+            )),
+            OutputFormat::Sqlite => {
+                let scan_id = checkpoint
+                    .scan_id
+                    .ok_or_else(|| anyhow::anyhow!("checkpoint is missing the scan id"))?;
+                Output::Sqlite(SqliteFileHandler::resume(path, scan_id, count).await?)
+            }
+        })
+    }
+    /// Makes everything written so far durable, returning the output's length in bytes
+    pub async fn flush(&mut self) -> anyhow::Result<u64> {
+        match self {
+            Output::CCheckJson(fh) => fh.flush().await,
+            Output::Csv(fh) => fh.flush().await,
+            Output::Sqlite(fh) => fh.flush().await,
+        }
+    }
+    pub fn scan_id(&self) -> Option<i64> {
+        match self {
+            Output::Sqlite(fh) => Some(fh.scan_id),
+            _ => None,
+        }
+    }
+    pub async fn write_resp(&mut self, resp: Server) -> anyhow::Result<()> {
+        match self {
+            Output::CCheckJson(fh) => fh.write_resp(resp).await,
+            Output::Csv(fh) => fh.write_resp(resp).await,
+            Output::Sqlite(fh) => fh.write_resp(resp).await,
+        }
+    }
+    /// JSON output embeds the metadata, SQLite stores it with the scan and
+    /// CSV/TSV write it to a `<output>.meta.json` sidecar file
+    pub async fn done(&mut self, metadata: Option<&ScanMetadata>) -> anyhow::Result<()> {
+        match self {
+            Output::CCheckJson(fh) => fh.done(metadata).await,
+            Output::Csv(fh) => fh.done(metadata).await,
+            Output::Sqlite(fh) => fh.done(metadata).await,
+        }
+    }
+    pub fn count(&self) -> usize {
+        match self {
+            Output::CCheckJson(fh) => fh.count,
+            Output::Csv(fh) => fh.count,
+            Output::Sqlite(fh) => fh.count,
+        }
+    }
+}
@@ -0,0 +1,245 @@
+// CCheck: utility for scanning and probing minecraft servers
+// Copyright (C) 2022 cleonyc
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use std::{
+    net::IpAddr,
+    path::{Path, PathBuf},
+};
+
+use rusqlite::{params, Connection, OpenFlags};
+use time::OffsetDateTime;
+
+use crate::{
+    adapters::is_placeholder_uuid,
+    format::{ccheck::Server, metadata::ScanMetadata},
+};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS scans (
+    id INTEGER PRIMARY KEY,
+    started_at INTEGER NOT NULL,
+    finished_at INTEGER,
+    server_count INTEGER NOT NULL DEFAULT 0,
+    -- JSON encoded scan metadata, see `ScanMetadata`
+    metadata TEXT
+);
+CREATE TABLE IF NOT EXISTS servers (
+    id INTEGER PRIMARY KEY,
+    ip TEXT NOT NULL,
+    port INTEGER NOT NULL,
+    first_seen INTEGER NOT NULL,
+    last_seen INTEGER NOT NULL,
+    UNIQUE (ip, port)
+);
+CREATE TABLE IF NOT EXISTS observations (
+    id INTEGER PRIMARY KEY,
+    scan_id INTEGER NOT NULL REFERENCES scans (id),
+    server_id INTEGER NOT NULL REFERENCES servers (id),
+    observed_at INTEGER NOT NULL,
+    version TEXT NOT NULL,
+    protocol INTEGER NOT NULL,
+    online_players INTEGER NOT NULL,
+    max_players INTEGER NOT NULL,
+    motd TEXT NOT NULL,
+    favicon_hash TEXT NOT NULL,
+    connect_ms REAL,
+    status_ms REAL,
+    latency_ms REAL,
+    retries INTEGER NOT NULL DEFAULT 0,
+    proxy_protocol INTEGER NOT NULL DEFAULT 0
+);
+CREATE INDEX IF NOT EXISTS observations_server ON observations (server_id);
+CREATE TABLE IF NOT EXISTS players (
+    id INTEGER PRIMARY KEY,
+    uuid TEXT NOT NULL UNIQUE,
+    name TEXT NOT NULL,
+    first_seen INTEGER NOT NULL,
+    last_seen INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS sightings (
+    observation_id INTEGER NOT NULL REFERENCES observations (id),
+    player_id INTEGER NOT NULL REFERENCES players (id),
+    PRIMARY KEY (observation_id, player_id)
+);
+CREATE INDEX IF NOT EXISTS sightings_player ON sightings (player_id);
+";
+
+/// Columns added after a table was first created, as (table, column, definition)
+const MIGRATIONS: &[(&str, &str, &str)] = &[
+    (
+        "observations",
+        "retries",
+        "retries INTEGER NOT NULL DEFAULT 0",
+    ),
+    (
+        "observations",
+        "proxy_protocol",
+        "proxy_protocol INTEGER NOT NULL DEFAULT 0",
+    ),
+];
+
+/// Opens (or creates) a ccheck database and makes sure the schema exists.
+pub fn open_db(path: &Path) -> anyhow::Result<Connection> {
+    let conn = Connection::open(path)?;
+    conn.execute_batch(SCHEMA)?;
+    for (table, column, definition) in MIGRATIONS {
+        let exists: bool = conn.query_row(
+            &format!("SELECT COUNT(*) > 0 FROM pragma_table_info('{table}') WHERE name = ?1"),
+            params![column],
+            |row| row.get(0),
+        )?;
+        if !exists {
+            conn.execute_batch(&format!("ALTER TABLE {table} ADD COLUMN {definition}"))?;
+        }
+    }
+    Ok(conn)
+}
+
+/// Reads the servers stored in a ccheck database, for use as monitor targets.
+pub struct SqliteFormat {
+    pub servers: Vec<(IpAddr, u16)>,
+}
+impl SqliteFormat {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        let mut stmt = conn.prepare("SELECT ip, port FROM servers ORDER BY id")?;
+        let servers = stmt
+            .query_map([], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, u16>(1)?))
+            })?
+            .map(|r| {
+                let (ip, port) = r?;
+                Ok((ip.parse::<IpAddr>()?, port))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(SqliteFormat { servers })
+    }
+    pub fn get_ips(&self) -> Vec<(IpAddr, u16)> {
+        self.servers.clone()
+    }
+}
+
+/// Writes scan results into a ccheck database, upserting servers and players
+/// so that repeated scans accumulate history.
+pub struct SqliteFileHandler {
+    pub path: PathBuf,
+    pub count: usize,
+    pub scan_id: i64,
+    conn: Connection,
+}
+impl SqliteFileHandler {
+    pub async fn new(path: PathBuf) -> anyhow::Result<Self> {
+        let conn = open_db(&path)?;
+        conn.execute(
+            "INSERT INTO scans (started_at) VALUES (?1)",
+            params![OffsetDateTime::now_utc().unix_timestamp()],
+        )?;
+        let scan_id = conn.last_insert_rowid();
+        // everything is committed at once in `done`, per-row commits are painfully slow
+        conn.execute_batch("BEGIN")?;
+        Ok(Self {
+            path,
+            count: 0,
+            scan_id,
+            conn,
+        })
+    }
+    /// Continues writing into a scan that was interrupted
+    pub async fn resume(path: PathBuf, scan_id: i64, count: usize) -> anyhow::Result<Self> {
+        let conn = open_db(&path)?;
+        conn.execute_batch("BEGIN")?;
+        Ok(Self {
+            path,
+            count,
+            scan_id,
+            conn,
+        })
+    }
+    /// Commits everything written so far
+    pub async fn flush(&mut self) -> anyhow::Result<u64> {
+        self.conn.execute_batch("COMMIT; BEGIN")?;
+        Ok(0)
+    }
+    pub async fn write_resp(&mut self, resp: Server) -> anyhow::Result<()> {
+        let observed_at = resp
+            .timestamp
+            .map(|t| t as i64)
+            .unwrap_or_else(|| OffsetDateTime::now_utc().unix_timestamp());
+        let server_id: i64 = self.conn.query_row(
+            "INSERT INTO servers (ip, port, first_seen, last_seen) VALUES (?1, ?2, ?3, ?3)
+             ON CONFLICT (ip, port) DO UPDATE SET last_seen = excluded.last_seen
+             RETURNING id",
+            params![resp.ip.0.to_string(), resp.ip.1, observed_at],
+            |row| row.get(0),
+        )?;
+        self.conn.execute(
+            "INSERT INTO observations (scan_id, server_id, observed_at, version, protocol,
+             online_players, max_players, motd, favicon_hash, connect_ms, status_ms, latency_ms,
+             retries, proxy_protocol)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14)",
+            params![
+                self.scan_id,
+                server_id,
+                observed_at,
+                resp.version,
+                resp.protocol as i64,
+                resp.online_players as i64,
+                resp.max_players as i64,
+                resp.motd.to_plain(),
+                resp.favicon_hash(),
+                resp.connect_ms,
+                resp.status_ms,
+                resp.latency_ms,
+                resp.retries,
+                resp.proxy_protocol,
+            ],
+        )?;
+        let observation_id = self.conn.last_insert_rowid();
+        // custom sample lines share the nil uuid, they would all end up as the same player
+        for player in resp
+            .players
+            .iter()
+            .filter(|p| !is_placeholder_uuid(&p.uuid))
+        {
+            let player_id: i64 = self.conn.query_row(
+                "INSERT INTO players (uuid, name, first_seen, last_seen) VALUES (?1, ?2, ?3, ?3)
+                 ON CONFLICT (uuid) DO UPDATE SET name = excluded.name, last_seen = excluded.last_seen
+                 RETURNING id",
+                params![player.uuid, player.username, observed_at],
+                |row| row.get(0),
+            )?;
+            self.conn.execute(
+                "INSERT OR IGNORE INTO sightings (observation_id, player_id) VALUES (?1, ?2)",
+                params![observation_id, player_id],
+            )?;
+        }
+        self.count += 1;
+        Ok(())
+    }
+    pub async fn done(&mut self, metadata: Option<&ScanMetadata>) -> anyhow::Result<()> {
+        let metadata = metadata.map(serde_json::to_string).transpose()?;
+        self.conn.execute(
+            "UPDATE scans SET finished_at = ?1, server_count = ?2, metadata = ?3 WHERE id = ?4",
+            params![
+                OffsetDateTime::now_utc().unix_timestamp(),
+                self.count as i64,
+                metadata,
+                self.scan_id
+            ],
+        )?;
+        self.conn.execute_batch("COMMIT")?;
+        Ok(())
+    }
+}
@@ -0,0 +1,156 @@
+// CCheck: utility for scanning and probing minecraft servers
+// Copyright (C) 2022 cleonyc
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use strum_macros::{Display, EnumString};
+
+/// Buckets for groups that have been idle long enough to refill are dropped
+/// once there are this many, so per-host limits don't grow without bound
+const MAX_IDLE_GROUPS: usize = 100_000;
+
+/// How targets are grouped for `--group-rate`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumString, Display)]
+#[strum(serialize_all = "snake_case", ascii_case_insensitive)]
+pub enum GroupBy {
+    /// Every IP address is its own group
+    Host,
+    /// IPv4 addresses are grouped by /24, IPv6 addresses by /64
+    Subnet,
+}
+impl GroupBy {
+    fn key(&self, ip: IpAddr) -> IpAddr {
+        match (self, ip) {
+            (GroupBy::Host, ip) => ip,
+            (GroupBy::Subnet, IpAddr::V4(ip)) => Ipv4Addr::from(u32::from(ip) & !0xff).into(),
+            (GroupBy::Subnet, IpAddr::V6(ip)) => {
+                Ipv6Addr::from(u128::from(ip) & !(u64::MAX as u128)).into()
+            }
+        }
+    }
+}
+
+/// Connection rate settings shared by `scan` and `monitor`
+#[derive(Debug, Clone, Default)]
+pub struct RateLimit {
+    /// Connections per second across all targets
+    pub rate: Option<f64>,
+    /// Connections that may be made at once after being idle
+    pub burst: Option<u32>,
+    /// Connections per second to a single group of targets
+    pub group_rate: Option<f64>,
+    pub group_by: Option<GroupBy>,
+}
+
+/// Classic token bucket: holds up to `burst` tokens, refilled at `rate` per second
+#[derive(Debug)]
+struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last: Instant,
+}
+impl TokenBucket {
+    fn new(rate: f64, burst: f64) -> Self {
+        Self {
+            rate,
+            burst,
+            tokens: burst,
+            last: Instant::now(),
+        }
+    }
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last = now;
+    }
+    /// Takes a token, returning how long the caller has to wait before it may use it.
+    /// Tokens can go negative, which queues callers up fairly without them having to retry.
+    fn reserve(&mut self) -> Duration {
+        self.refill(Instant::now());
+        self.tokens -= 1.;
+        if self.tokens >= 0. {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+    fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= self.burst
+    }
+}
+
+/// Paces new connections according to a `RateLimit`
+#[derive(Debug)]
+pub struct RateLimiter {
+    global: Option<Mutex<TokenBucket>>,
+    group_rate: f64,
+    group_by: GroupBy,
+    groups: Option<Mutex<HashMap<IpAddr, TokenBucket>>>,
+}
+impl RateLimiter {
+    pub fn new(limit: &RateLimit) -> anyhow::Result<Self> {
+        let burst = limit.burst.unwrap_or(1).max(1) as f64;
+        let global = match limit.rate {
+            Some(rate) if rate < 0. => anyhow::bail!("--rate can't be negative"),
+            Some(rate) if rate > 0. => Some(Mutex::new(TokenBucket::new(rate, burst))),
+            _ => None,
+        };
+        let groups = match limit.group_rate {
+            Some(rate) if rate < 0. => anyhow::bail!("--group-rate can't be negative"),
+            Some(rate) if rate > 0. => Some(Mutex::new(HashMap::new())),
+            _ => None,
+        };
+        Ok(Self {
+            global,
+            group_rate: limit.group_rate.unwrap_or_default(),
+            group_by: limit.group_by.unwrap_or(GroupBy::Subnet),
+            groups,
+        })
+    }
+    /// Waits until a connection to `ip` is allowed
+    pub async fn wait(&self, ip: IpAddr) {
+        // the per group limit goes first so waiting on it doesn't waste global tokens
+        if let Some(groups) = &self.groups {
+            let delay = {
+                let mut groups = groups.lock().unwrap();
+                if groups.len() >= MAX_IDLE_GROUPS {
+                    let now = Instant::now();
+                    groups.retain(|_, bucket| !bucket.is_full(now));
+                }
+                groups
+                    .entry(self.group_by.key(ip))
+                    .or_insert_with(|| TokenBucket::new(self.group_rate, 1.))
+                    .reserve()
+            };
+            sleep(delay).await;
+        }
+        if let Some(global) = &self.global {
+            let delay = global.lock().unwrap().reserve();
+            sleep(delay).await;
+        }
+    }
+}
+async fn sleep(delay: Duration) {
+    if !delay.is_zero() {
+        tokio::time::sleep(delay).await;
+    }
+}
@@ -13,25 +13,51 @@
 
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
-use std::{fs::File, path::PathBuf, str::FromStr, time::Duration};
+use std::{fs::File, net::IpAddr, path::PathBuf, str::FromStr, sync::Arc, time::Duration};
 
 use anyhow::bail;
 use clap::{Parser, Subcommand};
 use format::ccheck::CCheckFormat;
+use owo_colors::OwoColorize;
 use regex::Regex;
 
 use crate::{
     condition::{Actor, Condition, ConditionType, Conditions},
     config::Config,
-    format::masscan::MasscanFormat,
-    mode::Mode,
+    dial::{proxy_protocol::ProxyProtocol, Dialer, Proxy},
+    exclude::ExcludeList,
+    format::{csv::Column, masscan::MasscanFormat, sqlite::SqliteFormat, Output, OutputFormat},
+    limit::{GroupBy, RateLimit},
+    mode::{
+        alerts::AlertPolicy,
+        events::EventType,
+        exit::ExitPolicy,
+        schedule::{IntervalRule, SchedulePolicy},
+        state::StatsPolicy,
+        track::{self, read_players, TrackPolicy},
+        Mode,
+    },
+    notify::{
+        queue::DeliveryPolicy,
+        template::{AlertTemplates, Field},
+    },
+    probe::{ProbeErrorKind, ProxyHeader, Timeouts},
+    report::{ReportFormat, ReportKind, ReportOptions, TargetFilter},
+    retry::RetryPolicy,
 };
 
 pub mod adapters;
 pub mod condition;
 pub mod config;
+pub mod dial;
+pub mod exclude;
 pub mod format;
+pub mod limit;
 pub mod mode;
+pub mod notify;
+pub mod probe;
+pub mod report;
+pub mod retry;
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 struct Args {
@@ -54,74 +80,433 @@ enum Command {
         /// Formatted output file
         #[clap(value_parser)]
         output: PathBuf,
-        /// Timeout for each server in milliseconds
-        /// Default: 1000
-        #[clap(short, long, value_parser, default_value_t = 1000)]
+        /// Format of the output file: `json`, `csv`, `tsv` or `sqlite`
+        /// SQLite output is appended to an existing database, accumulating history across scans
+        #[clap(long, value_parser, default_value = "json")]
+        output_format: OutputFormat,
+        /// Columns to include in csv/tsv output, comma separated
+        /// supported columns: `ip, port, version, protocol, online, max, motd, players, favicon_hash, timestamp, connect_ms, status_ms, latency_ms, retries`
+        /// Default: all columns
+        #[clap(long, value_parser, value_delimiter = ',')]
+        columns: Option<Vec<Column>>,
+        /// File to record failed probes to, one JSON object per line
+        #[clap(long, value_parser)]
+        failures: Option<PathBuf>,
+        /// Overall timeout for each server in milliseconds, connect included
+        /// Default: 3000
+        #[clap(short, long, value_parser, default_value_t = 3000)]
         timeout: u64,
+        /// Timeout for the TCP connect in milliseconds
+        /// Default: 1000
+        #[clap(long, value_parser, default_value_t = 1000)]
+        connect_timeout: u64,
+        /// Milliseconds a server has to start answering after connecting
+        /// Default: 1000
+        #[clap(long, value_parser, default_value_t = 1000)]
+        first_byte_timeout: u64,
+        /// Milliseconds a server may go quiet while answering
+        /// Default: 500
+        #[clap(long, value_parser, default_value_t = 500)]
+        read_idle_timeout: u64,
+        /// Maximum new connections per second, independent of the number of workers
+        /// Default: unlimited for `scan`, `--workers` per second for `monitor`
+        #[clap(long, value_parser)]
+        rate: Option<f64>,
+        /// Connections allowed at once above `--rate` after being idle
+        /// Default: 1
+        #[clap(long, value_parser)]
+        burst: Option<u32>,
+        /// Maximum new connections per second to a single host or subnet, see `--group-by`
+        #[clap(long, value_parser)]
+        group_rate: Option<f64>,
+        /// What `--group-rate` applies to: `host`, or `subnet` (/24 for IPv4, /64 for IPv6)
+        /// Default: subnet
+        #[clap(long, value_parser)]
+        group_by: Option<GroupBy>,
+        /// File of IPs, CIDRs and ranges that must never be connected to, in masscan `excludefile` format.
+        /// Can be given more than once
+        #[clap(long, value_parser)]
+        exclude_file: Vec<PathBuf>,
+        /// Don't exclude reserved, private and otherwise non-routable ranges by default
+        #[clap(long, value_parser, default_value_t = false)]
+        no_default_excludes: bool,
+        /// Local address to connect from, several (comma separated or repeated) are used in turn
+        #[clap(long, value_parser, value_delimiter = ',')]
+        source_ip: Vec<IpAddr>,
+        /// Network interface to send traffic through (Linux only)
+        #[clap(long, value_parser)]
+        interface: Option<String>,
+        /// Proxy to connect through: `socks5://` or `http://[user:pass@]host:port`.
+        /// Several (comma separated or repeated) are used in turn
+        #[clap(long, value_parser, value_delimiter = ',')]
+        proxy: Vec<Proxy>,
+        /// File of proxy URLs, one per line, used in turn along with `--proxy`
+        #[clap(long, value_parser)]
+        proxy_file: Vec<PathBuf>,
+        /// Start every probe with a HAProxy PROXY protocol header: `v1` or `v2`
+        #[clap(long, value_parser)]
+        proxy_protocol: Option<ProxyProtocol>,
+        /// Probe servers that hang up without answering again, this time with a PROXY protocol header
+        /// (`--proxy-protocol` picks the version, v2 by default)
+        #[clap(long, value_parser, default_value_t = false)]
+        proxy_protocol_auto: bool,
+        /// Attempts per server before giving up on it, failures are retried after the other servers
+        /// Default: 1 (no retries)
+        #[clap(long, value_parser, default_value_t = 1)]
+        max_attempts: u32,
+        /// Failure categories to retry, comma separated
+        /// supported: `connect_timeout, refused, reset, unreachable, status_timeout, not_minecraft, protocol, io`
+        /// Default: connect_timeout,status_timeout,reset,refused
+        #[clap(long, value_parser, value_delimiter = ',')]
+        retry_on: Option<Vec<ProbeErrorKind>>,
+        /// Milliseconds to wait before the first retry, doubled (with jitter) for each retry after it
+        #[clap(long, value_parser, default_value_t = 500)]
+        retry_backoff: u64,
+        /// Maximum milliseconds to wait between retries
+        #[clap(long, value_parser, default_value_t = 10000)]
+        retry_max_backoff: u64,
+        /// Continue an interrupted scan from the checkpoint saved next to the output file
+        #[clap(long, value_parser, default_value_t = false)]
+        resume: bool,
+        /// Seconds between checkpoints of scan progress, 0 disables checkpointing
+        /// Default: 30
+        #[clap(long, value_parser, default_value_t = 30)]
+        checkpoint_interval: u64,
+        /// Start with few probes in flight and adjust automatically, based on timeouts,
+        /// connect latency and local resource errors. `--workers` becomes the upper limit
+        #[clap(long, value_parser, default_value_t = false)]
+        adaptive: bool,
 
         /// Use progress bar: slows down by a decent bit but has pretty output
         #[clap(short, long, value_parser, default_value_t = false)]
         progress_bar: bool,
         /// conditions to filter out servers
         /// format: `<actor>:<value>,<actor>:<value>`
-        /// supported actors: `PlayerName, PlayerUuid, Version, Protocol, ConnectedPlayers, MaxPlayers, Description, Favicon (base64 encoded)`
+        /// supported actors: `PlayerName, PlayerUuid, Version, Protocol, ConnectedPlayers, MaxPlayers, Description, Favicon (base64 encoded), Latency (ms)`
+        /// numeric actors also accept comparisons, e.g. `Latency:<50` or `ConnectedPlayers:>=10`
         #[clap(long, value_parser)]
         exclude: Option<Vec<String>>,
         /// regex conditions to filter out servers
         /// format: `<actor>:<value>,<actor>:<value>`
-        /// supported actors: `PlayerName, PlayerUuid, Version, Protocol, ConnectedPlayers, MaxPlayers, Description, Favicon (base64 encoded)`
+        /// supported actors: `PlayerName, PlayerUuid, Version, Protocol, ConnectedPlayers, MaxPlayers, Description, Favicon (base64 encoded), Latency (ms)`
+        /// numeric actors also accept comparisons, e.g. `Latency:<50` or `ConnectedPlayers:>=10`
         #[clap(long, value_parser)]
         exclude_regex: Option<Vec<String>>,
         /// regex conditions to filter in servers
         /// format: `<actor>:<value>,<actor>:<value>`
-        /// supported actors: `PlayerName, PlayerUuid, Version, Protocol, ConnectedPlayers, MaxPlayers, Description, Favicon (base64 encoded)`
+        /// supported actors: `PlayerName, PlayerUuid, Version, Protocol, ConnectedPlayers, MaxPlayers, Description, Favicon (base64 encoded), Latency (ms)`
+        /// numeric actors also accept comparisons, e.g. `Latency:<50` or `ConnectedPlayers:>=10`
         #[clap(long, value_parser)]
         include_regex: Option<Vec<String>>,
         /// conditions to filter in servers
         /// format: `<actor>:<value>,<actor>:<value>`
-        /// supported actors: `PlayerName, PlayerUuid, Version, Protocol, ConnectedPlayers, MaxPlayers, Description, Favicon (base64 encoded)`
+        /// supported actors: `PlayerName, PlayerUuid, Version, Protocol, ConnectedPlayers, MaxPlayers, Description, Favicon (base64 encoded), Latency (ms)`
+        /// numeric actors also accept comparisons, e.g. `Latency:<50` or `ConnectedPlayers:>=10`
         #[clap(long, value_parser)]
         include: Option<Vec<String>>,
     },
     /// Use to continually monitor for a condition of a minecraft server (e.g. a player logging on)
     Monitor {
-        /// JSON file outputed by `masscan` or `ccheck scan`
+        /// JSON file outputed by `masscan` or `ccheck scan`, or a `ccheck scan` SQLite database
         #[clap(value_parser)]
         input: PathBuf,
 
-        /// Number of async tasks to scan with.
+        /// Number of servers checked at once, also the checks per second when `--rate` isn't given
         #[clap(short, long, value_parser, default_value_t = 30)]
         workers: usize,
-        /// Timeout for each server in milliseconds
-        #[clap(short, long, value_parser, default_value_t = 1000)]
+        /// Seconds between checks of each server
+        #[clap(long, value_parser, default_value_t = 10.)]
+        interval: f64,
+        /// Servers with an interval of their own, as `<ip, cidr or first-last>=<seconds>`, comma separated.
+        /// The most specific range applies
+        #[clap(long, value_parser, value_delimiter = ',')]
+        target_interval: Vec<IntervalRule>,
+        /// Fraction of the interval checks are moved by at random, to spread them out
+        #[clap(long, value_parser, default_value_t = 0.1)]
+        interval_jitter: f64,
+        /// Offline servers are checked half as often after every failed check, down to once every this many seconds.
+        /// 0 keeps checking them at their usual interval
+        #[clap(long, value_parser, default_value_t = 600.)]
+        offline_max_interval: f64,
+        /// Overall timeout for each server in milliseconds, connect included
+        /// Default: 3000
+        #[clap(short, long, value_parser, default_value_t = 3000)]
         timeout: u64,
-        /// Webhook url to send alerts if server matching conditions is found
-        #[clap(short, long, value_parser)]
-        webhook_url: Option<String>,
+        /// Timeout for the TCP connect in milliseconds
+        /// Default: 1000
+        #[clap(long, value_parser, default_value_t = 1000)]
+        connect_timeout: u64,
+        /// Milliseconds a server has to start answering after connecting
+        /// Default: 1000
+        #[clap(long, value_parser, default_value_t = 1000)]
+        first_byte_timeout: u64,
+        /// Milliseconds a server may go quiet while answering
+        /// Default: 500
+        #[clap(long, value_parser, default_value_t = 500)]
+        read_idle_timeout: u64,
+        /// Maximum new connections per second, independent of the number of workers
+        /// Default: unlimited for `scan`, `--workers` per second for `monitor`
+        #[clap(long, value_parser)]
+        rate: Option<f64>,
+        /// Connections allowed at once above `--rate` after being idle
+        /// Default: 1
+        #[clap(long, value_parser)]
+        burst: Option<u32>,
+        /// Maximum new connections per second to a single host or subnet, see `--group-by`
+        #[clap(long, value_parser)]
+        group_rate: Option<f64>,
+        /// What `--group-rate` applies to: `host`, or `subnet` (/24 for IPv4, /64 for IPv6)
+        /// Default: subnet
+        #[clap(long, value_parser)]
+        group_by: Option<GroupBy>,
+        /// File of IPs, CIDRs and ranges that must never be connected to, in masscan `excludefile` format.
+        /// Can be given more than once
+        #[clap(long, value_parser)]
+        exclude_file: Vec<PathBuf>,
+        /// Don't exclude reserved, private and otherwise non-routable ranges by default
+        #[clap(long, value_parser, default_value_t = false)]
+        no_default_excludes: bool,
+        /// Local address to connect from, several (comma separated or repeated) are used in turn
+        #[clap(long, value_parser, value_delimiter = ',')]
+        source_ip: Vec<IpAddr>,
+        /// Network interface to send traffic through (Linux only)
+        #[clap(long, value_parser)]
+        interface: Option<String>,
+        /// Proxy to connect through: `socks5://` or `http://[user:pass@]host:port`.
+        /// Several (comma separated or repeated) are used in turn
+        #[clap(long, value_parser, value_delimiter = ',')]
+        proxy: Vec<Proxy>,
+        /// File of proxy URLs, one per line, used in turn along with `--proxy`
+        #[clap(long, value_parser)]
+        proxy_file: Vec<PathBuf>,
+        /// Start every probe with a HAProxy PROXY protocol header: `v1` or `v2`
+        #[clap(long, value_parser)]
+        proxy_protocol: Option<ProxyProtocol>,
+        /// Probe servers that hang up without answering again, this time with a PROXY protocol header
+        /// (`--proxy-protocol` picks the version, v2 by default)
+        #[clap(long, value_parser, default_value_t = false)]
+        proxy_protocol_auto: bool,
+        /// Attempts per server before giving up on it, failures are retried after the other servers
+        /// Default: 1 (no retries)
+        #[clap(long, value_parser, default_value_t = 1)]
+        max_attempts: u32,
+        /// Failure categories to retry, comma separated
+        /// supported: `connect_timeout, refused, reset, unreachable, status_timeout, not_minecraft, protocol, io`
+        /// Default: connect_timeout,status_timeout,reset,refused
+        #[clap(long, value_parser, value_delimiter = ',')]
+        retry_on: Option<Vec<ProbeErrorKind>>,
+        /// Milliseconds to wait before the first retry, doubled (with jitter) for each retry after it
+        #[clap(long, value_parser, default_value_t = 500)]
+        retry_backoff: u64,
+        /// Maximum milliseconds to wait between retries
+        #[clap(long, value_parser, default_value_t = 10000)]
+        retry_max_backoff: u64,
+        #[clap(flatten)]
+        notifications: Box<NotifyArgs>,
         /// Dont exit if server matching conditions is found
         #[clap(long, value_parser, default_value_t = false)]
         dont_exit_on_success: bool,
+        /// Exit once this many servers matched the conditions
+        /// Default: 1
+        #[clap(long, value_parser, conflicts_with_all = &["dont-exit-on-success", "exit-on-clear"])]
+        exit_after_matches: Option<usize>,
+        /// Exit once a server that matched the conditions stops matching or goes offline
+        /// (exit code 3), instead of when it starts matching
+        #[clap(long, value_parser, default_value_t = false)]
+        exit_on_clear: bool,
+        /// Exit after this many seconds (exit code 4)
+        #[clap(long, value_parser)]
+        time_limit: Option<f64>,
+        /// Exit once every server was checked this many times (exit code 5)
+        #[clap(long, value_parser)]
+        cycles: Option<usize>,
+        /// Print the servers the monitor exited over as JSON on stdout, everything else goes to stderr
+        #[clap(long, value_parser, default_value_t = false)]
+        json: bool,
+        #[clap(flatten)]
+        tracking: Box<TrackArgs>,
+        /// Kinds of changes to report, comma separated
+        /// supported: `online, offline, player_joined, player_left, player_count, version_changed, motd_changed, favicon_changed, matched, still_matching, cleared, session_started, session_ended`
+        /// Default: all of them
+        #[clap(long, value_parser, value_delimiter = ',')]
+        events: Vec<EventType>,
+        /// File to append events to, one JSON object per line
+        #[clap(long, value_parser)]
+        events_file: Option<PathBuf>,
+        /// Report a `player_count` event when a server's player count goes past this number,
+        /// several can be given comma separated
+        #[clap(long, value_parser, value_delimiter = ',')]
+        player_threshold: Vec<usize>,
+        /// Seconds after an alert about a server matching the conditions before it can alert again.
+        /// Servers that start matching again sooner are alerted once the cooldown is over
+        /// Default: 0
+        #[clap(long, value_parser, default_value_t = 0)]
+        alert_cooldown: usize,
+        /// Seconds between reminders that a server still matches the conditions, 0 for none
+        /// Default: 0
+        #[clap(long, value_parser, default_value_t = 0)]
+        alert_reminder: usize,
+        /// Also alert when a server stops matching the conditions or goes offline
+        #[clap(long, value_parser, default_value_t = false)]
+        alert_on_clear: bool,
+        /// SQLite database to keep the state of monitored servers and the event history in,
+        /// so a restarted monitor doesn't alert about the same things again. Can be the scan database
+        #[clap(long, value_parser)]
+        state: Option<PathBuf>,
+        /// Forget the state saved in `--state` and start over, the event history is kept
+        #[clap(long, value_parser, default_value_t = false, requires = "state")]
+        fresh_state: bool,
+        /// Also keep the result of every check and every outage in `--state`, see `ccheck report`
+        #[clap(long, value_parser, default_value_t = false, requires = "state")]
+        stats: bool,
+        /// Days checks and outages are kept for in `--state`, 0 keeps them forever
+        #[clap(long, value_parser, default_value_t = 30)]
+        stats_retention: usize,
         /// conditions to filter out servers
         /// format: `<actor>:<value>,<actor>:<value>`
-        /// supported actors: `PlayerName, PlayerUuid, Version, Protocol, ConnectedPlayers, MaxPlayers, Description, Favicon (base64 encoded)`
+        /// supported actors: `PlayerName, PlayerUuid, Version, Protocol, ConnectedPlayers, MaxPlayers, Description, Favicon (base64 encoded), Latency (ms)`
+        /// numeric actors also accept comparisons, e.g. `Latency:<50` or `ConnectedPlayers:>=10`
         #[clap(long, value_parser)]
         exclude: Option<Vec<String>>,
         /// regex conditions to filter out servers
         /// format: `<actor>:<value>,<actor>:<value>`
-        /// supported actors: `PlayerName, PlayerUuid, Version, Protocol, ConnectedPlayers, MaxPlayers, Description, Favicon (base64 encoded)`
+        /// supported actors: `PlayerName, PlayerUuid, Version, Protocol, ConnectedPlayers, MaxPlayers, Description, Favicon (base64 encoded), Latency (ms)`
+        /// numeric actors also accept comparisons, e.g. `Latency:<50` or `ConnectedPlayers:>=10`
         #[clap(long, value_parser)]
         exclude_regex: Option<Vec<String>>,
         /// regex conditions to filter in servers
         /// format: `<actor>:<value>,<actor>:<value>`
-        /// supported actors: `PlayerName, PlayerUuid, Version, Protocol, ConnectedPlayers, MaxPlayers, Description, Favicon (base64 encoded)`
+        /// supported actors: `PlayerName, PlayerUuid, Version, Protocol, ConnectedPlayers, MaxPlayers, Description, Favicon (base64 encoded), Latency (ms)`
+        /// numeric actors also accept comparisons, e.g. `Latency:<50` or `ConnectedPlayers:>=10`
         #[clap(long, value_parser)]
         include_regex: Option<Vec<String>>,
         /// conditions to filter in servers
         /// format: `<actor>:<value>,<actor>:<value>`
-        /// supported actors: `PlayerName, PlayerUuid, Version, Protocol, ConnectedPlayers, MaxPlayers, Description, Favicon (base64 encoded)`
+        /// supported actors: `PlayerName, PlayerUuid, Version, Protocol, ConnectedPlayers, MaxPlayers, Description, Favicon (base64 encoded), Latency (ms)`
+        /// numeric actors also accept comparisons, e.g. `Latency:<50` or `ConnectedPlayers:>=10`
         #[clap(long, value_parser)]
         include: Option<Vec<String>>,
     },
+    /// Use to convert `ccheck scan` JSON output into CSV or TSV
+    Convert {
+        /// JSON file outputed by `ccheck scan`
+        #[clap(value_parser)]
+        input: PathBuf,
+        /// Converted output file
+        #[clap(value_parser)]
+        output: PathBuf,
+        /// Format of the output file: `csv`, `tsv` or `sqlite`
+        #[clap(long, value_parser, default_value = "csv")]
+        output_format: OutputFormat,
+        /// Columns to include, comma separated
+        /// supported columns: `ip, port, version, protocol, online, max, motd, players, favicon_hash, timestamp, connect_ms, status_ms, latency_ms, retries`
+        /// Default: all columns
+        #[clap(long, value_parser, value_delimiter = ',')]
+        columns: Option<Vec<Column>>,
+    },
+    /// Use to look up where players tracked by `ccheck monitor --track` are
+    Where {
+        /// Snapshot file written by `ccheck monitor --track-snapshot`
+        #[clap(value_parser)]
+        snapshot: PathBuf,
+        /// Usernames or UUIDs to look up
+        /// Default: every tracked player
+        #[clap(value_parser)]
+        players: Vec<String>,
+        /// Also list past sessions, newest first
+        #[clap(long, value_parser, default_value_t = false)]
+        history: bool,
+        /// Print the players' records as JSON
+        #[clap(long, value_parser, default_value_t = false)]
+        json: bool,
+    },
+    /// Use to summarize the uptime of servers checked by `ccheck monitor --stats`
+    Report {
+        /// State database given to `ccheck monitor --state`
+        #[clap(value_parser)]
+        state: PathBuf,
+        /// Seconds the report covers, up to `--end`
+        #[clap(long, value_parser, default_value_t = 86400)]
+        window: usize,
+        /// Unix timestamp the report ends at
+        /// Default: now
+        #[clap(long, value_parser)]
+        end: Option<usize>,
+        /// Servers to report on, as `<ip>` or `<ip>:<port>`, comma separated
+        /// Default: all of them
+        #[clap(long, value_parser, value_delimiter = ',')]
+        server: Vec<TargetFilter>,
+        /// List every outage instead of the summary
+        #[clap(long, value_parser, default_value_t = false, conflicts_with = "series")]
+        outages: bool,
+        /// List checks, latency and player counts over time instead of the summary,
+        /// summed up in buckets of this many seconds
+        #[clap(long, value_parser)]
+        series: Option<usize>,
+        /// Format of the report: `table`, `json` or `csv`
+        #[clap(long, value_parser, default_value = "table")]
+        format: ReportFormat,
+        /// File to write the report to
+        /// Default: stdout
+        #[clap(short, long, value_parser)]
+        output: Option<PathBuf>,
+    },
+}
+
+/// Where monitor events are sent and how
+#[derive(clap::Args, Debug)]
+struct NotifyArgs {
+    /// Discord webhook url to send events to, same as `--notify discord:<url>`
+    #[clap(long, value_parser)]
+    webhook_url: Option<String>,
+    /// Where to send events as `<kind>:<target>`, can be given multiple times
+    /// supported: `json:<url>, discord:<url>, slack:<url>, ntfy:<url>, matrix:<url>, smtp://..., smtps://..., exec:<command>`
+    #[clap(long, value_parser)]
+    notify: Vec<String>,
+    /// Handlebars template for the title of notifications, e.g. `{{ip}}:{{port}} is up`
+    /// See the README for what templates can refer to
+    #[clap(long, value_parser)]
+    alert_title: Option<String>,
+    /// Handlebars template for the body of notifications
+    #[clap(long, value_parser)]
+    alert_body: Option<String>,
+    /// Field added to notifications, as `<name>=<handlebars template>`, can be given multiple times
+    #[clap(long, value_parser)]
+    alert_field: Vec<Field>,
+    /// Attempts per notification and notifier before giving up on it
+    #[clap(long, value_parser, default_value_t = 10)]
+    notify_max_attempts: u32,
+    /// Milliseconds to wait before retrying a notification, doubled (with jitter) for each retry after it.
+    /// HTTP 429 `Retry-After` takes precedence
+    #[clap(long, value_parser, default_value_t = 1000)]
+    notify_backoff: u64,
+    /// Maximum milliseconds to wait between retries of a notification
+    #[clap(long, value_parser, default_value_t = 300000)]
+    notify_max_backoff: u64,
+    /// Directory to keep unsent notifications in, they are sent on the next start if ccheck exits before
+    #[clap(long, value_parser)]
+    notify_spool: Option<PathBuf>,
+    /// File to append notifications that were given up on to, one JSON object per line
+    #[clap(long, value_parser)]
+    notify_dead_letter: Option<PathBuf>,
+}
+
+/// Players monitor follows across all servers
+#[derive(clap::Args, Debug)]
+struct TrackArgs {
+    /// Usernames or UUIDs of players to follow across all servers, comma separated or repeated
+    #[clap(long, value_parser, value_delimiter = ',')]
+    track: Vec<String>,
+    /// File of players to track, one username or UUID per line
+    #[clap(long, value_parser)]
+    track_file: Option<PathBuf>,
+    /// JSON file to keep where the tracked players are and have been in, see `ccheck where`
+    #[clap(long, value_parser)]
+    track_snapshot: Option<PathBuf>,
+    /// Seconds a tracked player may be missing from a server that only lists some of its players
+    /// before their session there ends
+    #[clap(long, value_parser, default_value_t = 300)]
+    track_timeout: usize,
 }
 
 #[tokio::main(flavor = "multi_thread", worker_threads = 10)]
@@ -132,14 +517,71 @@ async fn main() -> anyhow::Result<()> {
             Command::Monitor {
                 input,
                 workers,
+                interval,
+                target_interval,
+                interval_jitter,
+                offline_max_interval,
                 timeout,
+                connect_timeout,
+                first_byte_timeout,
+                read_idle_timeout,
+                rate,
+                burst,
+                group_rate,
+                group_by,
+                exclude_file,
+                no_default_excludes,
+                source_ip,
+                interface,
+                proxy,
+                proxy_file,
+                proxy_protocol,
+                proxy_protocol_auto,
+                max_attempts,
+                retry_on,
+                retry_backoff,
+                retry_max_backoff,
                 exclude,
                 exclude_regex,
                 include_regex,
                 include,
-                webhook_url,
+                notifications,
                 dont_exit_on_success,
+                exit_after_matches,
+                exit_on_clear,
+                time_limit,
+                cycles,
+                json,
+                tracking,
+                events,
+                events_file,
+                player_threshold,
+                alert_cooldown,
+                alert_reminder,
+                alert_on_clear,
+                state,
+                fresh_state,
+                stats,
+                stats_retention,
             } => {
+                let NotifyArgs {
+                    webhook_url,
+                    notify,
+                    alert_title,
+                    alert_body,
+                    alert_field,
+                    notify_max_attempts,
+                    notify_backoff,
+                    notify_max_backoff,
+                    notify_spool,
+                    notify_dead_letter,
+                } = *notifications;
+                let TrackArgs {
+                    track,
+                    track_file,
+                    track_snapshot,
+                    track_timeout,
+                } = *tracking;
                 let mut conds = vec![];
                 for i in exclude.unwrap_or_default() {
                     let vals = i.split(':').collect::<Vec<&str>>();
@@ -180,7 +622,9 @@ async fn main() -> anyhow::Result<()> {
                 let conds = Conditions { conditions: conds };
                 let cnf = Config {
                     addrs: {
-                        if let Ok(mformat) = MasscanFormat::try_from(
+                        if let Ok(sformat) = SqliteFormat::open(&input) {
+                            sformat.get_ips()
+                        } else if let Ok(mformat) = MasscanFormat::try_from(
                             File::open(input.clone()).expect("invalid input file"),
                         ) {
                             mformat.get_ips()
@@ -195,19 +639,124 @@ async fn main() -> anyhow::Result<()> {
                     },
                     mode: Mode::Monitor {
                         workers,
+                        schedule: SchedulePolicy {
+                            interval,
+                            rules: target_interval,
+                            jitter: interval_jitter,
+                            offline_max: offline_max_interval,
+                        },
                         webhook_url,
+                        notify,
                         exit_on_success: !dont_exit_on_success,
+                        exit: Box::new(ExitPolicy {
+                            matches: match exit_on_clear {
+                                true => 0,
+                                false => exit_after_matches.unwrap_or(1),
+                            },
+                            on_clear: exit_on_clear,
+                            time_limit,
+                            cycles,
+                            json,
+                        }),
+                        stats: StatsPolicy {
+                            record: stats,
+                            retention: stats_retention,
+                        },
+                        track: Box::new(TrackPolicy {
+                            players: match track_file {
+                                Some(path) => [track, read_players(&path)?].concat(),
+                                None => track,
+                            },
+                            snapshot: track_snapshot,
+                            timeout: track_timeout,
+                        }),
+                        events,
+                        events_file,
+                        player_thresholds: player_threshold,
+                        alerts: AlertPolicy {
+                            cooldown: alert_cooldown,
+                            reminder: alert_reminder,
+                            on_clear: alert_on_clear,
+                        },
+                        templates: Box::new(AlertTemplates {
+                            title: alert_title,
+                            body: alert_body,
+                            fields: alert_field,
+                        }),
+                        state,
+                        fresh_state,
+                        delivery: Box::new(DeliveryPolicy {
+                            max_attempts: notify_max_attempts,
+                            backoff: notify_backoff,
+                            max_backoff: notify_max_backoff,
+                            spool: notify_spool,
+                            dead_letter: notify_dead_letter,
+                        }),
                     },
                     conditions: conds,
-                    timeout: Duration::from_millis(timeout),
+                    timeouts: Timeouts {
+                        connect: Duration::from_millis(connect_timeout),
+                        first_byte: Duration::from_millis(first_byte_timeout),
+                        read_idle: Duration::from_millis(read_idle_timeout),
+                        total: Duration::from_millis(timeout),
+                    },
+                    rate_limit: RateLimit {
+                        rate,
+                        burst,
+                        group_rate,
+                        group_by,
+                    },
+                    exclude: ExcludeList::new(!no_default_excludes, &exclude_file)?,
+                    dialer: Arc::new(Dialer::new(
+                        &source_ip,
+                        interface,
+                        Proxy::list(proxy, &proxy_file)?,
+                    )?),
+                    proxy_header: match (proxy_protocol, proxy_protocol_auto) {
+                        (version, true) => ProxyHeader::Auto(version.unwrap_or(ProxyProtocol::V2)),
+                        (Some(version), false) => ProxyHeader::Always(version),
+                        (None, false) => ProxyHeader::Never,
+                    },
+                    retry: RetryPolicy {
+                        max_attempts: max_attempts.max(1),
+                        retry_on: retry_on
+                            .unwrap_or_else(|| RetryPolicy::DEFAULT_RETRY_ON.to_vec()),
+                        backoff: Duration::from_millis(retry_backoff),
+                        max_backoff: Duration::from_millis(retry_max_backoff),
+                    },
                 };
                 cnf.run().await?;
             }
             Command::Scan {
                 timeout,
+                connect_timeout,
+                first_byte_timeout,
+                read_idle_timeout,
+                rate,
+                burst,
+                group_rate,
+                group_by,
+                exclude_file,
+                no_default_excludes,
+                source_ip,
+                interface,
+                proxy,
+                proxy_file,
+                proxy_protocol,
+                proxy_protocol_auto,
+                max_attempts,
+                retry_on,
+                retry_backoff,
+                retry_max_backoff,
                 input,
                 workers,
                 output,
+                output_format,
+                columns,
+                failures,
+                resume,
+                checkpoint_interval,
+                adaptive,
                 exclude,
                 exclude_regex,
                 include_regex,
@@ -253,11 +802,47 @@ async fn main() -> anyhow::Result<()> {
                 }
                 let conds = Conditions { conditions: conds };
                 let format = Config {
-                    timeout: Duration::from_millis(timeout),
+                    timeouts: Timeouts {
+                        connect: Duration::from_millis(connect_timeout),
+                        first_byte: Duration::from_millis(first_byte_timeout),
+                        read_idle: Duration::from_millis(read_idle_timeout),
+                        total: Duration::from_millis(timeout),
+                    },
+                    rate_limit: RateLimit {
+                        rate,
+                        burst,
+                        group_rate,
+                        group_by,
+                    },
+                    exclude: ExcludeList::new(!no_default_excludes, &exclude_file)?,
+                    dialer: Arc::new(Dialer::new(
+                        &source_ip,
+                        interface,
+                        Proxy::list(proxy, &proxy_file)?,
+                    )?),
+                    proxy_header: match (proxy_protocol, proxy_protocol_auto) {
+                        (version, true) => ProxyHeader::Auto(version.unwrap_or(ProxyProtocol::V2)),
+                        (Some(version), false) => ProxyHeader::Always(version),
+                        (None, false) => ProxyHeader::Never,
+                    },
+                    retry: RetryPolicy {
+                        max_attempts: max_attempts.max(1),
+                        retry_on: retry_on
+                            .unwrap_or_else(|| RetryPolicy::DEFAULT_RETRY_ON.to_vec()),
+                        backoff: Duration::from_millis(retry_backoff),
+                        max_backoff: Duration::from_millis(retry_max_backoff),
+                    },
                     mode: Mode::Scanner {
                         workers,
+                        input: input.clone(),
                         output,
+                        output_format,
+                        columns: columns.unwrap_or_else(|| Column::ALL.to_vec()),
+                        failures,
                         progress_bar,
+                        resume,
+                        checkpoint_interval,
+                        adaptive,
                     },
                     addrs: MasscanFormat::try_from(
                         File::open(input).expect("invalid masscan file"),
@@ -267,6 +852,66 @@ async fn main() -> anyhow::Result<()> {
                 };
                 format.run().await?;
             }
+            Command::Convert {
+                input,
+                output,
+                output_format,
+                columns,
+            } => {
+                let CCheckFormat { servers, metadata } =
+                    CCheckFormat::try_from(File::open(input).expect("invalid input file"))
+                        .expect("input file is of invalid format");
+                let mut out = Output::new(
+                    output,
+                    output_format,
+                    columns.unwrap_or_else(|| Column::ALL.to_vec()),
+                )
+                .await?;
+                for sv in servers {
+                    out.write_resp(sv).await?;
+                }
+                out.done(metadata.as_ref()).await?;
+                println!(
+                    "{} Converted {} servers",
+                    "::".green().bold(),
+                    out.count().cyan()
+                );
+            }
+            Command::Where {
+                snapshot,
+                players,
+                history,
+                json,
+            } => {
+                track::query(&snapshot, &players, history, json)?;
+            }
+            Command::Report {
+                state,
+                window,
+                end,
+                server,
+                outages,
+                series,
+                format,
+                output,
+            } => {
+                let kind = match (outages, series) {
+                    (true, _) => ReportKind::Outages,
+                    (false, Some(bucket)) => ReportKind::Series(bucket),
+                    (false, None) => ReportKind::Summary,
+                };
+                report::run(
+                    &state,
+                    &ReportOptions {
+                        kind,
+                        window,
+                        end,
+                        targets: server,
+                        format,
+                        output,
+                    },
+                )?;
+            }
         },
         None => {
             bail!("You must specify a valid subcommand. Run with --help parameter for more information.");
@@ -0,0 +1,215 @@
+// CCheck: utility for scanning and probing minecraft servers
+// Copyright (C) 2022 cleonyc
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::{
+    adapters::CCheckResponse,
+    probe::{ProbeError, ProbeErrorKind},
+};
+
+/// How often the concurrency is adjusted
+pub const ADJUST_INTERVAL: Duration = Duration::from_secs(1);
+/// Windows with fewer finished probes than this say too little to act on
+const MIN_SAMPLES: usize = 20;
+/// Concurrency added per window once out of slow start
+const ADDITIVE_STEP: usize = 8;
+/// Concurrency is multiplied by this when congestion is detected
+const DECREASE_FACTOR: f64 = 0.7;
+/// How far the timeout rate may rise above the best seen before it counts as congestion
+const TIMEOUT_TOLERANCE: f64 = 0.05;
+/// Width of a connect latency histogram bucket, in milliseconds
+const BUCKET_MS: f64 = 10.;
+const BUCKETS: usize = 500;
+
+/// Limits how many probes are in flight, adjusting the limit AIMD-style:
+/// it doubles while everything looks healthy (slow start), then grows by a fixed step,
+/// and shrinks multiplicatively when timeouts, connect latency or local resource
+/// errors suggest the scan is overloading this machine or its network.
+#[derive(Debug)]
+pub struct Concurrency {
+    semaphore: Arc<Semaphore>,
+    /// Current limit on probes in flight. When it shrinks, the semaphore
+    /// takes its permits back as probes in flight finish
+    limit: AtomicUsize,
+    min: usize,
+    max: usize,
+    window: Window,
+}
+/// What happened since the last adjustment
+#[derive(Debug)]
+struct Window {
+    probes: AtomicUsize,
+    timeouts: AtomicUsize,
+    local_errors: AtomicUsize,
+    /// Histogram of connect times of successful probes
+    connect_ms: Vec<AtomicUsize>,
+}
+/// State the controller keeps between windows
+#[derive(Debug)]
+pub struct Controller {
+    slow_start: bool,
+    best_timeout_rate: Option<f64>,
+    best_p90_ms: Option<f64>,
+}
+impl Concurrency {
+    pub fn new(start: usize, min: usize, max: usize) -> Self {
+        let min = min.max(1);
+        let max = max.max(min);
+        let start = start.clamp(min, max);
+        Self {
+            semaphore: Arc::new(Semaphore::new(start)),
+            limit: AtomicUsize::new(start),
+            min,
+            max,
+            window: Window {
+                probes: AtomicUsize::new(0),
+                timeouts: AtomicUsize::new(0),
+                local_errors: AtomicUsize::new(0),
+                connect_ms: (0..BUCKETS).map(|_| AtomicUsize::new(0)).collect(),
+            },
+        }
+    }
+    pub fn limit(&self) -> usize {
+        self.limit.load(Ordering::Relaxed)
+    }
+    /// Waits for room for one more probe, which lasts until the permit is dropped
+    pub async fn acquire(&self) -> OwnedSemaphorePermit {
+        self.semaphore
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("semaphore is never closed")
+    }
+    pub fn record(&self, res: &Result<CCheckResponse, ProbeError>) {
+        self.window.probes.fetch_add(1, Ordering::Relaxed);
+        match res {
+            Ok(resp) => {
+                if let Some(timing) = resp.timing {
+                    let bucket = ((timing.connect_ms / BUCKET_MS) as usize).min(BUCKETS - 1);
+                    self.window.connect_ms[bucket].fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            Err(err) if err.is_local() => {
+                self.window.local_errors.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    ProbeErrorKind::ConnectTimeout | ProbeErrorKind::StatusTimeout
+                ) =>
+            {
+                self.window.timeouts.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(_) => {}
+        }
+    }
+    /// Starts a new window and adjusts the limit based on the last one
+    pub fn adjust(&self, controller: &mut Controller) {
+        let probes = self.window.probes.swap(0, Ordering::Relaxed);
+        let timeouts = self.window.timeouts.swap(0, Ordering::Relaxed);
+        let local_errors = self.window.local_errors.swap(0, Ordering::Relaxed);
+        let histogram = self
+            .window
+            .connect_ms
+            .iter()
+            .map(|b| b.swap(0, Ordering::Relaxed))
+            .collect::<Vec<_>>();
+        let limit = self.limit();
+        let congested = if local_errors > 0 {
+            true
+        } else if probes < MIN_SAMPLES {
+            return;
+        } else {
+            let timeout_rate = timeouts as f64 / probes as f64;
+            let p90_ms = percentile(&histogram, 0.9);
+            // baselines creep up slowly, so targets that are simply slower
+            // than earlier ones don't hold the scan back forever
+            let best_timeout_rate = controller
+                .best_timeout_rate
+                .map_or(timeout_rate, |best| (best + 0.01).min(timeout_rate));
+            controller.best_timeout_rate = Some(best_timeout_rate);
+            let slow_latency = match (p90_ms, controller.best_p90_ms) {
+                (Some(p90), Some(best)) => {
+                    controller.best_p90_ms = Some((best * 1.05).min(p90));
+                    p90 > best * 2. + BUCKET_MS * 2.
+                }
+                (Some(p90), None) => {
+                    controller.best_p90_ms = Some(p90);
+                    false
+                }
+                (None, _) => false,
+            };
+            timeout_rate > best_timeout_rate + TIMEOUT_TOLERANCE || slow_latency
+        };
+        let new_limit = if congested {
+            controller.slow_start = false;
+            ((limit as f64 * DECREASE_FACTOR) as usize).max(self.min)
+        } else if controller.slow_start {
+            limit.saturating_mul(2).min(self.max)
+        } else {
+            (limit + ADDITIVE_STEP).min(self.max)
+        };
+        self.set_limit(new_limit);
+    }
+    fn set_limit(&self, new_limit: usize) {
+        let limit = self.limit.swap(new_limit, Ordering::Relaxed);
+        if new_limit > limit {
+            self.semaphore.add_permits(new_limit - limit);
+        } else if new_limit < limit {
+            // takes the permits back as probes in flight finish
+            let semaphore = self.semaphore.clone();
+            let excess = (limit - new_limit) as u32;
+            tokio::spawn(async move {
+                if let Ok(permits) = semaphore.acquire_many_owned(excess).await {
+                    permits.forget();
+                }
+            });
+        }
+    }
+}
+impl Default for Controller {
+    fn default() -> Self {
+        Self {
+            slow_start: true,
+            best_timeout_rate: None,
+            best_p90_ms: None,
+        }
+    }
+}
+/// Upper bound of the bucket the `p` quantile falls in, `None` for an empty histogram
+fn percentile(histogram: &[usize], p: f64) -> Option<f64> {
+    let total = histogram.iter().sum::<usize>();
+    if total == 0 {
+        return None;
+    }
+    let target = (total as f64 * p).ceil() as usize;
+    let mut seen = 0;
+    for (i, count) in histogram.iter().enumerate() {
+        seen += count;
+        if seen >= target {
+            return Some((i + 1) as f64 * BUCKET_MS);
+        }
+    }
+    None
+}
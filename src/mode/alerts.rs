@@ -0,0 +1,154 @@
+// CCheck: utility for scanning and probing minecraft servers
+// Copyright (C) 2022 cleonyc
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+};
+
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+use crate::{
+    adapters::CCheckResponse,
+    mode::events::{Event, EventKind},
+};
+
+/// When alerts about servers matching the conditions are sent
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct AlertPolicy {
+    /// Seconds after an alert before the same server can alert again
+    pub cooldown: usize,
+    /// Seconds between reminders that a server still matches, 0 for none
+    pub reminder: usize,
+    /// Also alert when a server stops matching
+    pub on_clear: bool,
+}
+
+/// Alert state of a server for one set of conditions
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AlertState {
+    /// Unix timestamp of when the server started matching, `None` while it doesn't match
+    pub matching_since: Option<usize>,
+    /// Whether the current match was alerted, matches that start during the cooldown aren't
+    pub notified: bool,
+    /// Unix timestamp of the last alert or reminder
+    pub last_alert: Option<usize>,
+}
+
+/// Turns "server matches the conditions" into alerts that fire once per match,
+/// instead of on every cycle the server keeps matching
+#[derive(Debug)]
+pub struct Alerts {
+    policy: AlertPolicy,
+    /// Conditions the alerts are about, as given on the command line
+    condition: String,
+    servers: HashMap<(IpAddr, u16), AlertState>,
+    /// Servers whose state changed since [`Alerts::take_changed`]
+    changed: HashSet<(IpAddr, u16)>,
+}
+impl Alerts {
+    pub fn new(policy: AlertPolicy, condition: String) -> Self {
+        Self {
+            policy,
+            condition,
+            servers: HashMap::new(),
+            changed: HashSet::new(),
+        }
+    }
+    pub fn condition(&self) -> &str {
+        &self.condition
+    }
+    /// Picks up where a previous run left off
+    pub fn restore(&mut self, server: (IpAddr, u16), state: AlertState) {
+        self.servers.insert(server, state);
+    }
+    /// States of the servers that changed since the last call, for saving them
+    pub fn take_changed(&mut self) -> Vec<((IpAddr, u16), AlertState)> {
+        self.changed
+            .drain()
+            .filter_map(|server| Some((server, *self.servers.get(&server)?)))
+            .collect()
+    }
+    /// Records whether `server` matched in this cycle, `None` if it is offline,
+    /// and returns the alerts that are due
+    pub fn observe(
+        &mut self,
+        server: (IpAddr, u16),
+        matched: Option<&CCheckResponse>,
+    ) -> Option<Event> {
+        let now = OffsetDateTime::now_utc().unix_timestamp() as usize;
+        let policy = self.policy;
+        let state = self.servers.entry(server).or_default();
+        let before = *state;
+        let cooled_down = state
+            .last_alert
+            .is_none_or(|last| now >= last + policy.cooldown);
+        let kind = match matched {
+            Some(resp) => {
+                let since = *state.matching_since.get_or_insert(now);
+                if !state.notified && cooled_down {
+                    state.notified = true;
+                    state.last_alert = Some(now);
+                    Some(EventKind::Matched {
+                        condition: self.condition.clone(),
+                        version: resp.version.clone(),
+                        players: resp
+                            .sample
+                            .iter()
+                            .flatten()
+                            .map(|p| p.name.clone())
+                            .collect(),
+                        motd: resp.description.to_plain(),
+                    })
+                } else if state.notified
+                    && policy.reminder > 0
+                    && state
+                        .last_alert
+                        .is_some_and(|last| now >= last + policy.reminder)
+                {
+                    state.last_alert = Some(now);
+                    Some(EventKind::StillMatching {
+                        condition: self.condition.clone(),
+                        since,
+                    })
+                } else {
+                    None
+                }
+            }
+            None if state.matching_since.is_some() => {
+                let notified = state.notified;
+                state.matching_since = None;
+                state.notified = false;
+                // only clear matches that were alerted, so alerts always come in pairs
+                (notified && policy.on_clear).then(|| {
+                    state.last_alert = Some(now);
+                    EventKind::Cleared {
+                        condition: self.condition.clone(),
+                    }
+                })
+            }
+            None => None,
+        };
+        if *state != before {
+            self.changed.insert(server);
+        }
+        kind.map(|kind| Event {
+            timestamp: now,
+            server,
+            kind,
+        })
+    }
+}
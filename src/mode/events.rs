@@ -0,0 +1,381 @@
+// CCheck: utility for scanning and probing minecraft servers
+// Copyright (C) 2022 cleonyc
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    fs::{File, OpenOptions},
+    io::{BufWriter, Write},
+    net::IpAddr,
+    path::Path,
+};
+
+use serde::{Deserialize, Serialize};
+use strum_macros::{Display as StrumDisplay, EnumDiscriminants, EnumString};
+use time::OffsetDateTime;
+
+use crate::{
+    adapters::{is_placeholder_uuid, CCheckPlayer, CCheckResponse},
+    format::ccheck::favicon_hash,
+    probe::{ProbeError, ProbeErrorKind},
+};
+
+/// Something that changed about a monitored server since the previous cycle
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Event {
+    /// Unix timestamp of when the change was noticed
+    pub timestamp: usize,
+    pub server: (IpAddr, u16),
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, EnumDiscriminants)]
+#[serde(tag = "event", rename_all = "snake_case")]
+#[strum_discriminants(
+    name(EventType),
+    derive(Hash, Serialize, Deserialize, EnumString, StrumDisplay),
+    serde(rename_all = "snake_case"),
+    strum(serialize_all = "snake_case", ascii_case_insensitive)
+)]
+pub enum EventKind {
+    /// The server answered again after failing
+    Online,
+    /// The server stopped answering
+    Offline {
+        error: ProbeErrorKind,
+        message: String,
+    },
+    /// A player showed up in the server's player sample
+    PlayerJoined {
+        name: String,
+        uuid: String,
+    },
+    /// A player is no longer in the server's player sample
+    PlayerLeft {
+        name: String,
+        uuid: String,
+    },
+    /// The number of players online went past `threshold`, in either direction
+    PlayerCount {
+        from: usize,
+        to: usize,
+        threshold: usize,
+    },
+    VersionChanged {
+        from: String,
+        to: String,
+    },
+    MotdChanged {
+        from: String,
+        to: String,
+    },
+    /// Hex encoded SHA-256 of the old and new favicon, empty if there is none
+    FaviconChanged {
+        from: String,
+        to: String,
+    },
+    /// The server started matching `condition`
+    Matched {
+        condition: String,
+        version: String,
+        players: Vec<String>,
+        motd: String,
+    },
+    /// The server still matches `condition`, sent every `--alert-reminder` seconds
+    StillMatching {
+        condition: String,
+        /// Unix timestamp of when the server started matching
+        since: usize,
+    },
+    /// The server no longer matches `condition`, or went offline
+    Cleared {
+        condition: String,
+    },
+    /// A tracked player was seen on the server, starting a session there
+    SessionStarted {
+        name: String,
+        uuid: String,
+    },
+    /// A tracked player is no longer on the server, `duration` is how long they were seen there in seconds
+    SessionEnded {
+        name: String,
+        uuid: String,
+        duration: usize,
+    },
+}
+impl Display for Event {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let server = format!("{}:{}", self.server.0, self.server.1);
+        match &self.kind {
+            EventKind::Online => write!(f, "{server} is online"),
+            EventKind::Offline { message, .. } => write!(f, "{server} went offline ({message})"),
+            EventKind::PlayerJoined { name, .. } => write!(f, "{name} joined {server}"),
+            EventKind::PlayerLeft { name, .. } => write!(f, "{name} left {server}"),
+            EventKind::PlayerCount {
+                from,
+                to,
+                threshold,
+            } => {
+                let direction = if to > from { "above" } else { "below" };
+                write!(
+                    f,
+                    "{server} went {direction} {threshold} players ({from} -> {to})"
+                )
+            }
+            EventKind::VersionChanged { from, to } => {
+                write!(f, "{server} changed version from {from} to {to}")
+            }
+            EventKind::MotdChanged { from, to } => {
+                write!(f, "{server} changed its MOTD from {from:?} to {to:?}")
+            }
+            EventKind::FaviconChanged { .. } => write!(f, "{server} changed its favicon"),
+            EventKind::Matched { condition, .. } => {
+                write!(f, "Found matching server {server}{}", in_parens(condition))
+            }
+            EventKind::StillMatching { condition, .. } => {
+                write!(f, "{server} still matches{}", in_parens(condition))
+            }
+            EventKind::Cleared { condition } => {
+                write!(f, "{server} no longer matches{}", in_parens(condition))
+            }
+            EventKind::SessionStarted { name, .. } => {
+                write!(f, "Tracked player {name} is on {server}")
+            }
+            EventKind::SessionEnded { name, duration, .. } => write!(
+                f,
+                "Tracked player {name} left {server} after {}",
+                format_duration(*duration)
+            ),
+        }
+    }
+}
+/// `secs` as e.g. `1h 2m`, or `42s` when it's under a minute
+pub fn format_duration(secs: usize) -> String {
+    let (days, hours, minutes) = (secs / 86400, secs / 3600 % 24, secs / 60 % 60);
+    match (days, hours, minutes) {
+        (0, 0, 0) => format!("{secs}s"),
+        (0, 0, _) => format!("{minutes}m"),
+        (0, _, _) => format!("{hours}h {minutes}m"),
+        _ => format!("{days}d {hours}h"),
+    }
+}
+/// Unix timestamp as `YYYY-MM-DD HH:MM:SS` in UTC
+pub fn format_timestamp(timestamp: usize) -> String {
+    match OffsetDateTime::from_unix_timestamp(timestamp as i64) {
+        Ok(t) => format!(
+            "{}-{:02}-{:02} {:02}:{:02}:{:02}",
+            t.year(),
+            u8::from(t.month()),
+            t.day(),
+            t.hour(),
+            t.minute(),
+            t.second()
+        ),
+        Err(_) => timestamp.to_string(),
+    }
+}
+fn in_parens(s: &str) -> String {
+    match s.is_empty() {
+        true => String::new(),
+        false => format!(" ({s})"),
+    }
+}
+
+/// What a target looked like the last time it was probed
+#[derive(Debug, Clone)]
+pub struct TargetState {
+    pub online: bool,
+    /// Last successful response, kept while the server is offline to compare against when it is back
+    pub last: Option<CCheckResponse>,
+}
+
+/// Remembers the last state of every target to tell what changed between cycles
+#[derive(Debug, Default)]
+pub struct Tracker {
+    /// Player counts that trigger [`EventKind::PlayerCount`] when crossed
+    thresholds: Vec<usize>,
+    targets: HashMap<(IpAddr, u16), TargetState>,
+    /// Targets whose state changed in a way that matters for diffing since [`Tracker::take_changed`]
+    changed: HashSet<(IpAddr, u16)>,
+}
+impl Tracker {
+    pub fn new(thresholds: Vec<usize>) -> Self {
+        Self {
+            thresholds,
+            targets: HashMap::new(),
+            changed: HashSet::new(),
+        }
+    }
+    /// Picks up where a previous run left off, `state` is diffed against like a previous cycle
+    pub fn restore(&mut self, server: (IpAddr, u16), state: TargetState) {
+        self.targets.insert(server, state);
+    }
+    /// States of the targets that changed since the last call, for saving them.
+    /// Responses that only differ in ways that don't produce events aren't included
+    pub fn take_changed(&mut self) -> Vec<((IpAddr, u16), TargetState)> {
+        self.changed
+            .drain()
+            .filter_map(|server| Some((server, self.targets.get(&server)?.clone())))
+            .collect()
+    }
+    /// Last successful response from `server`
+    pub fn last(&self, server: (IpAddr, u16)) -> Option<&CCheckResponse> {
+        self.targets.get(&server)?.last.as_ref()
+    }
+    /// Records the result of probing `server` and returns what changed since the last time.
+    /// The first result for a server only sets the baseline, so it never produces events
+    pub fn observe(
+        &mut self,
+        server: (IpAddr, u16),
+        result: Result<&CCheckResponse, &ProbeError>,
+    ) -> Vec<Event> {
+        let timestamp = OffsetDateTime::now_utc().unix_timestamp() as usize;
+        let mut kinds = vec![];
+        let is_new = !self.targets.contains_key(&server);
+        match (self.targets.get_mut(&server), result) {
+            (None, result) => {
+                self.targets.insert(
+                    server,
+                    TargetState {
+                        online: result.is_ok(),
+                        last: result.ok().cloned(),
+                    },
+                );
+            }
+            (Some(state), Ok(resp)) => {
+                if !state.online {
+                    kinds.push(EventKind::Online);
+                }
+                if let Some(last) = &state.last {
+                    kinds.extend(diff(last, resp, &self.thresholds));
+                }
+                state.online = true;
+                state.last = Some(resp.clone());
+            }
+            (Some(state), Err(err)) => {
+                if state.online {
+                    kinds.push(EventKind::Offline {
+                        error: err.kind(),
+                        message: err.to_string(),
+                    });
+                }
+                state.online = false;
+            }
+        }
+        if is_new || !kinds.is_empty() {
+            self.changed.insert(server);
+        }
+        kinds
+            .into_iter()
+            .map(|kind| Event {
+                timestamp,
+                server,
+                kind,
+            })
+            .collect()
+    }
+}
+
+/// Everything that changed between two responses of the same server
+fn diff(old: &CCheckResponse, new: &CCheckResponse, thresholds: &[usize]) -> Vec<EventKind> {
+    let mut kinds = vec![];
+    // with only part of the players listed, somebody missing from the sample may well still be there
+    if complete_sample(old) && complete_sample(new) {
+        let old_players = players(old);
+        let new_players = players(new);
+        for (key, player) in &new_players {
+            if !old_players.contains_key(key) {
+                kinds.push(EventKind::PlayerJoined {
+                    name: player.name.clone(),
+                    uuid: player.id.clone(),
+                });
+            }
+        }
+        for (key, player) in &old_players {
+            if !new_players.contains_key(key) {
+                kinds.push(EventKind::PlayerLeft {
+                    name: player.name.clone(),
+                    uuid: player.id.clone(),
+                });
+            }
+        }
+    }
+    let (from, to) = (old.online_players, new.online_players);
+    for threshold in thresholds {
+        if (from < *threshold) != (to < *threshold) {
+            kinds.push(EventKind::PlayerCount {
+                from,
+                to,
+                threshold: *threshold,
+            });
+        }
+    }
+    if old.version != new.version || old.protocol != new.protocol {
+        kinds.push(EventKind::VersionChanged {
+            from: format!("{} ({})", old.version, old.protocol),
+            to: format!("{} ({})", new.version, new.protocol),
+        });
+    }
+    let (old_motd, new_motd) = (old.description.to_plain(), new.description.to_plain());
+    if old_motd != new_motd {
+        kinds.push(EventKind::MotdChanged {
+            from: old_motd,
+            to: new_motd,
+        });
+    }
+    if old.favicon != new.favicon {
+        kinds.push(EventKind::FaviconChanged {
+            from: favicon_hash(old.favicon.as_deref().unwrap_or_default()),
+            to: favicon_hash(new.favicon.as_deref().unwrap_or_default()),
+        });
+    }
+    kinds
+}
+/// Whether the sample lists every online player
+fn complete_sample(resp: &CCheckResponse) -> bool {
+    resp.sample.as_ref().map_or(0, Vec::len) >= resp.online_players
+}
+/// Sampled players by uuid, or by name for the placeholder uuid that custom sample lines share
+fn players(resp: &CCheckResponse) -> HashMap<&str, &CCheckPlayer> {
+    resp.sample
+        .iter()
+        .flatten()
+        .map(|p| match is_placeholder_uuid(&p.id) {
+            true => (p.name.as_str(), p),
+            false => (p.id.as_str(), p),
+        })
+        .collect()
+}
+
+/// Appends events to a file, one JSON object per line
+pub struct EventLog {
+    writer: BufWriter<File>,
+}
+impl EventLog {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            writer: BufWriter::new(file),
+        })
+    }
+    pub fn write(&mut self, events: &[Event]) -> anyhow::Result<()> {
+        for event in events {
+            serde_json::to_writer(&mut self.writer, event)?;
+            self.writer.write_all(b"\n")?;
+        }
+        self.writer.flush()?;
+        Ok(())
+    }
+}
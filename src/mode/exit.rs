@@ -0,0 +1,196 @@
+// CCheck: utility for scanning and probing minecraft servers
+// Copyright (C) 2022 cleonyc
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//! When the monitor stops, and what it tells the script that started it
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use strum_macros::Display as StrumDisplay;
+use tokio::time::Instant;
+
+use crate::{adapters::CCheckResponse, format::ccheck::Server};
+
+/// When the monitor exits, it keeps going until one of these is met
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExitPolicy {
+    /// Exit once this many servers matched the conditions, 0 to never exit on matches
+    pub matches: usize,
+    /// Exit once a server that matched stops matching or goes offline
+    #[serde(default)]
+    pub on_clear: bool,
+    /// Seconds after which to exit
+    #[serde(default)]
+    pub time_limit: Option<f64>,
+    /// Exit once every server was checked this many times
+    #[serde(default)]
+    pub cycles: Option<usize>,
+    /// Print the servers the monitor exited over as JSON on stdout, and everything else on stderr
+    #[serde(default)]
+    pub json: bool,
+}
+impl Default for ExitPolicy {
+    fn default() -> Self {
+        Self {
+            matches: 1,
+            on_clear: false,
+            time_limit: None,
+            cycles: None,
+            json: false,
+        }
+    }
+}
+impl ExitPolicy {
+    /// Rejects limits the monitor could never reach
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if let Some(secs) = self.time_limit {
+            if !secs.is_finite() || secs < 0. {
+                anyhow::bail!("invalid time limit `{secs}`");
+            }
+        }
+        if self.cycles == Some(0) {
+            anyhow::bail!("the number of cycles must be at least 1");
+        }
+        Ok(())
+    }
+}
+
+/// Why the monitor exited
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, StrumDisplay)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum ExitReason {
+    /// Enough servers matched the conditions
+    Matched,
+    /// A server that matched stopped matching
+    Cleared,
+    /// The time limit ran out
+    TimeLimit,
+    /// Every server was checked as many times as asked
+    Cycles,
+    /// Stopped by Ctrl-C or SIGTERM
+    Interrupted,
+}
+impl ExitReason {
+    /// Process exit code, 1 and 2 are taken by errors and invalid arguments
+    pub fn code(&self) -> i32 {
+        match self {
+            ExitReason::Matched => 0,
+            ExitReason::Cleared => 3,
+            ExitReason::TimeLimit => 4,
+            ExitReason::Cycles => 5,
+            ExitReason::Interrupted => 130,
+        }
+    }
+}
+
+/// Keeps track of the results that count towards the [`ExitPolicy`]
+pub struct ExitTracker {
+    policy: ExitPolicy,
+    deadline: Option<Instant>,
+    /// Servers that matched during this run, in the order they first did, with their last matching response
+    matched: Vec<Server>,
+    /// Position of each target in `matched`
+    positions: HashMap<usize, usize>,
+    /// Targets matching right now
+    matching: HashSet<usize>,
+    /// Targets that stopped matching, positions in `matched`
+    cleared: Vec<usize>,
+    /// Completed checks per target
+    checks: Vec<usize>,
+    /// Targets checked at least `policy.cycles` times
+    done: usize,
+}
+impl ExitTracker {
+    pub fn new(policy: ExitPolicy, targets: usize) -> Self {
+        let deadline = policy
+            .time_limit
+            .map(|secs| Instant::now() + Duration::from_secs_f64(secs));
+        Self {
+            policy,
+            deadline,
+            matched: vec![],
+            positions: HashMap::new(),
+            matching: HashSet::new(),
+            cleared: vec![],
+            checks: vec![0; targets],
+            done: 0,
+        }
+    }
+    /// When the time limit runs out
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+    /// Whether there is nothing to wait for, with no targets to check at all
+    pub fn finished(&self) -> Option<ExitReason> {
+        match self.policy.cycles {
+            Some(_) if self.done >= self.checks.len() => Some(ExitReason::Cycles),
+            _ => None,
+        }
+    }
+    /// Counts a check of `target` that didn't tell whether it matches
+    pub fn checked(&mut self, target: usize) -> Option<ExitReason> {
+        self.checks[target] += 1;
+        if Some(self.checks[target]) == self.policy.cycles {
+            self.done += 1;
+        }
+        self.finished()
+    }
+    /// Records the result of checking `target`, `response` is `None` while it's offline.
+    /// Returns why the monitor should exit, if it should
+    pub fn observe(
+        &mut self,
+        target: usize,
+        server: (IpAddr, u16),
+        response: Option<&CCheckResponse>,
+        matched: bool,
+    ) -> Option<ExitReason> {
+        match (response, matched) {
+            (Some(resp), true) => {
+                let current = Server::from_resp(resp.clone(), server);
+                match self.positions.get(&target) {
+                    Some(&pos) => self.matched[pos] = current,
+                    None => {
+                        self.positions.insert(target, self.matched.len());
+                        self.matched.push(current);
+                    }
+                }
+                self.matching.insert(target);
+            }
+            _ => {
+                if self.matching.remove(&target) {
+                    self.cleared.push(self.positions[&target]);
+                }
+            }
+        }
+        if self.policy.matches > 0 && self.matched.len() >= self.policy.matches {
+            return Some(ExitReason::Matched);
+        }
+        if self.policy.on_clear && !self.cleared.is_empty() {
+            return Some(ExitReason::Cleared);
+        }
+        self.checked(target)
+    }
+    /// The servers to print on exit: the ones that stopped matching if that's why, otherwise all that matched
+    pub fn servers(&self, reason: ExitReason) -> Vec<&Server> {
+        match reason {
+            ExitReason::Cleared => self.cleared.iter().map(|&pos| &self.matched[pos]).collect(),
+            _ => self.matched.iter().collect(),
+        }
+    }
+}
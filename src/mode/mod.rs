@@ -17,18 +17,89 @@ use std::path::PathBuf;
 
 use serde::{Deserialize, Serialize};
 
+use crate::{
+    format::{csv::Column, OutputFormat},
+    mode::{
+        alerts::AlertPolicy, events::EventType, exit::ExitPolicy, schedule::SchedulePolicy,
+        state::StatsPolicy, track::TrackPolicy,
+    },
+    notify::{queue::DeliveryPolicy, template::AlertTemplates},
+};
+
+pub mod adaptive;
+pub mod alerts;
+pub mod events;
+pub mod exit;
 pub mod monitor;
 pub mod scanner;
+pub mod schedule;
+pub mod state;
+pub mod track;
+fn default_checkpoint_interval() -> u64 {
+    30
+}
 #[derive(Serialize, Deserialize, Clone)]
 pub enum Mode {
     Scanner {
         workers: usize,
+        input: PathBuf,
         output: PathBuf,
+        output_format: OutputFormat,
+        columns: Vec<Column>,
+        failures: Option<PathBuf>,
         progress_bar: bool,
+        /// Continue from the checkpoint left next to `output` by an interrupted scan
+        #[serde(default)]
+        resume: bool,
+        /// Seconds between checkpoints, 0 disables checkpointing
+        #[serde(default = "default_checkpoint_interval")]
+        checkpoint_interval: u64,
+        /// Adjust the number of probes in flight between 1 and `workers` automatically
+        #[serde(default)]
+        adaptive: bool,
     },
     Monitor {
         workers: usize,
+        /// How often each server is checked
+        #[serde(default)]
+        schedule: SchedulePolicy,
         webhook_url: Option<String>,
+        /// Notifier specs events are sent to, see [`crate::notify::from_spec`]
+        #[serde(default)]
+        notify: Vec<String>,
         exit_on_success: bool,
+        /// When the monitor stops besides matches, `matches` only applies with `exit_on_success`
+        #[serde(default)]
+        exit: Box<ExitPolicy>,
+        /// Players to follow across the servers
+        #[serde(default)]
+        track: Box<TrackPolicy>,
+        /// Whether check results are kept in `state` for `ccheck report`
+        #[serde(default)]
+        stats: StatsPolicy,
+        /// Kinds of events to report, all of them if empty
+        #[serde(default)]
+        events: Vec<EventType>,
+        /// File to append events to as JSON lines
+        #[serde(default)]
+        events_file: Option<PathBuf>,
+        /// Player counts that are reported when a server goes past them
+        #[serde(default)]
+        player_thresholds: Vec<usize>,
+        /// When servers matching the conditions are alerted
+        #[serde(default)]
+        alerts: AlertPolicy,
+        /// Messages notifiers send
+        #[serde(default)]
+        templates: Box<AlertTemplates>,
+        /// Database to keep the monitor's state and event history in between runs
+        #[serde(default)]
+        state: Option<PathBuf>,
+        /// Ignore the saved state
+        #[serde(default)]
+        fresh_state: bool,
+        /// How failed notifications are retried
+        #[serde(default)]
+        delivery: Box<DeliveryPolicy>,
     },
 }
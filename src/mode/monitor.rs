@@ -13,132 +13,385 @@
 
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
-use anyhow::bail;
 use owo_colors::OwoColorize;
-use spinoff::{Color, Spinner, Spinners};
-use std::panic;
+use spinoff::{Color, Spinner, Spinners, Streams};
+use std::fmt::Display;
+use std::io::Write;
 use std::time::Duration as StdDuration;
-use std::{net::IpAddr, sync::Arc};
-use tokio::{net::TcpStream, sync::RwLock};
-use webhook::client::WebhookClient;
+use std::{collections::HashMap, net::IpAddr, path::PathBuf, sync::Arc};
+use time::OffsetDateTime;
+use tokio::{sync::RwLock, task::JoinSet, time::Instant};
 
-use crate::{adapters::CCheckResponse, condition::Conditions, format::ccheck::Server};
+use crate::{
+    adapters::CCheckResponse,
+    condition::Conditions,
+    format::ccheck::Server,
+    limit::RateLimiter,
+    mode::{
+        alerts::{AlertPolicy, Alerts},
+        events::{Event, EventKind, EventLog, EventType, Tracker},
+        exit::{ExitPolicy, ExitReason, ExitTracker},
+        scanner::shutdown_signal,
+        schedule::{SchedulePolicy, Scheduler},
+        state::{MonitorState, StatsPolicy},
+        track::{PlayerTracker, TrackPolicy},
+    },
+    notify::{
+        queue::DeliveryQueue,
+        template::{Message, Templates},
+        Notification,
+    },
+    probe::{summarize, ProbeError, ProbeErrorKind, Prober},
+    retry::RetryPolicy,
+};
+
+/// How long to wait for queued notifications when the monitor exits
+const SHUTDOWN_GRACE: StdDuration = StdDuration::from_secs(30);
+/// How often checks past their retention are deleted
+const PRUNE_INTERVAL: StdDuration = StdDuration::from_secs(3600);
 
 #[derive(Debug, Clone)]
 pub struct Monitor {
-    /// Servers per second to scan
-    pub rate: usize,
-    pub timeout: StdDuration,
+    /// Paces how quickly servers are pinged
+    pub limiter: Arc<RateLimiter>,
+    /// Checks in flight at once, including those waiting for `limiter`
+    pub workers: usize,
+    pub retry: RetryPolicy,
+    pub prober: Prober,
     pub conditions: Conditions,
     pub addrs: Arc<RwLock<Vec<(IpAddr, u16)>>>,
-    pub webhook_url: Option<String>,
+    /// Where events are sent besides the console
+    pub queue: Arc<DeliveryQueue>,
+    /// Messages notifiers send
+    pub templates: Arc<Templates>,
+    /// Kinds of events to report, all of them if empty
+    pub events: Vec<EventType>,
+    /// File to append events to as JSON lines
+    pub events_file: Option<PathBuf>,
+    /// How often each server is checked
+    pub schedule: SchedulePolicy,
+    /// Database the monitor's state and event history are kept in between runs
+    pub state_file: Option<PathBuf>,
+    /// Ignore the state saved in `state_file`
+    pub fresh_state: bool,
+    /// Player counts that are reported when a server goes past them
+    pub player_thresholds: Vec<usize>,
+    /// When servers matching the conditions are alerted
+    pub alerts: AlertPolicy,
+    /// When the monitor stops
+    pub exit: ExitPolicy,
+    /// Players to follow across the servers
+    pub track: TrackPolicy,
+    /// Whether check results are kept in the state database for `ccheck report`
+    pub stats: StatsPolicy,
 }
 impl Monitor {
-    pub async fn ping(&self, server: usize) -> anyhow::Result<(CCheckResponse, Server)> {
+    /// Returns the server's response and whether it matches the conditions
+    pub async fn ping(&self, server: usize) -> Result<(CCheckResponse, bool), ProbeError> {
         let addr = self.addrs.read().await[server];
         // println!(
         //     "{} Pinging server {}",
         //     "::".blue().bold(),
         //     format!("{}:{}", addr.0, addr.1).cyan()
         // );
-        panic::set_hook(Box::new(move |info| {
-            eprintln!("panic!! info: {}, sv: {}:{}", info, addr.0, addr.1);
-        }));
-        let mut stream = match tokio::time::timeout(self.timeout, TcpStream::connect(addr)).await {
-            Ok(s) => match s {
-                Ok(s) => s,
-                Err(err) => {
-                    bail!(err);
+        let cresp = self.prober.probe(addr).await?;
+        let matched = self.conditions.is_valid(cresp.clone());
+        Ok((cresp, matched))
+    }
+    /// Prints a line for the user, on stderr when stdout is kept for `--json`
+    pub fn say(&self, line: impl Display) {
+        match self.exit.json {
+            true => eprintln!("{line}"),
+            false => println!("{line}"),
+        }
+    }
+    /// Monitors the servers until the exit policy is met, and returns why
+    pub async fn run(&self) -> anyhow::Result<ExitReason> {
+        self.say(format!(
+            "{} Monitoring {} servers",
+            "::".blue().bold(),
+            self.addrs.read().await.len().purple()
+        ));
+        let mut tracker = Tracker::new(self.player_thresholds.clone());
+        let mut alerts = Alerts::new(self.alerts, self.conditions.to_string());
+        let mut event_log = self
+            .events_file
+            .as_deref()
+            .map(EventLog::open)
+            .transpose()?;
+        let mut state = match &self.state_file {
+            Some(path) => {
+                let state = MonitorState::open(path, self.fresh_state)?;
+                let restored =
+                    state.restore(&self.addrs.read().await, &mut tracker, &mut alerts)?;
+                if restored > 0 {
+                    self.say(format!(
+                        "{} Restored the state of {} servers from {}",
+                        "::".blue().bold(),
+                        restored.purple(),
+                        path.display()
+                    ));
                 }
-            },
-            Err(err) => {
-                bail!(err);
+                Some(state)
             }
+            None => None,
         };
-        let resp = match tokio::time::timeout(
-            self.timeout,
-            craftping::tokio::ping(&mut stream, &addr.0.to_string(), addr.1),
-        )
-        .await
-        {
-            Ok(res) => match res {
-                Ok(res) => res,
-                Err(err) => {
-                    bail!(err);
-                }
-            },
-            Err(err) => {
-                bail!(err);
+        if let Some(state) = &mut state {
+            if self.stats.record {
+                self.prune_stats(state)?;
             }
+        }
+        let mut pruned_at = Instant::now();
+        let mut players = match self.track.players.is_empty() {
+            true => None,
+            false => Some(PlayerTracker::new(&self.track)?),
         };
-        let cresp: CCheckResponse = resp.into();
-        if self.conditions.is_valid(cresp.clone()) {
-            if let Some(webhook) = self.webhook_url.clone() {
-                let client = WebhookClient::new(&webhook.clone());
-                client
-                    .send(|m| {
-                        m.embed(|e| {
-                            e.title("Server matching conditions found!")
-                                .description(&format!("`{}:{}`", addr.0, addr.1))
-                                .field("Version", &cresp.clone().version, true)
-                                .field(
-                                    "Players",
-                                    &cresp
-                                        .clone()
-                                        .sample
-                                        .unwrap_or_default()
-                                        .iter()
-                                        .fold(String::new(), |str, val| {
-                                            format!("{}, {str}", val.name)
-                                        }),
-                                    false,
-                                )
-                                .field("Motd", &cresp.clone().description.text, false)
-                        })
-                    })
-                    .await
-                    .expect("Failed to send webhook");
+        let stream = match self.exit.json {
+            true => Streams::Stderr,
+            false => Streams::Stdout,
+        };
+        let mut spinner =
+            Spinner::new_with_stream(Spinners::Dots, "Monitoring servers", Color::Blue, stream);
+        let targets = self.addrs.read().await.len();
+        let mut scheduler = Scheduler::new(self.schedule.clone(), targets);
+        let mut outcome = ExitTracker::new(self.exit.clone(), targets);
+        let mut in_flight = JoinSet::new();
+        // why the servers that are offline right now failed
+        let mut offline: HashMap<usize, ProbeErrorKind> = HashMap::new();
+        let interrupted = shutdown_signal();
+        tokio::pin!(interrupted);
+        let mut reason = outcome.finished();
+        while reason.is_none() {
+            // every target is either queued or in flight, so this only idles with no targets at all
+            let next_due = scheduler
+                .next_due()
+                .unwrap_or_else(|| Instant::now() + StdDuration::from_secs(3600));
+            let deadline = outcome.deadline();
+            tokio::select! {
+                _ = tokio::time::sleep_until(deadline.unwrap_or(next_due)), if deadline.is_some() => {
+                    reason = Some(ExitReason::TimeLimit);
+                }
+                _ = &mut interrupted => {
+                    reason = Some(ExitReason::Interrupted);
+                    eprintln!(
+                        "\n{} Stopping, press Ctrl-C again to quit immediately",
+                        "::".yellow().bold()
+                    );
+                    tokio::spawn(async {
+                        shutdown_signal().await;
+                        std::process::exit(130);
+                    });
+                }
+                // one target per turn, so results keep being handled while lots of targets are due
+                _ = tokio::time::sleep_until(next_due), if in_flight.len() < self.workers => {
+                    if let Some((sv, attempt)) = scheduler.pop_due(Instant::now()) {
+                        let self_clone = self.clone();
+                        let ip = self.addrs.read().await[sv].0;
+                        // a panicking probe must not lose the target, so it runs in a task of its own
+                        let ping = tokio::spawn(async move {
+                            self_clone.limiter.wait(ip).await;
+                            self_clone.ping(sv).await
+                        });
+                        in_flight.spawn(async move { (sv, attempt, ping.await) });
+                    }
+                }
+                Some(joined) = in_flight.join_next() => {
+                    let (sv, attempt, result) = joined.expect("checks catch their panics");
+                    let addr = self.addrs.read().await[sv];
+                    let previous = tracker.last(addr).cloned();
+                    let events = match result {
+                        Ok(Ok((resp, matched))) => {
+                            reason = outcome.observe(sv, addr, Some(&resp), matched);
+                            self.record_check(&mut state, addr, Ok(&resp))?;
+                            offline.remove(&sv);
+                            scheduler.done(sv, addr.0, true);
+                            let mut new = tracker.observe(addr, Ok(&resp));
+                            new.extend(alerts.observe(addr, matched.then_some(&resp)));
+                            if let Some(players) = &mut players {
+                                new.extend(players.observe(addr, Some(&resp)));
+                            }
+                            notifications(addr, new, Some(&resp), previous)
+                        }
+                        Ok(Err(err)) if self.retry.should_retry(err.kind(), attempt) => {
+                            scheduler.retry(sv, attempt + 1, self.retry.delay(attempt));
+                            continue;
+                        }
+                        Ok(Err(err)) => {
+                            reason = outcome.observe(sv, addr, None, false);
+                            self.record_check(&mut state, addr, Err(&err))?;
+                            offline.insert(sv, err.kind());
+                            scheduler.done(sv, addr.0, false);
+                            let mut new = tracker.observe(addr, Err(&err));
+                            new.extend(alerts.observe(addr, None));
+                            if let Some(players) = &mut players {
+                                new.extend(players.observe(addr, None));
+                            }
+                            notifications(addr, new, None, previous)
+                        }
+                        Err(err) => {
+                            eprintln!(
+                                "{} Check of {}:{} panicked: {err}",
+                                "::".red().bold(),
+                                addr.0,
+                                addr.1
+                            );
+                            reason = outcome.checked(sv);
+                            // back off like an offline server rather than hitting the panic again right away
+                            scheduler.done(sv, addr.0, false);
+                            continue;
+                        }
+                    };
+                    self.report(events, &mut event_log, &mut state).await?;
+                    if let Some(state) = &mut state {
+                        let now = OffsetDateTime::now_utc().unix_timestamp() as usize;
+                        state.save(now, &mut tracker, &mut alerts)?;
+                        state.flush_checks(false)?;
+                        if self.stats.record && pruned_at.elapsed() >= PRUNE_INTERVAL {
+                            self.prune_stats(state)?;
+                            pruned_at = Instant::now();
+                        }
+                    }
+                    if let Some(players) = &mut players {
+                        players.save(false)?;
+                    }
+                    if offline.is_empty() {
+                        spinner.update_text("Monitoring servers");
+                    } else {
+                        let mut failures: HashMap<ProbeErrorKind, usize> = HashMap::new();
+                        for kind in offline.values() {
+                            *failures.entry(*kind).or_default() += 1;
+                        }
+                        spinner.update_text(format!(
+                            "Monitoring servers ({} offline: {})",
+                            offline.len(),
+                            summarize(&failures)
+                        ));
+                    }
+                }
             }
-            println!(
-                "{} Found matching server {}",
-                "::".green().bold(),
-                format!("{}:{}", addr.0, addr.1).cyan()
+        }
+        in_flight.abort_all();
+        if let Some(state) = &mut state {
+            state.flush_checks(true)?;
+        }
+        if let Some(players) = &mut players {
+            players.save(true)?;
+        }
+        spinner.update_text("Sending notifications");
+        let undelivered = self.queue.close(SHUTDOWN_GRACE).await;
+        if undelivered > 0 {
+            let kept = match self.queue.is_spooled() {
+                true => ", they are kept in the spool",
+                false => "",
+            };
+            eprintln!(
+                "{} {undelivered} notifications could not be sent yet{kept}",
+                "::".yellow().bold()
             );
-            return Ok((cresp.clone(), Server::from_resp(cresp.clone(), addr)));
         }
-        bail!("Server does not match condtions")
+        let reason = reason.expect("the loop only ends with a reason");
+        spinner.success(&format!("Done! ({reason})"));
+        if self.exit.json {
+            let servers = outcome.servers(reason);
+            let output = serde_json::json!({ "reason": reason, "servers": servers });
+            let mut stdout = std::io::stdout().lock();
+            writeln!(stdout, "{output}")?;
+            stdout.flush()?;
+        }
+        Ok(reason)
     }
-    pub async fn run(&self, exit_on_success: bool) -> anyhow::Result<()> {
-        println!(
-            "{} Monitoring {} servers",
-            "::".blue().bold(),
-            self.addrs.read().await.len().purple()
-        );
-        let mut spinner = Spinner::new(Spinners::Dots, "Monitoring servers", Color::Blue);
-        loop {
-            spinner.update_text("Monitoring servers");
-            let mut join_handles = vec![];
-
-            for sv in 0..self.addrs.read().await.len() {
-                let self_clone = self.clone();
-                let jh = tokio::spawn(async move { self_clone.ping(sv).await });
-                join_handles.push(jh);
-                tokio::time::sleep(StdDuration::from_micros((1_000_000 / self.rate) as u64)).await;
+    /// Adds the result of a check to the uptime statistics, if they are kept
+    fn record_check(
+        &self,
+        state: &mut Option<MonitorState>,
+        server: (IpAddr, u16),
+        result: Result<&CCheckResponse, &ProbeError>,
+    ) -> anyhow::Result<()> {
+        match state {
+            Some(state) if self.stats.record => {
+                let now = OffsetDateTime::now_utc().unix_timestamp() as usize;
+                state.record_check(now, server, result)
             }
-            spinner.update_text("Processing results");
-            let mut exit = false;
-            for jh in join_handles {
-                if let Ok(Ok(_)) = jh.await {
-                    if exit_on_success {
-                        exit = true
-                    }
-                }
-            }
-            if exit {
-                break;
+            _ => Ok(()),
+        }
+    }
+    /// Deletes checks older than the retention
+    fn prune_stats(&self, state: &mut MonitorState) -> anyhow::Result<()> {
+        if self.stats.retention == 0 {
+            return Ok(());
+        }
+        let now = OffsetDateTime::now_utc().unix_timestamp() as usize;
+        state.prune_stats(now.saturating_sub(self.stats.retention * 86400))
+    }
+    /// Sends events to the console, the events file, the event history and the notifiers
+    async fn report(
+        &self,
+        mut notifications: Vec<Notification>,
+        event_log: &mut Option<EventLog>,
+        state: &mut Option<MonitorState>,
+    ) -> anyhow::Result<()> {
+        if !self.events.is_empty() {
+            notifications.retain(|n| self.events.contains(&EventType::from(&n.event.kind)));
+        }
+        let events = notifications
+            .iter()
+            .map(|n| n.event.clone())
+            .collect::<Vec<_>>();
+        for event in &events {
+            let prefix = match event.kind {
+                EventKind::Online
+                | EventKind::PlayerJoined { .. }
+                | EventKind::Matched { .. }
+                | EventKind::SessionStarted { .. } => "::".green().bold().to_string(),
+                EventKind::Offline { .. }
+                | EventKind::PlayerLeft { .. }
+                | EventKind::SessionEnded { .. } => "::".red().bold().to_string(),
+                _ => "::".blue().bold().to_string(),
+            };
+            self.say(format!("{prefix} {event}"));
+        }
+        if let Some(event_log) = event_log {
+            event_log.write(&events)?;
+        }
+        if let Some(state) = state {
+            state.write_events(&events)?;
+        }
+        if self.queue.is_empty() {
+            return Ok(());
+        }
+        for mut notification in notifications {
+            match self.templates.render(&notification) {
+                Ok(message) => notification.message = message,
+                Err(err) => eprintln!(
+                    "{} Failed to render alert templates, using the defaults: {err}",
+                    "::".yellow().bold()
+                ),
             }
+            self.queue.push(&notification);
         }
-        spinner.success("Done!");
         Ok(())
     }
 }
+
+/// Wraps the events about `server` with its current and previous response
+fn notifications(
+    server: (IpAddr, u16),
+    events: Vec<Event>,
+    response: Option<&CCheckResponse>,
+    previous: Option<CCheckResponse>,
+) -> Vec<Notification> {
+    if events.is_empty() {
+        return vec![];
+    }
+    let response = response.map(|resp| Server::from_resp(resp.clone(), server));
+    let previous = previous.map(|resp| Server::from_resp(resp, server));
+    events
+        .into_iter()
+        .map(|event| Notification {
+            event,
+            response: response.clone(),
+            previous: previous.clone(),
+            message: Message::default(),
+        })
+        .collect()
+}
@@ -14,154 +14,525 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 use anyhow::bail;
-use craftping::tokio::ping;
-use kdam::{tqdm, Bar, BarExt};
+use kdam::{tqdm, BarExt};
 use owo_colors::OwoColorize;
 use spinoff::{Color, Spinner, Spinners};
-use std::{fmt::Display, net::IpAddr, sync::Arc, time::Duration};
-use tokio::{net::TcpStream, sync::RwLock};
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+    fmt::Display,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::mpsc;
 
 use crate::{
     adapters::CCheckResponse,
     condition::Conditions,
-    format::ccheck::{CCheckFileHandler, Server},
+    exclude::ExcludeList,
+    format::{
+        ccheck::Server,
+        checkpoint::{Checkpoint, Completed},
+        failures::FailureFileHandler,
+        metadata::ScanMetadata,
+        Output,
+    },
+    limit::RateLimiter,
+    mode::adaptive::{Concurrency, Controller, ADJUST_INTERVAL},
+    probe::{summarize, FailureCounts, ProbeError, Prober},
+    retry::RetryPolicy,
 };
-#[derive(Debug, Clone)]
 
+/// How often the progress bar/spinner is redrawn
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+/// Longest an idle worker sleeps before checking for due retries again
+const RETRY_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Min-heap of targets to retry as (due, index into `addrs`, attempt)
+type RetryQueue = BinaryHeap<Reverse<(Instant, usize, u32)>>;
+
+#[derive(Debug, Clone)]
 pub struct Scanner {
-    pub addrs: Arc<RwLock<Vec<(IpAddr, u16)>>>,
+    /// Every target of the scan, indexed the same way as the checkpoint bitmap
+    pub addrs: Arc<Vec<(IpAddr, u16)>>,
+    /// Indices into `addrs` that still have to be probed, in order
+    pub pending: Arc<Vec<usize>>,
+    /// Position in `pending` of the next target to hand out
+    next: Arc<AtomicUsize>,
+    /// Number of targets probed by this run, for progress output
+    probed: Arc<AtomicUsize>,
+    pub completed: Arc<Completed>,
+    pub failures: Arc<FailureCounts>,
+    pub limiter: Arc<RateLimiter>,
+    pub retry: RetryPolicy,
+    /// Targets waiting to be retried, soonest first
+    retry_queue: Arc<Mutex<RetryQueue>>,
+    /// Length of `retry_queue`, so workers only lock it when there is something in it
+    queued_retries: Arc<AtomicUsize>,
+    /// Targets currently being probed, which may still end up in `retry_queue`
+    in_flight: Arc<AtomicUsize>,
+    /// Number of retries made
+    retries: Arc<AtomicUsize>,
+    /// Limits probes in flight below the number of workers when scanning adaptively
+    pub concurrency: Option<Arc<Concurrency>>,
+    /// Set on SIGINT/SIGTERM so workers stop picking up new targets
+    pub stop: Arc<AtomicBool>,
     pub conditions: Conditions,
-    pub timeout: Duration,
+    pub prober: Prober,
     pub progress_bar: bool,
 }
 #[derive(Debug)]
 enum ScannerError {
     EmptyAddrs,
+    Probe(usize, u32, (IpAddr, u16), ProbeError),
 }
 impl Display for ScannerError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             ScannerError::EmptyAddrs => write!(f, "Empty Address buffer!"),
+            ScannerError::Probe(_, _, addr, err) => write!(f, "{}:{}: {err}", addr.0, addr.1),
         }
     }
 }
+/// Result of probing a single target, sent from the workers to the writer task
+enum Record {
+    Found(usize, Box<Server>),
+    /// Responded, but didn't match the conditions
+    Filtered(usize),
+    /// Failed on its last attempt, which is also recorded
+    Failed(usize, (IpAddr, u16), ProbeError, u32),
+}
 impl Scanner {
-    async fn ping(&self, pb: Option<Arc<RwLock<Bar>>>) -> anyhow::Result<(CCheckResponse, Server)> {
-        let addr = {
-            if let Some(i) = self.addrs.write().await.pop() {
-                i
-            } else {
-                bail!(ScannerError::EmptyAddrs)
-            }
+    /// Creates a scanner over `addrs`, skipping targets already probed according to `checkpoint`
+    pub fn new(
+        addrs: Vec<(IpAddr, u16)>,
+        conditions: Conditions,
+        prober: Prober,
+        progress_bar: bool,
+        limiter: Arc<RateLimiter>,
+        retry: RetryPolicy,
+        checkpoint: Option<&Checkpoint>,
+    ) -> anyhow::Result<Self> {
+        let (completed, failures, retries) = match checkpoint {
+            Some(cp) => (
+                Completed::decode(addrs.len(), &cp.completed)?,
+                FailureCounts::from(&cp.metadata.failures),
+                cp.metadata.retries,
+            ),
+            None => (Completed::new(addrs.len()), FailureCounts::default(), 0),
         };
-        let mut stream = match tokio::time::timeout(self.timeout, TcpStream::connect(addr)).await {
-            Ok(s) => match s {
-                Ok(s) => s,
-                Err(err) => {
-                    update(pb).await;
-                    bail!(err);
+        let pending = (0..addrs.len()).filter(|i| !completed.is_set(*i)).collect();
+        Ok(Self {
+            addrs: Arc::new(addrs),
+            pending: Arc::new(pending),
+            next: Arc::new(AtomicUsize::new(0)),
+            probed: Arc::new(AtomicUsize::new(0)),
+            completed: Arc::new(completed),
+            failures: Arc::new(failures),
+            limiter,
+            retry,
+            retry_queue: Arc::new(Mutex::new(BinaryHeap::new())),
+            queued_retries: Arc::new(AtomicUsize::new(0)),
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            retries: Arc::new(AtomicUsize::new(retries)),
+            concurrency: None,
+            stop: Arc::new(AtomicBool::new(false)),
+            conditions,
+            prober,
+            progress_bar,
+        })
+    }
+    /// Drops excluded targets from the ones still to be probed, returning how many were dropped
+    pub fn exclude(&mut self, list: &ExcludeList) -> usize {
+        if list.is_empty() {
+            return 0;
+        }
+        let pending = self
+            .pending
+            .iter()
+            .copied()
+            .filter(|i| !list.contains(self.addrs[*i].0))
+            .collect::<Vec<_>>();
+        let skipped = self.pending.len() - pending.len();
+        self.pending = Arc::new(pending);
+        skipped
+    }
+    /// Hands out the next target and which attempt at it this is. Retries that are due
+    /// come first, then fresh targets. Returns `None` once nothing is left to probe.
+    async fn next_target(&self) -> Option<(usize, u32)> {
+        loop {
+            if self.stop.load(Ordering::Relaxed) {
+                return None;
+            }
+            let mut next_due = None;
+            if self.queued_retries.load(Ordering::Acquire) > 0 {
+                let mut queue = self.retry_queue.lock().unwrap();
+                match queue.peek() {
+                    Some(Reverse((due, _, _))) if *due <= Instant::now() => {
+                        let Reverse((_, i, attempt)) = queue.pop().expect("peeked");
+                        self.in_flight.fetch_add(1, Ordering::AcqRel);
+                        self.queued_retries.fetch_sub(1, Ordering::AcqRel);
+                        return Some((i, attempt));
+                    }
+                    Some(Reverse((due, _, _))) => next_due = Some(*due),
+                    None => {}
+                }
+            }
+            if let Some(i) = self.pending.get(self.next.fetch_add(1, Ordering::Relaxed)) {
+                self.in_flight.fetch_add(1, Ordering::AcqRel);
+                return Some((*i, 1));
+            }
+            // a probe in flight may still be queued up for a retry
+            if self.queued_retries.load(Ordering::Acquire) == 0
+                && self.in_flight.load(Ordering::Acquire) == 0
+            {
+                return None;
+            }
+            let wait = next_due
+                .map(|due| due.saturating_duration_since(Instant::now()))
+                .unwrap_or(RETRY_POLL_INTERVAL)
+                .min(RETRY_POLL_INTERVAL);
+            tokio::time::sleep(wait).await;
+        }
+    }
+    /// Probes the next target. Failures the retry policy allows are queued up
+    /// to be tried again later instead of being returned.
+    async fn ping(&self) -> anyhow::Result<(usize, CCheckResponse, Server)> {
+        loop {
+            let (i, attempt) = match self.next_target().await {
+                Some(target) => target,
+                None => bail!(ScannerError::EmptyAddrs),
+            };
+            let addr = self.addrs[i];
+            let permit = match &self.concurrency {
+                Some(concurrency) => Some(concurrency.acquire().await),
+                None => None,
+            };
+            self.limiter.wait(addr.0).await;
+            let res = self.prober.probe(addr).await;
+            if let Some(concurrency) = &self.concurrency {
+                concurrency.record(&res);
+            }
+            drop(permit);
+            match res {
+                Err(err) if self.retry.should_retry(err.kind(), attempt) => {
+                    let due = Instant::now() + self.retry.delay(attempt);
+                    self.retry_queue
+                        .lock()
+                        .unwrap()
+                        .push(Reverse((due, i, attempt + 1)));
+                    self.queued_retries.fetch_add(1, Ordering::AcqRel);
+                    self.retries.fetch_add(1, Ordering::Relaxed);
+                    self.in_flight.fetch_sub(1, Ordering::AcqRel);
+                }
+                res => {
+                    self.in_flight.fetch_sub(1, Ordering::AcqRel);
+                    self.probed.fetch_add(1, Ordering::Relaxed);
+                    return match res {
+                        Ok(c_res) => {
+                            let mut server = Server::from_resp(c_res.clone(), addr);
+                            server.retries = attempt - 1;
+                            Ok((i, c_res, server))
+                        }
+                        Err(err) => bail!(ScannerError::Probe(i, attempt, addr, err)),
+                    };
                 }
-            },
-            Err(err) => {
-                update(pb).await;
-                bail!(err);
             }
+        }
+    }
+    /// Flushes the outputs and saves which targets are done to `path`.
+    /// Only the writer task records results, so everything marked completed has been written.
+    async fn checkpoint(
+        &self,
+        out: &mut Output,
+        failures_out: &mut Option<FailureFileHandler>,
+        metadata: &ScanMetadata,
+        path: &Path,
+    ) -> anyhow::Result<()> {
+        let output_len = out.flush().await?;
+        let (failures_count, failures_len) = match failures_out {
+            Some(fh) => (fh.count, Some(fh.flush().await?)),
+            None => (0, None),
         };
-
-        match tokio::time::timeout(self.timeout, ping(&mut stream, &addr.0.to_string(), addr.1))
-            .await
-        {
-            Ok(res) => match res {
-                Ok(res) => {
-                    update(pb).await;
-                    let c_res: CCheckResponse = res.into();
-                    Ok((c_res.clone(), Server::from_resp(c_res, addr)))
+        let mut metadata = metadata.clone();
+        metadata.found = out.count();
+        metadata.failures = self.failures.snapshot();
+        metadata.retries = self.retries.load(Ordering::Relaxed);
+        Checkpoint {
+            metadata,
+            completed: self.completed.encode(),
+            count: out.count(),
+            output_len,
+            scan_id: out.scan_id(),
+            failures_count,
+            failures_len,
+        }
+        .save(path)
+    }
+    /// Owns the outputs for the whole scan: records every result the workers send,
+    /// and checkpoints every `checkpoint_interval`. Returns the outputs once all workers are done.
+    async fn write(
+        &self,
+        mut rx: mpsc::Receiver<Record>,
+        mut out: Output,
+        mut failures_out: Option<FailureFileHandler>,
+        metadata: &ScanMetadata,
+        checkpoint_path: &Path,
+        checkpoint_interval: Option<Duration>,
+    ) -> anyhow::Result<(Output, Option<FailureFileHandler>)> {
+        let mut ticker = checkpoint_interval.map(|interval| {
+            let mut ticker =
+                tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+            ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            ticker
+        });
+        loop {
+            let tick = async {
+                match &mut ticker {
+                    Some(ticker) => ticker.tick().await,
+                    None => std::future::pending().await,
                 }
-                Err(err) => {
-                    update(pb).await;
-                    bail!(err)
+            };
+            tokio::select! {
+                record = rx.recv() => match record {
+                    Some(Record::Found(i, server)) => {
+                        out.write_resp(*server).await?;
+                        self.completed.set(i);
+                    }
+                    Some(Record::Filtered(i)) => self.completed.set(i),
+                    Some(Record::Failed(i, addr, err, attempts)) => {
+                        self.failures.add(err.kind());
+                        if let Some(fh) = &mut failures_out {
+                            fh.write_failure(addr, &err, attempts).await?;
+                        }
+                        self.completed.set(i);
+                    }
+                    None => break,
+                },
+                _ = tick => {
+                    if let Err(err) = self
+                        .checkpoint(&mut out, &mut failures_out, metadata, checkpoint_path)
+                        .await
+                    {
+                        eprintln!("{} Failed to save checkpoint: {err}", "::".red().bold());
+                    }
                 }
-            },
-            Err(err) => {
-                update(pb).await;
-                bail!(err)
             }
         }
+        Ok((out, failures_out))
     }
-    pub async fn run(&self, workers: usize, out: CCheckFileHandler) -> anyhow::Result<()> {
+    /// Scans every pending target. Progress is checkpointed to `checkpoint_path`
+    /// every `checkpoint_interval`, and on SIGINT/SIGTERM the scan stops early,
+    /// saves a final checkpoint and still finalizes the outputs.
+    pub async fn run(
+        &self,
+        workers: usize,
+        out: Output,
+        failures_out: Option<FailureFileHandler>,
+        mut metadata: ScanMetadata,
+        checkpoint_path: PathBuf,
+        checkpoint_interval: Option<Duration>,
+    ) -> anyhow::Result<()> {
         let mut join_handles = vec![];
-        let total_servers = self.addrs.read().await.len();
-        let pb = if self.progress_bar {
-            Some(Arc::new(RwLock::new(tqdm!(
-                total = self.addrs.read().await.len(),
-                // bar_format = "{animation} {percentage}".parse::<Template>().unwrap(),
-                colour = "gradient(#5A56E0,#EE6FF8)",
-                force_refresh = true
-            ))))
-        } else {
-            None
+        let total_servers = self.addrs.len();
+        let stop = self.stop.clone();
+        let signal_handler = tokio::spawn(async move {
+            shutdown_signal().await;
+            stop.store(true, Ordering::Relaxed);
+            eprintln!(
+                "\n{} Stopping after in-flight servers, press Ctrl-C again to quit immediately",
+                "::".yellow().bold()
+            );
+            shutdown_signal().await;
+            std::process::exit(130);
+        });
+        let scanning = Arc::new(AtomicBool::new(true));
+        let controller = self.concurrency.clone().map(|concurrency| {
+            tokio::spawn(async move {
+                let mut controller = Controller::default();
+                loop {
+                    tokio::time::sleep(ADJUST_INTERVAL).await;
+                    concurrency.adjust(&mut controller);
+                }
+            })
+        });
+        let progress = {
+            let probed = self.probed.clone();
+            let concurrency = self.concurrency.clone();
+            let scanning = scanning.clone();
+            let total = self.pending.len();
+            let mut pb = self.progress_bar.then(|| {
+                tqdm!(
+                    total = total,
+                    colour = "gradient(#5A56E0,#EE6FF8)",
+                    force_refresh = true
+                )
+            });
+            let mut spinner = (!self.progress_bar)
+                .then(|| Spinner::new(Spinners::Dots, "Scanning", Color::Magenta));
+            tokio::spawn(async move {
+                while scanning.load(Ordering::Relaxed) {
+                    tokio::time::sleep(PROGRESS_INTERVAL).await;
+                    let probed = probed.load(Ordering::Relaxed);
+                    let limit = concurrency.as_ref().map(|c| c.limit());
+                    if let Some(pb) = &mut pb {
+                        if let Some(limit) = limit {
+                            pb.set_description(format!("{limit} in flight"));
+                        }
+                        pb.update_to(probed);
+                    }
+                    if let Some(spinner) = &mut spinner {
+                        spinner.update_text(match limit {
+                            Some(limit) => {
+                                format!("Scanning ({probed}/{total}, {limit} in flight)")
+                            }
+                            None => format!("Scanning ({probed}/{total})"),
+                        });
+                    }
+                }
+                spinner
+            })
         };
-        let spinner = if !self.progress_bar {
-            Some(Spinner::new(Spinners::Dots, "Scanning", Color::Magenta))
-        } else {
-            None
+        // bounded so a slow disk applies backpressure instead of buffering every result
+        let (tx, rx) = mpsc::channel(workers.clamp(1, 4096) * 4);
+        let writer = {
+            let self_clone = self.clone();
+            let metadata = metadata.clone();
+            let checkpoint_path = checkpoint_path.clone();
+            tokio::spawn(async move {
+                self_clone
+                    .write(
+                        rx,
+                        out,
+                        failures_out,
+                        &metadata,
+                        &checkpoint_path,
+                        checkpoint_interval,
+                    )
+                    .await
+            })
         };
-        let safe_file_handler = Arc::new(RwLock::new(out));
         for _ in 0..=workers {
             let self_clone = self.clone();
-            let new_pb = pb.clone();
-            let cloned_safe_file_handler = safe_file_handler.clone();
+            let tx = tx.clone();
             let jh = tokio::spawn(async move {
                 loop {
-                    match self_clone.ping(new_pb.clone()).await {
-                        Ok(resp) => {
-                            cloned_safe_file_handler
-                                .clone()
-                                .write()
-                                .await
-                                .write_resp(resp.1)
-                                .await
-                                .unwrap();
-                        }
-                        Err(e) => {
-                            if let Ok(ScannerError::EmptyAddrs) = e.downcast() {
-                                break;
+                    let record = match self_clone.ping().await {
+                        Ok((i, resp, server)) => {
+                            if self_clone.conditions.is_valid(resp) {
+                                Record::Found(i, Box::new(server))
+                            } else {
+                                Record::Filtered(i)
                             }
                         }
+                        Err(e) => match e.downcast() {
+                            Ok(ScannerError::EmptyAddrs) => break,
+                            Ok(ScannerError::Probe(i, attempt, addr, err)) => {
+                                Record::Failed(i, addr, err, attempt)
+                            }
+                            Err(_) => continue,
+                        },
+                    };
+                    if tx.send(record).await.is_err() {
+                        // the writer failed, its error is reported below
+                        break;
                     }
                 }
             });
             join_handles.push(jh);
         }
+        drop(tx);
         for jh in join_handles {
             jh.await?;
         }
-        let good_servers = {
-            let mut fh = safe_file_handler.write().await;
+        let (mut out, mut failures_out) = writer.await??;
+        scanning.store(false, Ordering::Relaxed);
+        let spinner = progress.await?;
+        signal_handler.abort();
+        if let Some(controller) = controller {
+            controller.abort();
+        }
+        let interrupted = self.stop.load(Ordering::Relaxed);
+        let failures = self.failures.snapshot();
+        metadata.retries = self.retries.load(Ordering::Relaxed);
+        if interrupted {
+            // the checkpoint has to be taken before `done` appends the closing brackets
+            self.checkpoint(&mut out, &mut failures_out, &metadata, &checkpoint_path)
+                .await?;
+            metadata.found = out.count();
+            metadata.failures = failures.clone();
+        } else {
+            metadata.finish(out.count(), failures.clone());
+            if checkpoint_path.exists() {
+                std::fs::remove_file(&checkpoint_path)?;
+            }
+        }
+        out.done(Some(&metadata)).await?;
+        if let Some(fh) = &mut failures_out {
             fh.done().await?;
-            fh.count
-        };
-        if let Some(s) = spinner {
-            s.success(&format!(
+        }
+        let good_servers = out.count();
+        let msg = if interrupted {
+            format!(
+                "Interrupted after finding {} good servers, run again with --resume to continue",
+                good_servers.cyan()
+            )
+        } else {
+            format!(
                 "Found {} good servers out of {}!",
                 good_servers.cyan(),
                 total_servers.cyan()
-            ));
-        } else {
+            )
+        };
+        match spinner {
+            Some(s) if interrupted => s.warn(&msg),
+            Some(s) => s.success(&msg),
+            None => println!("\n{} {msg}", "::".green().bold()),
+        }
+        if !failures.is_empty() {
             println!(
-                "{} Found {} good servers out of {}!",
-                "::".green().bold(),
-                good_servers.cyan(),
-                total_servers.cyan()
+                "{} Failed to ping {} servers: {}",
+                "::".yellow().bold(),
+                failures.values().sum::<usize>().cyan(),
+                summarize(&failures)
+            );
+        }
+        if metadata.skipped > 0 {
+            println!(
+                "{} Skipped {} excluded targets",
+                "::".blue().bold(),
+                metadata.skipped.cyan()
+            );
+        }
+        if metadata.retries > 0 {
+            println!(
+                "{} Retried {} probes",
+                "::".blue().bold(),
+                metadata.retries.cyan()
             );
         }
 
         Ok(())
     }
 }
-async fn update(opt_pb: Option<Arc<RwLock<Bar>>>) {
-    if let Some(pb) = opt_pb {
-        let mut bar = pb.write().await;
-        bar.update(1);
+/// Resolves on SIGINT, or SIGTERM on unix
+pub async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut term = signal(SignalKind::terminate()).expect("failed to listen for SIGTERM");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {},
+            _ = term.recv() => {},
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
     }
 }
@@ -0,0 +1,218 @@
+// CCheck: utility for scanning and probing minecraft servers
+// Copyright (C) 2022 cleonyc
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//! When each monitored target is checked next
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap},
+    fmt::Display,
+    net::IpAddr,
+    str::FromStr,
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+
+use crate::{exclude::IpRange, retry::jitter};
+
+/// How often targets are checked
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SchedulePolicy {
+    /// Seconds between checks of a target
+    pub interval: f64,
+    /// Targets checked at their own interval, the most specific rule applies
+    #[serde(default)]
+    pub rules: Vec<IntervalRule>,
+    /// Fraction of the interval checks are moved by at random, so targets checked together drift apart
+    pub jitter: f64,
+    /// Seconds offline targets are checked at most, the interval doubles with every failed check
+    /// up to this. 0 checks offline targets at their usual interval
+    pub offline_max: f64,
+}
+impl Default for SchedulePolicy {
+    fn default() -> Self {
+        Self {
+            interval: 10.,
+            rules: vec![],
+            jitter: 0.1,
+            offline_max: 600.,
+        }
+    }
+}
+impl SchedulePolicy {
+    /// Rejects intervals that can't be turned into durations, and checking servers back to back
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let valid = |secs: f64| secs.is_finite() && secs >= 0.;
+        if !(valid(self.interval) && self.interval > 0.) {
+            anyhow::bail!(
+                "invalid interval `{}`, it must be more than 0",
+                self.interval
+            );
+        }
+        if !valid(self.offline_max) {
+            anyhow::bail!("invalid offline interval `{}`", self.offline_max);
+        }
+        if !(0. ..=1.).contains(&self.jitter) {
+            anyhow::bail!(
+                "interval jitter must be between 0 and 1, got `{}`",
+                self.jitter
+            );
+        }
+        Ok(())
+    }
+    /// Interval of `ip` while it's online
+    pub fn interval(&self, ip: IpAddr) -> Duration {
+        let secs = self
+            .rules
+            .iter()
+            .filter(|rule| rule.range.contains(ip))
+            .min_by_key(|rule| rule.range.span())
+            .map_or(self.interval, |rule| rule.interval);
+        Duration::from_secs_f64(secs)
+    }
+    /// Interval of `ip` after `failures` failed checks in a row
+    fn backoff(&self, ip: IpAddr, failures: u32) -> Duration {
+        let interval = self.interval(ip);
+        if failures == 0 || self.offline_max <= 0. {
+            return interval;
+        }
+        let max = Duration::from_secs_f64(self.offline_max).max(interval);
+        interval
+            .saturating_mul(2u32.saturating_pow(failures))
+            .min(max)
+    }
+}
+
+/// An interval for a range of targets, `<ip, cidr or first-last>=<seconds>` on the command line
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct IntervalRule {
+    pub range: IpRange,
+    pub interval: f64,
+}
+impl FromStr for IntervalRule {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (range, interval) = s
+            .rsplit_once('=')
+            .ok_or_else(|| format!("invalid interval `{s}`, expected <range>=<seconds>"))?;
+        let interval = interval
+            .parse::<f64>()
+            .ok()
+            .filter(|secs| secs.is_finite() && *secs > 0.)
+            .ok_or_else(|| format!("invalid interval `{interval}`, it must be more than 0"))?;
+        Ok(Self {
+            range: range.parse().map_err(|e| format!("{e}"))?,
+            interval,
+        })
+    }
+}
+impl TryFrom<String> for IntervalRule {
+    type Error = String;
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+impl From<IntervalRule> for String {
+    fn from(rule: IntervalRule) -> Self {
+        rule.to_string()
+    }
+}
+impl Display for IntervalRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}={}", self.range, self.interval)
+    }
+}
+
+/// A check that is due at some point
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Check {
+    due: Instant,
+    target: usize,
+    /// Attempt number of the check, retries count up
+    attempt: u32,
+}
+
+/// Hands out targets when they are due, earliest first
+#[derive(Debug)]
+pub struct Scheduler {
+    policy: SchedulePolicy,
+    queue: BinaryHeap<Reverse<Check>>,
+    /// Failed checks in a row per target
+    failures: HashMap<usize, u32>,
+}
+impl Scheduler {
+    /// Schedules every one of `targets` targets right away
+    pub fn new(policy: SchedulePolicy, targets: usize) -> Self {
+        let now = Instant::now();
+        Self {
+            policy,
+            queue: (0..targets)
+                .map(|target| {
+                    Reverse(Check {
+                        due: now,
+                        target,
+                        attempt: 1,
+                    })
+                })
+                .collect(),
+            failures: HashMap::new(),
+        }
+    }
+    /// When the next check is due, `None` if every target is being checked
+    pub fn next_due(&self) -> Option<Instant> {
+        self.queue.peek().map(|Reverse(check)| check.due)
+    }
+    /// A target that is due and its attempt number
+    pub fn pop_due(&mut self, now: Instant) -> Option<(usize, u32)> {
+        match self.queue.peek() {
+            Some(Reverse(check)) if check.due <= now => {
+                let check = self.queue.pop()?.0;
+                Some((check.target, check.attempt))
+            }
+            _ => None,
+        }
+    }
+    /// Tries `target` again after `delay` as attempt number `attempt`
+    pub fn retry(&mut self, target: usize, attempt: u32, delay: Duration) {
+        self.push(target, attempt, delay);
+    }
+    /// Schedules the next check of `target` after it was checked, `online` telling how that went
+    pub fn done(&mut self, target: usize, ip: IpAddr, online: bool) {
+        let failures = match online {
+            true => {
+                self.failures.remove(&target);
+                0
+            }
+            false => {
+                let failures = self.failures.entry(target).or_default();
+                *failures += 1;
+                *failures
+            }
+        };
+        let interval = self.policy.backoff(ip, failures);
+        // somewhere in interval ± jitter
+        let spread = self.policy.jitter.clamp(0., 1.) * (jitter() * 2. - 1.);
+        self.push(target, 1, interval.mul_f64(1. + spread));
+    }
+    fn push(&mut self, target: usize, attempt: u32, delay: Duration) {
+        self.queue.push(Reverse(Check {
+            due: Instant::now() + delay,
+            target,
+            attempt,
+        }));
+    }
+}
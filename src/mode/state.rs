@@ -0,0 +1,358 @@
+// CCheck: utility for scanning and probing minecraft servers
+// Copyright (C) 2022 cleonyc
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//! Monitor state that outlives a run
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    adapters::CCheckResponse,
+    format::ccheck::Server,
+    mode::{
+        alerts::{AlertState, Alerts},
+        events::{Event, EventType, TargetState, Tracker},
+    },
+    probe::ProbeError,
+};
+
+/// Checks buffered before they are written
+const CHECK_BATCH: usize = 1000;
+/// Longest checks are buffered for
+const CHECK_FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS monitor_targets (
+    ip TEXT NOT NULL,
+    port INTEGER NOT NULL,
+    online INTEGER NOT NULL,
+    -- JSON encoded last successful response, see `Server`
+    last TEXT,
+    updated_at INTEGER NOT NULL,
+    PRIMARY KEY (ip, port)
+);
+CREATE TABLE IF NOT EXISTS monitor_alerts (
+    ip TEXT NOT NULL,
+    port INTEGER NOT NULL,
+    -- conditions as given on the command line, alerts for other conditions are kept apart
+    condition TEXT NOT NULL,
+    matching_since INTEGER,
+    notified INTEGER NOT NULL,
+    last_alert INTEGER,
+    PRIMARY KEY (ip, port, condition)
+);
+CREATE TABLE IF NOT EXISTS monitor_events (
+    id INTEGER PRIMARY KEY,
+    timestamp INTEGER NOT NULL,
+    ip TEXT NOT NULL,
+    port INTEGER NOT NULL,
+    event TEXT NOT NULL,
+    -- JSON encoded event, as in `--events-file`
+    data TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS monitor_events_server ON monitor_events (ip, port, timestamp);
+CREATE TABLE IF NOT EXISTS monitor_checks (
+    timestamp INTEGER NOT NULL,
+    ip TEXT NOT NULL,
+    port INTEGER NOT NULL,
+    online INTEGER NOT NULL,
+    -- ping round trip, NULL while offline or if the server doesn't answer pings
+    latency_ms REAL,
+    players INTEGER,
+    -- failure category while offline
+    error TEXT
+);
+CREATE INDEX IF NOT EXISTS monitor_checks_server ON monitor_checks (ip, port, timestamp);
+CREATE TABLE IF NOT EXISTS monitor_outages (
+    id INTEGER PRIMARY KEY,
+    ip TEXT NOT NULL,
+    port INTEGER NOT NULL,
+    -- first failed check, and the first successful check after it, NULL while the server is still down
+    started_at INTEGER NOT NULL,
+    ended_at INTEGER,
+    -- failure category of the first failed check
+    error TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS monitor_outages_server ON monitor_outages (ip, port, started_at);
+";
+
+/// Whether the result of every check is kept for `ccheck report`
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct StatsPolicy {
+    pub record: bool,
+    /// Days checks and outages are kept for, 0 keeps them forever
+    pub retention: usize,
+}
+impl Default for StatsPolicy {
+    fn default() -> Self {
+        Self {
+            record: false,
+            retention: 30,
+        }
+    }
+}
+
+/// Result of one check, waiting to be written
+struct CheckRow {
+    timestamp: usize,
+    server: (IpAddr, u16),
+    latency_ms: Option<f64>,
+    players: Option<usize>,
+    error: Option<String>,
+}
+
+/// What the monitor knows about its targets, kept in a SQLite database so a restarted monitor
+/// continues diffing and alerting where the last run stopped. The tables can live in the same
+/// database as `--output-format sqlite` scans
+pub struct MonitorState {
+    conn: Connection,
+    /// Ids of the outages still going on
+    outages: HashMap<(IpAddr, u16), i64>,
+    /// Checks not written yet
+    checks: Vec<CheckRow>,
+    flushed_at: Instant,
+}
+impl MonitorState {
+    /// Opens (or creates) the state database, `fresh` forgets the saved state but keeps the event history
+    pub fn open(path: &Path, fresh: bool) -> anyhow::Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(SCHEMA)?;
+        if fresh {
+            conn.execute_batch("DELETE FROM monitor_targets; DELETE FROM monitor_alerts;")?;
+        }
+        let outages = conn
+            .prepare("SELECT ip, port, id FROM monitor_outages WHERE ended_at IS NULL")?
+            .query_map([], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, u16>(1)?, row.get(2)?))
+            })?
+            .filter_map(|row| {
+                let (ip, port, id) = row.ok()?;
+                Some(((ip.parse().ok()?, port), id))
+            })
+            .collect();
+        Ok(Self {
+            conn,
+            outages,
+            checks: vec![],
+            flushed_at: Instant::now(),
+        })
+    }
+    /// Loads the saved state of `targets` into `tracker` and `alerts`, returns how many targets had one
+    pub fn restore(
+        &self,
+        targets: &[(IpAddr, u16)],
+        tracker: &mut Tracker,
+        alerts: &mut Alerts,
+    ) -> anyhow::Result<usize> {
+        let mut restored = 0;
+        let mut target_stmt = self
+            .conn
+            .prepare("SELECT online, last FROM monitor_targets WHERE ip = ?1 AND port = ?2")?;
+        let mut alert_stmt = self.conn.prepare(
+            "SELECT matching_since, notified, last_alert FROM monitor_alerts
+             WHERE ip = ?1 AND port = ?2 AND condition = ?3",
+        )?;
+        for &(ip, port) in targets {
+            let ip_str = ip.to_string();
+            let mut rows = target_stmt.query(params![ip_str, port])?;
+            if let Some(row) = rows.next()? {
+                let last = row
+                    .get::<_, Option<String>>(1)?
+                    .map(|last| serde_json::from_str::<Server>(&last))
+                    .transpose()?
+                    .map(Server::into_resp);
+                let state = TargetState {
+                    online: row.get(0)?,
+                    last,
+                };
+                tracker.restore((ip, port), state);
+                restored += 1;
+            }
+            let mut rows = alert_stmt.query(params![ip_str, port, alerts.condition()])?;
+            if let Some(row) = rows.next()? {
+                let state = AlertState {
+                    matching_since: row.get(0)?,
+                    notified: row.get(1)?,
+                    last_alert: row.get(2)?,
+                };
+                alerts.restore((ip, port), state);
+            }
+        }
+        Ok(restored)
+    }
+    /// Saves the targets and alerts that changed since the last call
+    pub fn save(
+        &mut self,
+        timestamp: usize,
+        tracker: &mut Tracker,
+        alerts: &mut Alerts,
+    ) -> anyhow::Result<()> {
+        let targets = tracker.take_changed();
+        let alert_states = alerts.take_changed();
+        if targets.is_empty() && alert_states.is_empty() {
+            return Ok(());
+        }
+        let tx = self.conn.transaction()?;
+        {
+            let mut stmt = tx.prepare(
+                "INSERT OR REPLACE INTO monitor_targets (ip, port, online, last, updated_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+            )?;
+            for ((ip, port), state) in targets {
+                let last = state
+                    .last
+                    .map(|resp| serde_json::to_string(&Server::from_resp(resp, (ip, port))))
+                    .transpose()?;
+                stmt.execute(params![ip.to_string(), port, state.online, last, timestamp])?;
+            }
+            let mut stmt = tx.prepare(
+                "INSERT OR REPLACE INTO monitor_alerts
+                 (ip, port, condition, matching_since, notified, last_alert)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            )?;
+            for ((ip, port), state) in alert_states {
+                stmt.execute(params![
+                    ip.to_string(),
+                    port,
+                    alerts.condition(),
+                    state.matching_since,
+                    state.notified,
+                    state.last_alert
+                ])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+    /// Adds `events` to the event history
+    pub fn write_events(&mut self, events: &[Event]) -> anyhow::Result<()> {
+        if events.is_empty() {
+            return Ok(());
+        }
+        let tx = self.conn.transaction()?;
+        {
+            let mut stmt = tx.prepare(
+                "INSERT INTO monitor_events (timestamp, ip, port, event, data)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+            )?;
+            for event in events {
+                stmt.execute(params![
+                    event.timestamp,
+                    event.server.0.to_string(),
+                    event.server.1,
+                    EventType::from(&event.kind).to_string(),
+                    serde_json::to_string(event)?
+                ])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+    /// Records the result of a check for the uptime statistics, and opens or closes outages.
+    /// Checks are written in batches, see [`MonitorState::flush_checks`]
+    pub fn record_check(
+        &mut self,
+        timestamp: usize,
+        server: (IpAddr, u16),
+        result: Result<&CCheckResponse, &ProbeError>,
+    ) -> anyhow::Result<()> {
+        let (ip, port) = (server.0.to_string(), server.1);
+        let row = match result {
+            Ok(resp) => {
+                if let Some(id) = self.outages.remove(&server) {
+                    self.conn.execute(
+                        "UPDATE monitor_outages SET ended_at = ?1 WHERE id = ?2",
+                        params![timestamp, id],
+                    )?;
+                }
+                CheckRow {
+                    timestamp,
+                    server,
+                    latency_ms: resp.timing.as_ref().and_then(|t| t.latency_ms),
+                    players: Some(resp.online_players),
+                    error: None,
+                }
+            }
+            Err(err) => {
+                let error = err.kind().to_string();
+                if !self.outages.contains_key(&server) {
+                    self.conn.execute(
+                        "INSERT INTO monitor_outages (ip, port, started_at, error) VALUES (?1, ?2, ?3, ?4)",
+                        params![ip, port, timestamp, error],
+                    )?;
+                    self.outages.insert(server, self.conn.last_insert_rowid());
+                }
+                CheckRow {
+                    timestamp,
+                    server,
+                    latency_ms: None,
+                    players: None,
+                    error: Some(error),
+                }
+            }
+        };
+        self.checks.push(row);
+        Ok(())
+    }
+    /// Writes the buffered checks once enough piled up or they waited long enough, or right away with `force`
+    pub fn flush_checks(&mut self, force: bool) -> anyhow::Result<()> {
+        if self.checks.is_empty()
+            || !force
+                && self.checks.len() < CHECK_BATCH
+                && self.flushed_at.elapsed() < CHECK_FLUSH_INTERVAL
+        {
+            return Ok(());
+        }
+        let tx = self.conn.transaction()?;
+        {
+            let mut stmt = tx.prepare(
+                "INSERT INTO monitor_checks (timestamp, ip, port, online, latency_ms, players, error)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            )?;
+            for check in self.checks.drain(..) {
+                stmt.execute(params![
+                    check.timestamp,
+                    check.server.0.to_string(),
+                    check.server.1,
+                    check.error.is_none(),
+                    check.latency_ms,
+                    check.players,
+                    check.error
+                ])?;
+            }
+        }
+        tx.commit()?;
+        self.flushed_at = Instant::now();
+        Ok(())
+    }
+    /// Deletes checks and finished outages from before `timestamp`
+    pub fn prune_stats(&mut self, timestamp: usize) -> anyhow::Result<()> {
+        self.conn.execute(
+            "DELETE FROM monitor_checks WHERE timestamp < ?1",
+            params![timestamp],
+        )?;
+        self.conn.execute(
+            "DELETE FROM monitor_outages WHERE ended_at < ?1",
+            params![timestamp],
+        )?;
+        Ok(())
+    }
+}
@@ -0,0 +1,550 @@
+// CCheck: utility for scanning and probing minecraft servers
+// Copyright (C) 2022 cleonyc
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//! Where tracked players are across all monitored servers, and where they have been
+use std::{
+    fs::{self, File},
+    io::{BufReader, BufWriter, Write},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use owo_colors::OwoColorize;
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+use crate::{
+    adapters::CCheckResponse,
+    mode::events::{format_duration, format_timestamp, Event, EventKind},
+};
+
+/// Finished sessions kept per player, older ones are dropped
+const MAX_SESSIONS: usize = 1000;
+/// Longest the snapshot goes without the latest sightings, sessions starting or ending are written right away
+const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Players to follow across the monitored servers
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrackPolicy {
+    /// Usernames (case insensitive) or UUIDs
+    pub players: Vec<String>,
+    /// JSON file the players' whereabouts are kept in, and picked up from on the next start
+    #[serde(default)]
+    pub snapshot: Option<PathBuf>,
+    /// Seconds a player may be missing from a server's player sample before their session there ends.
+    /// Only applies to servers that list part of their players, complete samples end sessions right away
+    pub timeout: usize,
+}
+impl Default for TrackPolicy {
+    fn default() -> Self {
+        Self {
+            players: vec![],
+            snapshot: None,
+            timeout: 300,
+        }
+    }
+}
+
+/// Reads players to track from `path`, one username or UUID per line. Empty lines and `#` comments are skipped
+pub fn read_players(path: &Path) -> anyhow::Result<Vec<String>> {
+    let players = fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("failed to read {}: {e}", path.display()))?;
+    Ok(players
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter(|line| !line.is_empty())
+        .map(String::from)
+        .collect())
+}
+
+/// UUID without dashes in lower case, `None` if `s` isn't one
+fn normalize_uuid(s: &str) -> Option<String> {
+    let hex = s.replace('-', "").to_ascii_lowercase();
+    (hex.len() == 32 && hex.chars().all(|c| c.is_ascii_hexdigit())).then_some(hex)
+}
+
+/// What a tracked player is recognized by
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Tracked {
+    Uuid(String),
+    /// Lower case
+    Name(String),
+}
+impl Tracked {
+    fn new(query: &str) -> Self {
+        match normalize_uuid(query) {
+            Some(uuid) => Tracked::Uuid(uuid),
+            None => Tracked::Name(query.to_lowercase()),
+        }
+    }
+    fn matches(&self, name: &str, uuid: &str) -> bool {
+        match self {
+            Tracked::Uuid(tracked) => normalize_uuid(uuid).as_ref() == Some(tracked),
+            Tracked::Name(tracked) => name.to_lowercase() == *tracked,
+        }
+    }
+}
+
+/// Time a player spent on one server
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Session {
+    pub server: (IpAddr, u16),
+    /// Unix timestamp of when the player was first seen on the server
+    pub start: usize,
+    /// Unix timestamp of when the player was last seen on the server
+    pub last_seen: usize,
+    /// Seconds between `start` and `last_seen`
+    pub duration: usize,
+}
+
+/// Everything known about one tracked player
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlayerRecord {
+    /// The username or UUID as given to `--track`
+    pub query: String,
+    /// Name the player was last seen with
+    pub name: Option<String>,
+    /// UUID the player was last seen with
+    pub uuid: Option<String>,
+    /// Unix timestamps of when the player was first and last seen anywhere
+    pub first_seen: Option<usize>,
+    pub last_seen: Option<usize>,
+    /// Sessions going on right now, several if the name is on more than one server
+    pub online: Vec<Session>,
+    /// Finished sessions, oldest first
+    pub sessions: Vec<Session>,
+}
+impl PlayerRecord {
+    fn new(query: String) -> Self {
+        Self {
+            query,
+            name: None,
+            uuid: None,
+            first_seen: None,
+            last_seen: None,
+            online: vec![],
+            sessions: vec![],
+        }
+    }
+    fn display_name(&self) -> &str {
+        self.name.as_deref().unwrap_or(&self.query)
+    }
+    /// Whether `player` refers to this record, by query, name or UUID
+    fn is(&self, player: &str) -> bool {
+        let tracked = Tracked::new(player);
+        self.query.eq_ignore_ascii_case(player)
+            || self
+                .uuid
+                .as_deref()
+                .is_some_and(|uuid| uuid.eq_ignore_ascii_case(player))
+            || tracked.matches(
+                self.name.as_deref().unwrap_or_default(),
+                self.uuid.as_deref().unwrap_or_default(),
+            )
+    }
+}
+
+/// The JSON snapshot file
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// Unix timestamp of when the snapshot was written
+    pub updated: usize,
+    pub players: Vec<PlayerRecord>,
+}
+impl Snapshot {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let file = File::open(path)
+            .map_err(|e| anyhow::anyhow!("failed to open {}: {e}", path.display()))?;
+        Ok(serde_json::from_reader(BufReader::new(file))?)
+    }
+    /// Writes to a temporary file first so readers never see half a snapshot
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        serde_json::to_writer_pretty(&mut writer, self)?;
+        writer.flush()?;
+        fs::rename(tmp, path)?;
+        Ok(())
+    }
+}
+
+/// Follows the tracked players through the responses of every monitored server
+#[derive(Debug)]
+pub struct PlayerTracker {
+    timeout: usize,
+    snapshot_file: Option<PathBuf>,
+    /// Same order as `records`
+    tracked: Vec<Tracked>,
+    records: Vec<PlayerRecord>,
+    /// Whether a session started or ended since the snapshot was last written
+    changed: bool,
+    /// Whether players were seen since the snapshot was last written
+    seen: bool,
+    saved_at: Instant,
+}
+impl PlayerTracker {
+    /// Picks up the history of the players still tracked from the snapshot file, if there is one
+    pub fn new(policy: &TrackPolicy) -> anyhow::Result<Self> {
+        let mut saved = match &policy.snapshot {
+            Some(path) if path.exists() => Snapshot::load(path)?.players,
+            _ => vec![],
+        };
+        let records = policy
+            .players
+            .iter()
+            .map(
+                |query| match saved.iter().position(|record| record.query == *query) {
+                    Some(pos) => saved.swap_remove(pos),
+                    None => PlayerRecord::new(query.clone()),
+                },
+            )
+            .collect();
+        Ok(Self {
+            timeout: policy.timeout,
+            snapshot_file: policy.snapshot.clone(),
+            tracked: policy.players.iter().map(|q| Tracked::new(q)).collect(),
+            records,
+            changed: true,
+            seen: false,
+            saved_at: Instant::now(),
+        })
+    }
+    /// Records the result of checking `server`, `response` is `None` while it's offline.
+    /// Returns the sessions that started or ended
+    pub fn observe(
+        &mut self,
+        server: (IpAddr, u16),
+        response: Option<&CCheckResponse>,
+    ) -> Vec<Event> {
+        let timestamp = OffsetDateTime::now_utc().unix_timestamp() as usize;
+        let sample = response
+            .and_then(|resp| resp.sample.as_deref())
+            .unwrap_or_default();
+        // a sample listing everybody tells for sure who isn't there
+        let complete = response.is_none_or(|resp| sample.len() >= resp.online_players);
+        let mut events = vec![];
+        for (tracked, record) in self.tracked.iter().zip(&mut self.records) {
+            let seen = sample.iter().find(|p| tracked.matches(&p.name, &p.id));
+            let session = record.online.iter().position(|s| s.server == server);
+            match (seen, session) {
+                (Some(player), session) => {
+                    record.name = Some(player.name.clone());
+                    record.uuid = Some(player.id.clone()).filter(|id| !id.is_empty());
+                    record.first_seen.get_or_insert(timestamp);
+                    record.last_seen = Some(timestamp);
+                    match session {
+                        Some(pos) => {
+                            let session = &mut record.online[pos];
+                            session.last_seen = timestamp;
+                            session.duration = timestamp.saturating_sub(session.start);
+                        }
+                        None => {
+                            record.online.push(Session {
+                                server,
+                                start: timestamp,
+                                last_seen: timestamp,
+                                duration: 0,
+                            });
+                            events.push(Event {
+                                timestamp,
+                                server,
+                                kind: EventKind::SessionStarted {
+                                    name: player.name.clone(),
+                                    uuid: player.id.clone(),
+                                },
+                            });
+                            self.changed = true;
+                        }
+                    }
+                    self.seen = true;
+                }
+                (None, Some(pos))
+                    if complete
+                        || timestamp.saturating_sub(record.online[pos].last_seen)
+                            >= self.timeout =>
+                {
+                    let session = record.online.remove(pos);
+                    events.push(Event {
+                        timestamp,
+                        server,
+                        kind: EventKind::SessionEnded {
+                            name: record.display_name().to_string(),
+                            uuid: record.uuid.clone().unwrap_or_default(),
+                            duration: session.duration,
+                        },
+                    });
+                    record.sessions.push(session);
+                    if record.sessions.len() > MAX_SESSIONS {
+                        record.sessions.remove(0);
+                    }
+                    self.changed = true;
+                }
+                _ => {}
+            }
+        }
+        events
+    }
+    /// Writes the snapshot file if a session started or ended, players were seen a while ago,
+    /// or with `force` anything changed since the last time
+    pub fn save(&mut self, force: bool) -> anyhow::Result<()> {
+        let Some(path) = &self.snapshot_file else {
+            return Ok(());
+        };
+        let due = force || self.saved_at.elapsed() >= SNAPSHOT_INTERVAL;
+        if !(self.changed || self.seen && due) {
+            return Ok(());
+        }
+        Snapshot {
+            updated: OffsetDateTime::now_utc().unix_timestamp() as usize,
+            players: self.records.clone(),
+        }
+        .save(path)?;
+        self.changed = false;
+        self.seen = false;
+        self.saved_at = Instant::now();
+        Ok(())
+    }
+}
+
+/// Prints where the players in the snapshot at `path` are, all of them if `players` is empty
+pub fn query(path: &Path, players: &[String], history: bool, json: bool) -> anyhow::Result<()> {
+    let snapshot = Snapshot::load(path)?;
+    let now = OffsetDateTime::now_utc().unix_timestamp() as usize;
+    let records = snapshot
+        .players
+        .iter()
+        .filter(|record| players.is_empty() || players.iter().any(|p| record.is(p)))
+        .collect::<Vec<_>>();
+    if json {
+        println!("{}", serde_json::to_string_pretty(&records)?);
+        return Ok(());
+    }
+    if records.is_empty() {
+        println!("{} No such players are tracked", "::".yellow().bold());
+    }
+    for record in records {
+        let name = record.display_name();
+        for session in &record.online {
+            println!(
+                "{} {} is on {} since {} ({})",
+                "::".green().bold(),
+                name.cyan(),
+                format!("{}:{}", session.server.0, session.server.1).purple(),
+                format_timestamp(session.start),
+                format_duration(session.duration)
+            );
+        }
+        match record.sessions.last() {
+            _ if !record.online.is_empty() => {}
+            Some(session) => println!(
+                "{} {} was last seen on {} at {} ({} ago)",
+                "::".blue().bold(),
+                name.cyan(),
+                format!("{}:{}", session.server.0, session.server.1).purple(),
+                format_timestamp(session.last_seen),
+                format_duration(now.saturating_sub(session.last_seen))
+            ),
+            None => println!(
+                "{} {} hasn't been seen yet",
+                "::".yellow().bold(),
+                name.cyan()
+            ),
+        }
+        if history {
+            for session in record.sessions.iter().rev() {
+                println!(
+                    "   {:<21} {} - {} ({})",
+                    format!("{}:{}", session.server.0, session.server.1),
+                    format_timestamp(session.start),
+                    format_timestamp(session.last_seen),
+                    format_duration(session.duration)
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use crate::adapters::{CCheckComponent, CCheckPlayer};
+
+    const SERVER: (IpAddr, u16) = (IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)), 25565);
+    const NOTCH: &str = "069a79f4-44e9-4726-a5be-fca90e38aaf5";
+
+    /// A response listing `sample` out of `online` players
+    fn response(online: usize, sample: &[(&str, &str)]) -> CCheckResponse {
+        CCheckResponse {
+            version: "1.20.1".to_string(),
+            protocol: 763,
+            max_players: 20,
+            online_players: online,
+            sample: Some(
+                sample
+                    .iter()
+                    .map(|(name, id)| CCheckPlayer {
+                        name: name.to_string(),
+                        id: id.to_string(),
+                    })
+                    .collect(),
+            ),
+            description: CCheckComponent::default(),
+            favicon: None,
+            timing: None,
+            proxy_protocol: false,
+        }
+    }
+
+    fn tracker(players: &[&str]) -> PlayerTracker {
+        PlayerTracker::new(&TrackPolicy {
+            players: players.iter().map(|p| p.to_string()).collect(),
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    fn joined(tracker: &mut PlayerTracker) {
+        let events = tracker.observe(SERVER, Some(&response(1, &[("Notch", NOTCH)])));
+        assert!(matches!(
+            &events[..],
+            [Event { kind: EventKind::SessionStarted { name, uuid }, .. }]
+                if name == "Notch" && uuid == NOTCH
+        ));
+    }
+
+    #[test]
+    fn join() {
+        // by name in any case, and by uuid with or without dashes
+        for query in ["notch", NOTCH, &NOTCH.replace('-', "")] {
+            let mut tracker = tracker(&[query]);
+            joined(&mut tracker);
+            let record = &tracker.records[0];
+            assert_eq!(record.name.as_deref(), Some("Notch"));
+            assert_eq!(record.online.len(), 1);
+            assert_eq!(record.online[0].server, SERVER);
+            // still there, no new session
+            let events = tracker.observe(SERVER, Some(&response(1, &[("Notch", NOTCH)])));
+            assert!(events.is_empty());
+            assert_eq!(tracker.records[0].online.len(), 1);
+        }
+    }
+
+    #[test]
+    fn complete_sample_ends_session() {
+        let mut tracker = tracker(&["Notch"]);
+        joined(&mut tracker);
+        let events = tracker.observe(SERVER, Some(&response(1, &[("jeb_", "j")])));
+        assert!(matches!(
+            &events[..],
+            [Event { kind: EventKind::SessionEnded { name, .. }, .. }] if name == "Notch"
+        ));
+        let record = &tracker.records[0];
+        assert!(record.online.is_empty());
+        assert_eq!(record.sessions.len(), 1);
+    }
+
+    #[test]
+    fn partial_sample_waits_for_timeout() {
+        let mut tracker = tracker(&["Notch"]);
+        joined(&mut tracker);
+        // 30 online but only one listed, Notch may well still be there
+        let partial = response(30, &[("jeb_", "j")]);
+        assert!(tracker.observe(SERVER, Some(&partial)).is_empty());
+        assert_eq!(tracker.records[0].online.len(), 1);
+        tracker.records[0].online[0].last_seen -= tracker.timeout;
+        let events = tracker.observe(SERVER, Some(&partial));
+        assert!(matches!(
+            &events[..],
+            [Event {
+                kind: EventKind::SessionEnded { .. },
+                ..
+            }]
+        ));
+        assert!(tracker.records[0].online.is_empty());
+    }
+
+    #[test]
+    fn offline_ends_session() {
+        let mut tracker = tracker(&["Notch"]);
+        joined(&mut tracker);
+        let events = tracker.observe(SERVER, None);
+        assert_eq!(events.len(), 1);
+        assert!(tracker.records[0].online.is_empty());
+        // other servers going offline don't matter
+        joined(&mut tracker);
+        let other = (SERVER.0, 25566);
+        assert!(tracker.observe(other, None).is_empty());
+        assert_eq!(tracker.records[0].online.len(), 1);
+    }
+
+    #[test]
+    fn old_sessions_are_dropped() {
+        let mut tracker = tracker(&["Notch"]);
+        let old = |start| Session {
+            server: SERVER,
+            start,
+            last_seen: start,
+            duration: 0,
+        };
+        tracker.records[0].sessions = (0..MAX_SESSIONS).map(old).collect();
+        joined(&mut tracker);
+        tracker.observe(SERVER, None);
+        let sessions = &tracker.records[0].sessions;
+        assert_eq!(sessions.len(), MAX_SESSIONS);
+        assert_eq!(sessions[0].start, 1);
+        assert!(sessions[MAX_SESSIONS - 1].start > MAX_SESSIONS);
+    }
+
+    #[test]
+    fn snapshot_round_trip() {
+        let path = std::env::temp_dir().join(format!("ccheck-track-{}.json", std::process::id()));
+        let policy = |players: &[&str]| TrackPolicy {
+            players: players.iter().map(|p| p.to_string()).collect(),
+            snapshot: Some(path.clone()),
+            ..Default::default()
+        };
+        let mut tracker = PlayerTracker::new(&policy(&["Notch", "jeb_"])).unwrap();
+        joined(&mut tracker);
+        tracker.save(false).unwrap();
+        assert!(path.exists());
+        // a sighting alone waits for the interval, or for the monitor stopping
+        fs::remove_file(&path).unwrap();
+        tracker.observe(SERVER, Some(&response(1, &[("Notch", NOTCH)])));
+        tracker.save(false).unwrap();
+        assert!(!path.exists());
+        tracker.save(true).unwrap();
+        assert!(path.exists());
+
+        // picked up by query whatever the order, players no longer tracked are dropped
+        let tracker = PlayerTracker::new(&policy(&["Dinnerbone", "Notch"])).unwrap();
+        let _ = fs::remove_file(&path);
+        let queries = tracker
+            .records
+            .iter()
+            .map(|r| r.query.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(queries, ["Dinnerbone", "Notch"]);
+        assert!(tracker.records[0].online.is_empty());
+        let notch = &tracker.records[1];
+        assert_eq!(notch.uuid.as_deref(), Some(NOTCH));
+        assert_eq!(notch.online.len(), 1);
+        assert!(notch.first_seen.is_some());
+    }
+}
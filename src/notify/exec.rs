@@ -0,0 +1,116 @@
+// CCheck: utility for scanning and probing minecraft servers
+// Copyright (C) 2022 cleonyc
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//! Runs a command per event
+use std::{process::Stdio, time::Duration};
+
+use async_trait::async_trait;
+use tokio::{io::AsyncWriteExt, process::Command};
+
+use super::{Notification, Notifier};
+
+/// How long the command may run
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Runs a command with the event as JSON on its stdin. The command line is split on
+/// whitespace and run directly, use `sh -c '...'` for anything a shell is needed for.
+/// Its output is discarded, it would end up in the middle of `monitor --json` output otherwise
+#[derive(Debug)]
+pub struct Exec {
+    program: String,
+    args: Vec<String>,
+}
+impl Exec {
+    pub fn new(command: &str) -> anyhow::Result<Self> {
+        let mut words = command.split_whitespace().map(str::to_string);
+        let program = words
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("exec notifier needs a command"))?;
+        Ok(Self {
+            program,
+            args: words.collect(),
+        })
+    }
+}
+#[async_trait]
+impl Notifier for Exec {
+    fn name(&self) -> String {
+        format!("exec:{}", self.program)
+    }
+    async fn notify(&self, notification: &Notification) -> anyhow::Result<()> {
+        let mut child = Command::new(&self.program)
+            .args(&self.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()?;
+        let mut input = serde_json::to_vec(&notification.event)?;
+        input.push(b'\n');
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        let run = async {
+            // a command that doesn't read its input is fine
+            let _ = stdin.write_all(&input).await;
+            drop(stdin);
+            child.wait().await
+        };
+        let status = tokio::time::timeout(COMMAND_TIMEOUT, run)
+            .await
+            .map_err(|_| anyhow::anyhow!("command timed out"))??;
+        if !status.success() {
+            anyhow::bail!("command exited with {status}");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notify::tests::notification;
+
+    #[tokio::test]
+    async fn event_on_stdin() {
+        let path = std::env::temp_dir().join(format!("ccheck-exec-{}.json", std::process::id()));
+        let exec = Exec::new(&format!("tee {}", path.display())).unwrap();
+        exec.notify(&notification("Hello", &[])).await.unwrap();
+        let written = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let event: serde_json::Value = serde_json::from_str(&written).unwrap();
+        assert_eq!(event["event"], "matched");
+        assert_eq!(event["motd"], "Hello");
+    }
+
+    #[tokio::test]
+    async fn exit_status() {
+        let notification = notification("Hello", &[]);
+        Exec::new("cat")
+            .unwrap()
+            .notify(&notification)
+            .await
+            .unwrap();
+        let err = Exec::new("false")
+            .unwrap()
+            .notify(&notification)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().starts_with("command exited with"));
+        assert!(Exec::new("ccheck-no-such-command")
+            .unwrap()
+            .notify(&notification)
+            .await
+            .is_err());
+        assert!(Exec::new("  ").is_err());
+    }
+}
@@ -0,0 +1,641 @@
+// CCheck: utility for scanning and probing minecraft servers
+// Copyright (C) 2022 cleonyc
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//! Notifiers that make an HTTP request per event
+use std::time::{Duration, SystemTime};
+
+use async_trait::async_trait;
+use hyper::{client::HttpConnector, header, Body, Client, Method, Request, StatusCode};
+use hyper_tls::HttpsConnector;
+use serde::Serialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use url::Url;
+
+use super::{template::Message, DeliveryError, Notification, Notifier};
+use crate::mode::events::{Event, EventKind, EventType};
+
+/// How long a single request may take
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone)]
+struct HttpClient(Client<HttpsConnector<HttpConnector>>);
+impl HttpClient {
+    fn new() -> Self {
+        Self(Client::builder().build(HttpsConnector::new()))
+    }
+    async fn send(
+        &self,
+        method: Method,
+        url: &Url,
+        headers: &[(&str, String)],
+        content_type: &str,
+        body: Vec<u8>,
+    ) -> anyhow::Result<()> {
+        let mut request = Request::builder()
+            .method(method)
+            .uri(url.as_str())
+            .header(header::CONTENT_TYPE, content_type);
+        for (name, value) in headers {
+            request = request.header(*name, value);
+        }
+        let request = request.body(Body::from(body))?;
+        let response = tokio::time::timeout(REQUEST_TIMEOUT, self.0.request(request))
+            .await
+            .map_err(|_| anyhow::anyhow!("request timed out"))??;
+        let status = response.status();
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            let retry_after = response
+                .headers()
+                .get(header::RETRY_AFTER)
+                .and_then(|v| v.to_str().ok())
+                .and_then(retry_after);
+            return match retry_after {
+                Some(after) => Err(DeliveryError::RetryAfter(after).into()),
+                None => Err(anyhow::anyhow!("server answered {status}")),
+            };
+        }
+        if status.is_client_error() && status != StatusCode::REQUEST_TIMEOUT {
+            return Err(DeliveryError::Rejected(format!("server answered {status}")).into());
+        }
+        if !status.is_success() {
+            anyhow::bail!("server answered {status}");
+        }
+        Ok(())
+    }
+    async fn send_json<T: Serialize>(
+        &self,
+        method: Method,
+        url: &Url,
+        headers: &[(&str, String)],
+        body: &T,
+    ) -> anyhow::Result<()> {
+        let body = serde_json::to_vec(body)?;
+        self.send(method, url, headers, "application/json", body)
+            .await
+    }
+}
+
+/// Parses a `Retry-After` header, either seconds or an HTTP date
+fn retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse::<f64>() {
+        return Duration::try_from_secs_f64(secs).ok();
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    // a date in the past means right away
+    Some(date.duration_since(SystemTime::now()).unwrap_or_default())
+}
+
+/// `url` without credentials or query, which may hold tokens
+fn redact(url: &Url) -> String {
+    format!(
+        "{}://{}{}",
+        url.scheme(),
+        url.host_str().unwrap_or_default(),
+        url.port().map(|p| format!(":{p}")).unwrap_or_default()
+    )
+}
+
+/// POSTs the event as JSON, with a human readable `summary` and the rendered templates next to its fields
+#[derive(Debug)]
+pub struct Json {
+    client: HttpClient,
+    url: Url,
+}
+impl Json {
+    pub fn new(url: Url) -> Self {
+        Self {
+            client: HttpClient::new(),
+            url,
+        }
+    }
+}
+#[async_trait]
+impl Notifier for Json {
+    fn name(&self) -> String {
+        format!("json:{}", redact(&self.url))
+    }
+    async fn notify(&self, notification: &Notification) -> anyhow::Result<()> {
+        #[derive(Serialize)]
+        struct Payload<'a> {
+            summary: String,
+            #[serde(flatten)]
+            event: &'a Event,
+            #[serde(flatten)]
+            message: &'a Message,
+        }
+        let payload = Payload {
+            summary: notification.event.to_string(),
+            event: &notification.event,
+            message: &notification.message,
+        };
+        self.client
+            .send_json(Method::POST, &self.url, &[], &payload)
+            .await
+    }
+}
+
+/// Discord webhook, as an embed with the server's favicon as thumbnail
+#[derive(Debug)]
+pub struct Discord {
+    client: HttpClient,
+    url: Url,
+}
+impl Discord {
+    pub fn new(url: Url) -> Self {
+        Self {
+            client: HttpClient::new(),
+            url,
+        }
+    }
+}
+#[async_trait]
+impl Notifier for Discord {
+    fn name(&self) -> String {
+        format!("discord:{}", redact(&self.url))
+    }
+    async fn notify(&self, notification: &Notification) -> anyhow::Result<()> {
+        let event = &notification.event;
+        let message = &notification.message;
+        let server = format!("`{}:{}`", event.server.0, event.server.1);
+        let mut embed = match &event.kind {
+            _ if !message.is_empty() => json!({
+                "title": message.title(notification),
+                "description": message.body.as_ref().unwrap_or(&server),
+                "fields": message
+                    .fields
+                    .iter()
+                    .map(|f| embed_field(&f.name, &f.value, false))
+                    .collect::<Vec<_>>(),
+            }),
+            EventKind::Matched {
+                version,
+                players,
+                motd,
+                ..
+            } => json!({
+                "title": "Server matching conditions found!",
+                "description": server,
+                "fields": [
+                    embed_field("Version", version, true),
+                    embed_field("Players", &players.join(", "), false),
+                    embed_field("Motd", motd, false),
+                ],
+            }),
+            _ => json!({"title": event.to_string(), "description": server}),
+        };
+        let favicon = notification
+            .response
+            .as_ref()
+            .and_then(|resp| base64::decode(&resp.favicon).ok())
+            .filter(|favicon| !favicon.is_empty());
+        let Some(favicon) = favicon else {
+            return self
+                .client
+                .send_json(Method::POST, &self.url, &[], &json!({ "embeds": [embed] }))
+                .await;
+        };
+        // attachments have to be uploaded along with the message as multipart/form-data
+        embed["thumbnail"] = json!({"url": "attachment://favicon.png"});
+        let payload = json!({
+            "embeds": [embed],
+            "attachments": [{"id": 0, "filename": "favicon.png"}],
+        });
+        // the favicon's own hash can't appear inside it
+        let boundary = format!("ccheck-{:x}", Sha256::digest(&favicon));
+        let mut body = format!(
+            "--{boundary}\r\nContent-Disposition: form-data; name=\"payload_json\"\r\n\
+             Content-Type: application/json\r\n\r\n{payload}\r\n\
+             --{boundary}\r\nContent-Disposition: form-data; name=\"files[0]\"; filename=\"favicon.png\"\r\n\
+             Content-Type: image/png\r\n\r\n"
+        )
+        .into_bytes();
+        body.extend(favicon);
+        body.extend(format!("\r\n--{boundary}--\r\n").into_bytes());
+        self.client
+            .send(
+                Method::POST,
+                &self.url,
+                &[],
+                &format!("multipart/form-data; boundary={boundary}"),
+                body,
+            )
+            .await
+    }
+}
+
+/// Discord rejects the whole message over a single empty or overlong field
+fn embed_field(name: &str, value: &str, inline: bool) -> serde_json::Value {
+    json!({"name": embed_text(name, 256), "value": embed_text(value, 1024), "inline": inline})
+}
+/// `text` cut down to `max` characters, `-` if it's blank
+fn embed_text(text: &str, max: usize) -> String {
+    if text.trim().is_empty() {
+        return "-".to_string();
+    }
+    match text.char_indices().nth(max) {
+        Some(_) => text.chars().take(max - 1).chain(['…']).collect(),
+        None => text.to_string(),
+    }
+}
+
+/// Slack incoming webhook
+#[derive(Debug)]
+pub struct Slack {
+    client: HttpClient,
+    url: Url,
+}
+impl Slack {
+    pub fn new(url: Url) -> Self {
+        Self {
+            client: HttpClient::new(),
+            url,
+        }
+    }
+}
+#[async_trait]
+impl Notifier for Slack {
+    fn name(&self) -> String {
+        format!("slack:{}", redact(&self.url))
+    }
+    async fn notify(&self, notification: &Notification) -> anyhow::Result<()> {
+        let text = notification.message.full_text(notification);
+        self.client
+            .send_json(Method::POST, &self.url, &[], &json!({ "text": text }))
+            .await
+    }
+}
+
+/// ntfy topic, e.g. `https://ntfy.sh/my-topic`
+#[derive(Debug)]
+pub struct Ntfy {
+    client: HttpClient,
+    url: Url,
+}
+impl Ntfy {
+    pub fn new(url: Url) -> Self {
+        Self {
+            client: HttpClient::new(),
+            url,
+        }
+    }
+}
+#[async_trait]
+impl Notifier for Ntfy {
+    fn name(&self) -> String {
+        format!("ntfy:{}", redact(&self.url))
+    }
+    async fn notify(&self, notification: &Notification) -> anyhow::Result<()> {
+        let event_type = EventType::from(&notification.event.kind);
+        let title = match &notification.message.title {
+            Some(title) => title.clone(),
+            None => format!("ccheck: {event_type}"),
+        };
+        let headers = [("Title", title), ("Tags", event_type.to_string())];
+        self.client
+            .send(
+                Method::POST,
+                &self.url,
+                &headers,
+                "text/plain; charset=utf-8",
+                notification.message.text(notification).into_bytes(),
+            )
+            .await
+    }
+}
+
+/// Matrix room, given as `https://homeserver/<room id>?access_token=<token>`
+#[derive(Debug)]
+pub struct Matrix {
+    client: HttpClient,
+    homeserver: Url,
+    room: String,
+    token: String,
+}
+impl Matrix {
+    pub fn new(url: Url) -> anyhow::Result<Self> {
+        let token = url
+            .query_pairs()
+            .find(|(k, _)| k == "access_token")
+            .map(|(_, v)| v.to_string())
+            .ok_or_else(|| anyhow::anyhow!("matrix notifier needs an `access_token`"))?;
+        let room = url.path().trim_start_matches('/').to_string();
+        if !room.starts_with('!') {
+            anyhow::bail!("matrix notifier needs a room id (`!...:server`), got `{room}`");
+        }
+        let mut homeserver = url.clone();
+        homeserver.set_path("");
+        homeserver.set_query(None);
+        Ok(Self {
+            client: HttpClient::new(),
+            homeserver,
+            room,
+            token,
+        })
+    }
+}
+#[async_trait]
+impl Notifier for Matrix {
+    fn name(&self) -> String {
+        format!("matrix:{}/{}", redact(&self.homeserver), self.room)
+    }
+    async fn notify(&self, notification: &Notification) -> anyhow::Result<()> {
+        // the same for every retry of this event, so the homeserver drops duplicates
+        let txn = format!(
+            "ccheck-{:x}",
+            Sha256::digest(serde_json::to_vec(&notification.event)?)
+        );
+        let mut url = self.homeserver.clone();
+        url.path_segments_mut()
+            .map_err(|_| anyhow::anyhow!("invalid homeserver URL"))?
+            .extend([
+                "_matrix",
+                "client",
+                "v3",
+                "rooms",
+                &self.room,
+                "send",
+                "m.room.message",
+                &txn,
+            ]);
+        self.client
+            .send_json(
+                Method::PUT,
+                &url,
+                &[("Authorization", format!("Bearer {}", self.token))],
+                &json!({ "msgtype": "m.text", "body": notification.message.full_text(notification) }),
+            )
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        task::JoinHandle,
+    };
+
+    use super::*;
+    use crate::notify::tests::notification;
+
+    /// 1x1 PNG
+    const FAVICON: &str = "iVBORw0KGgoAAAANSUhEUgAAAAEAAAABCAYAAAAfFcSJAAAADUlEQVR42mP8z8BQDwAEhQGAhKmMIQAAAABJRU5ErkJggg==";
+
+    struct Received {
+        method: String,
+        path: String,
+        headers: Vec<(String, String)>,
+        body: Vec<u8>,
+    }
+    impl Received {
+        fn header(&self, name: &str) -> Option<&str> {
+            self.headers
+                .iter()
+                .find(|(n, _)| n.eq_ignore_ascii_case(name))
+                .map(|(_, v)| v.as_str())
+        }
+        fn json(&self) -> serde_json::Value {
+            serde_json::from_slice(&self.body).unwrap()
+        }
+    }
+
+    /// Answers `count` requests with `response` (status line and headers), returning what was sent
+    async fn stand_in(response: &'static str, count: usize) -> (Url, JoinHandle<Vec<Received>>) {
+        let listener = TcpListener::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)))
+            .await
+            .unwrap();
+        let url = Url::parse(&format!("http://{}", listener.local_addr().unwrap())).unwrap();
+        let handle = tokio::spawn(async move {
+            let mut received = vec![];
+            while received.len() < count {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut head = vec![];
+                while !head.ends_with(b"\r\n\r\n") {
+                    head.push(stream.read_u8().await.unwrap());
+                }
+                let head = String::from_utf8(head).unwrap();
+                let mut lines = head.lines();
+                let mut request_line = lines.next().unwrap().split(' ');
+                let (method, path) = (request_line.next().unwrap(), request_line.next().unwrap());
+                let headers = lines
+                    .filter_map(|l| l.split_once(": "))
+                    .map(|(n, v)| (n.to_string(), v.to_string()))
+                    .collect::<Vec<_>>();
+                let length = headers
+                    .iter()
+                    .find(|(n, _)| n.eq_ignore_ascii_case("content-length"))
+                    .map_or(0, |(_, v)| v.parse().unwrap());
+                let mut body = vec![0; length];
+                stream.read_exact(&mut body).await.unwrap();
+                stream
+                    .write_all(
+                        format!("{response}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+                            .as_bytes(),
+                    )
+                    .await
+                    .unwrap();
+                received.push(Received {
+                    method: method.to_string(),
+                    path: path.to_string(),
+                    headers,
+                    body,
+                });
+            }
+            received
+        });
+        (url, handle)
+    }
+
+    async fn send_one(
+        notifier: impl FnOnce(Url) -> Box<dyn Notifier>,
+        path: &str,
+    ) -> (anyhow::Result<()>, Received) {
+        let (url, handle) = stand_in("HTTP/1.1 200 OK", 1).await;
+        let notifier = notifier(url.join(path).unwrap());
+        let result = notifier.notify(&notification("Hello", &[])).await;
+        (result, handle.await.unwrap().remove(0))
+    }
+
+    #[tokio::test]
+    async fn json() {
+        let (result, received) = send_one(|url| Box::new(Json::new(url)), "/hook").await;
+        result.unwrap();
+        assert_eq!(received.method, "POST");
+        assert_eq!(received.path, "/hook");
+        assert_eq!(received.header("content-type"), Some("application/json"));
+        let body = received.json();
+        assert_eq!(body["event"], "matched");
+        assert_eq!(body["server"], json!(["192.0.2.1", 25565]));
+        assert_eq!(body["summary"], "Found matching server 192.0.2.1:25565");
+    }
+
+    #[tokio::test]
+    async fn slack() {
+        let (result, received) = send_one(|url| Box::new(Slack::new(url)), "/services/T/B/X").await;
+        result.unwrap();
+        assert_eq!(received.method, "POST");
+        assert_eq!(received.path, "/services/T/B/X");
+        assert_eq!(
+            received.json(),
+            json!({"text": "Found matching server 192.0.2.1:25565"})
+        );
+    }
+
+    #[tokio::test]
+    async fn ntfy() {
+        let (result, received) = send_one(|url| Box::new(Ntfy::new(url)), "/my-topic").await;
+        result.unwrap();
+        assert_eq!(received.method, "POST");
+        assert_eq!(received.path, "/my-topic");
+        assert_eq!(received.header("title"), Some("ccheck: matched"));
+        assert_eq!(received.header("tags"), Some("matched"));
+        assert_eq!(
+            received.header("content-type"),
+            Some("text/plain; charset=utf-8")
+        );
+        assert_eq!(received.body, b"Found matching server 192.0.2.1:25565");
+    }
+
+    #[tokio::test]
+    async fn matrix() {
+        let (url, handle) = stand_in("HTTP/1.1 200 OK", 3).await;
+        let matrix =
+            Matrix::new(url.join("/!room:example.org?access_token=secret").unwrap()).unwrap();
+        let first = notification("Hello", &[]);
+        let mut second = first.clone();
+        second.event.timestamp += 1;
+        for notification in [&first, &first, &second] {
+            matrix.notify(notification).await.unwrap();
+        }
+        let received = handle.await.unwrap();
+        assert!(received.iter().all(|r| r.method == "PUT"));
+        assert!(received[0]
+            .path
+            .starts_with("/_matrix/client/v3/rooms/!room:example.org/send/m.room.message/ccheck-"));
+        assert_eq!(received[0].header("authorization"), Some("Bearer secret"));
+        assert!(!received[0].path.contains("secret"));
+        assert_eq!(
+            received[0].json(),
+            json!({"msgtype": "m.text", "body": "Found matching server 192.0.2.1:25565"})
+        );
+        // a retried event keeps its transaction id, a different one gets its own
+        assert_eq!(received[0].path, received[1].path);
+        assert_ne!(received[0].path, received[2].path);
+    }
+
+    #[tokio::test]
+    async fn discord_embed() {
+        let (url, handle) = stand_in("HTTP/1.1 204 No Content", 1).await;
+        let discord = Discord::new(url.join("/api/webhooks/1/token").unwrap());
+        // no players and a huge motd, neither of which Discord accepts as is
+        let motd = "x".repeat(2000);
+        discord.notify(&notification(&motd, &[])).await.unwrap();
+        let received = handle.await.unwrap().remove(0);
+        assert_eq!(received.method, "POST");
+        assert_eq!(received.path, "/api/webhooks/1/token");
+        assert_eq!(received.header("content-type"), Some("application/json"));
+        let embed = &received.json()["embeds"][0];
+        assert_eq!(embed["title"], "Server matching conditions found!");
+        assert_eq!(embed["description"], "`192.0.2.1:25565`");
+        let fields = embed["fields"].as_array().unwrap();
+        assert_eq!(fields[0]["value"], "1.20.1");
+        assert_eq!(fields[1]["value"], "-");
+        let motd = fields[2]["value"].as_str().unwrap();
+        assert_eq!(motd.chars().count(), 1024);
+        assert!(motd.ends_with('…'));
+    }
+
+    #[tokio::test]
+    async fn discord_favicon() {
+        let (url, handle) = stand_in("HTTP/1.1 200 OK", 1).await;
+        let discord = Discord::new(url.join("/api/webhooks/1/token").unwrap());
+        let favicon = base64::decode(FAVICON).unwrap();
+        discord
+            .notify(&notification("Hello", &favicon))
+            .await
+            .unwrap();
+        let received = handle.await.unwrap().remove(0);
+        let boundary = received
+            .header("content-type")
+            .and_then(|v| v.strip_prefix("multipart/form-data; boundary="))
+            .unwrap()
+            .to_string();
+        let body = &received.body;
+        let parts = body
+            .windows(boundary.len() + 2)
+            .filter(|w| *w == format!("--{boundary}").as_bytes())
+            .count();
+        // two parts and the closing delimiter
+        assert_eq!(parts, 3);
+        let text = String::from_utf8_lossy(body);
+        assert!(text.contains("name=\"payload_json\""));
+        assert!(text.contains("\"url\":\"attachment://favicon.png\""));
+        assert!(text.contains("name=\"files[0]\"; filename=\"favicon.png\""));
+        assert!(body.windows(favicon.len()).any(|w| w == favicon.as_slice()));
+        assert!(text.ends_with(&format!("--{boundary}--\r\n")));
+    }
+
+    #[test]
+    fn retry_after_header() {
+        assert_eq!(retry_after("7"), Some(Duration::from_secs(7)));
+        assert_eq!(retry_after(" 1.5 "), Some(Duration::from_millis(1500)));
+        assert_eq!(retry_after("1e300"), None);
+        assert_eq!(retry_after("-1"), None);
+        assert_eq!(retry_after("soon"), None);
+        assert_eq!(
+            retry_after("Wed, 21 Oct 2015 07:28:00 GMT"),
+            Some(Duration::ZERO)
+        );
+        let later = httpdate::fmt_http_date(SystemTime::now() + Duration::from_secs(120));
+        let after = retry_after(&later).unwrap();
+        assert!(after > Duration::from_secs(110) && after <= Duration::from_secs(120));
+    }
+
+    #[tokio::test]
+    async fn status_codes() {
+        let (url, _) = stand_in("HTTP/1.1 429 Too Many Requests\r\nRetry-After: 7", 1).await;
+        let err = Json::new(url)
+            .notify(&notification("Hello", &[]))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<DeliveryError>(),
+            Some(DeliveryError::RetryAfter(after)) if *after == Duration::from_secs(7)
+        ));
+
+        let (url, _) = stand_in("HTTP/1.1 404 Not Found", 1).await;
+        let err = Json::new(url)
+            .notify(&notification("Hello", &[]))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<DeliveryError>(),
+            Some(DeliveryError::Rejected(_))
+        ));
+
+        // worth retrying as usual
+        let (url, _) = stand_in("HTTP/1.1 503 Service Unavailable", 1).await;
+        let err = Json::new(url)
+            .notify(&notification("Hello", &[]))
+            .await
+            .unwrap_err();
+        assert!(err.downcast_ref::<DeliveryError>().is_none());
+    }
+}
@@ -0,0 +1,140 @@
+// CCheck: utility for scanning and probing minecraft servers
+// Copyright (C) 2022 cleonyc
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//! Where monitor events are sent
+use std::{
+    fmt::{Debug, Display},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::{format::ccheck::Server, mode::events::Event};
+
+pub mod exec;
+pub mod http;
+pub mod queue;
+pub mod smtp;
+pub mod template;
+
+use template::Message;
+
+/// An event with what the server looked like around it
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Notification {
+    pub event: Event,
+    /// The response that triggered the event, `None` if the server didn't answer
+    pub response: Option<Server>,
+    /// The response from the cycle before
+    pub previous: Option<Server>,
+    /// Rendered from the alert templates, empty if there are none
+    pub message: Message,
+}
+
+/// Failures that change how a delivery is retried, returned inside the error of [`Notifier::notify`].
+/// Any other error is retried with the usual backoff
+#[derive(Debug)]
+pub enum DeliveryError {
+    /// The receiver asked to wait this long before trying again, e.g. HTTP 429 with `Retry-After`
+    RetryAfter(Duration),
+    /// Trying again won't help, e.g. HTTP 404 or a rejected email address
+    Rejected(String),
+}
+impl Display for DeliveryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeliveryError::RetryAfter(after) => {
+                write!(f, "rate limited, retry after {}s", after.as_secs())
+            }
+            DeliveryError::Rejected(reason) => write!(f, "{reason}"),
+        }
+    }
+}
+impl std::error::Error for DeliveryError {}
+
+/// Something that can be told about events
+#[async_trait]
+pub trait Notifier: Debug + Send + Sync {
+    /// Short description for messages, without any credentials
+    fn name(&self) -> String;
+    async fn notify(&self, notification: &Notification) -> anyhow::Result<()>;
+}
+
+/// Creates a notifier from its `--notify` spec, e.g. `slack:https://hooks.slack.com/...`
+pub fn from_spec(spec: &str) -> anyhow::Result<Box<dyn Notifier>> {
+    let (kind, target) = spec
+        .split_once(':')
+        .ok_or_else(|| anyhow::anyhow!("invalid notifier `{spec}`, expected <kind>:<target>"))?;
+    let url = || Url::parse(target).map_err(|e| anyhow::anyhow!("invalid URL `{target}`: {e}"));
+    Ok(match kind {
+        "json" => Box::new(http::Json::new(url()?)),
+        "discord" => Box::new(http::Discord::new(url()?)),
+        "slack" => Box::new(http::Slack::new(url()?)),
+        "ntfy" => Box::new(http::Ntfy::new(url()?)),
+        "matrix" => Box::new(http::Matrix::new(url()?)?),
+        "smtp" | "smtps" => Box::new(smtp::Smtp::new(spec)?),
+        "exec" => Box::new(exec::Exec::new(target)?),
+        _ => anyhow::bail!(
+            "unknown notifier `{kind}`, supported: json, discord, slack, ntfy, matrix, smtp, smtps, exec"
+        ),
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use super::*;
+    use crate::{
+        adapters::{CCheckComponent, CCheckResponse},
+        mode::events::EventKind,
+    };
+
+    /// A `matched` notification for 192.0.2.1:25565, whose response has `favicon` as its favicon
+    pub fn notification(motd: &str, favicon: &[u8]) -> Notification {
+        let server = (IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)), 25565);
+        let response = CCheckResponse {
+            version: "1.20.1".to_string(),
+            protocol: 763,
+            max_players: 20,
+            online_players: 0,
+            sample: None,
+            description: CCheckComponent {
+                text: motd.to_string(),
+                ..Default::default()
+            },
+            favicon: Some(favicon.to_vec()),
+            timing: None,
+            proxy_protocol: false,
+        };
+        Notification {
+            event: Event {
+                timestamp: 1700000000,
+                server,
+                kind: EventKind::Matched {
+                    condition: String::new(),
+                    version: response.version.clone(),
+                    players: vec![],
+                    motd: motd.to_string(),
+                },
+            },
+            response: Some(Server::from_resp(response, server)),
+            previous: None,
+            message: Message::default(),
+        }
+    }
+}
@@ -0,0 +1,419 @@
+// CCheck: utility for scanning and probing minecraft servers
+// Copyright (C) 2022 cleonyc
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//! Delivers notifications in the background, retrying failures
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use owo_colors::OwoColorize;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use time::OffsetDateTime;
+use tokio::{sync::mpsc, task::JoinHandle};
+
+use super::{from_spec, DeliveryError, Notification, Notifier};
+use crate::retry::RetryPolicy;
+
+/// How notifications that fail to send are retried and where they are kept meanwhile
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeliveryPolicy {
+    /// Attempts per notification and notifier before it goes to the dead letter log
+    pub max_attempts: u32,
+    /// Milliseconds before the first retry, doubled for every retry after it
+    pub backoff: u64,
+    pub max_backoff: u64,
+    /// Directory undelivered notifications are kept in, so they survive a restart
+    pub spool: Option<PathBuf>,
+    /// File notifications that were given up on are appended to
+    pub dead_letter: Option<PathBuf>,
+}
+impl Default for DeliveryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 10,
+            backoff: 1000,
+            max_backoff: 300_000,
+            spool: None,
+            dead_letter: None,
+        }
+    }
+}
+
+/// A notification on its way to one notifier
+#[derive(Serialize, Deserialize)]
+struct Delivery {
+    /// Which notifier this is for, see [`notifier_key`]
+    notifier: String,
+    /// Failed attempts so far
+    attempts: u32,
+    /// Unix timestamp of when the notification was queued
+    queued_at: usize,
+    notification: Notification,
+    /// Where the delivery is spooled
+    #[serde(skip)]
+    file: Option<PathBuf>,
+}
+
+/// Identifies a notifier across restarts without writing its credentials to disk
+fn notifier_key(spec: &str) -> String {
+    format!("{:x}", Sha256::digest(spec))[..16].to_string()
+}
+
+/// Sends notifications from a queue per notifier, so a slow or rate limited notifier doesn't
+/// hold up the others. Notifications to the same notifier are sent in order
+pub struct DeliveryQueue {
+    senders: Mutex<Vec<(String, mpsc::UnboundedSender<Delivery>)>>,
+    workers: Mutex<Vec<JoinHandle<()>>>,
+    /// Deliveries that are neither sent nor given up on
+    pending: Arc<AtomicUsize>,
+    spool: Option<Arc<Spool>>,
+}
+impl std::fmt::Debug for DeliveryQueue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DeliveryQueue")
+            .field("pending", &self.pending)
+            .field("spool", &self.spool.as_ref().map(|s| &s.dir))
+            .finish()
+    }
+}
+impl DeliveryQueue {
+    /// Creates the notifiers from their `--notify` specs and starts sending anything left in the spool
+    pub fn start(specs: &[String], policy: &DeliveryPolicy) -> anyhow::Result<Self> {
+        let retry = RetryPolicy {
+            max_attempts: policy.max_attempts.max(1),
+            // only used for probes
+            retry_on: vec![],
+            backoff: Duration::from_millis(policy.backoff),
+            max_backoff: Duration::from_millis(policy.max_backoff),
+        };
+        let spool = policy
+            .spool
+            .as_deref()
+            .map(Spool::open)
+            .transpose()?
+            .map(Arc::new);
+        let dead_letters = Arc::new(
+            policy
+                .dead_letter
+                .as_deref()
+                .map(DeadLetters::open)
+                .transpose()?,
+        );
+        let mut spooled: HashMap<String, Vec<Delivery>> = HashMap::new();
+        if let Some(spool) = &spool {
+            for delivery in spool.load()? {
+                spooled
+                    .entry(delivery.notifier.clone())
+                    .or_default()
+                    .push(delivery);
+            }
+        }
+        let pending = Arc::new(AtomicUsize::new(0));
+        let mut senders = vec![];
+        let mut workers = vec![];
+        for spec in specs {
+            let key = notifier_key(spec);
+            let notifier = from_spec(spec)?;
+            let (sender, receiver) = mpsc::unbounded_channel();
+            for delivery in spooled.remove(&key).unwrap_or_default() {
+                pending.fetch_add(1, Ordering::Relaxed);
+                sender.send(delivery).expect("receiver is alive");
+            }
+            let worker = Worker {
+                notifier,
+                retry: retry.clone(),
+                spool: spool.clone(),
+                dead_letters: dead_letters.clone(),
+                pending: pending.clone(),
+            };
+            workers.push(tokio::spawn(worker.run(receiver)));
+            senders.push((key, sender));
+        }
+        // notifiers that were removed since these were spooled
+        for delivery in spooled.into_values().flatten() {
+            let err = anyhow::anyhow!("notifier is no longer configured");
+            give_up(&delivery, "unknown notifier", &err, &spool, &dead_letters);
+        }
+        Ok(Self {
+            senders: Mutex::new(senders),
+            workers: Mutex::new(workers),
+            pending,
+            spool,
+        })
+    }
+    /// Queues `notification` for every notifier, spooling it first if there is a spool.
+    /// A notification that can't be spooled is still sent, it just won't survive a restart
+    pub fn push(&self, notification: &Notification) {
+        let queued_at = OffsetDateTime::now_utc().unix_timestamp() as usize;
+        for (key, sender) in self.senders.lock().unwrap().iter() {
+            let mut delivery = Delivery {
+                notifier: key.clone(),
+                attempts: 0,
+                queued_at,
+                notification: notification.clone(),
+                file: None,
+            };
+            if let Some(spool) = &self.spool {
+                if let Err(err) = spool.save(&mut delivery) {
+                    eprintln!(
+                        "{} Failed to spool notification, keeping it in memory only: {err}",
+                        "::".yellow().bold()
+                    );
+                    delivery.file = None;
+                }
+            }
+            self.pending.fetch_add(1, Ordering::Relaxed);
+            if sender.send(delivery).is_err() {
+                self.pending.fetch_sub(1, Ordering::Relaxed);
+            }
+        }
+    }
+    /// Stops taking notifications and waits up to `grace` for the queued ones to be sent.
+    /// Returns how many weren't, which stay in the spool if there is one
+    pub async fn close(&self, grace: Duration) -> usize {
+        self.senders.lock().unwrap().clear();
+        let workers = std::mem::take(&mut *self.workers.lock().unwrap());
+        let aborts = workers.iter().map(|w| w.abort_handle()).collect::<Vec<_>>();
+        let all_done = async {
+            for worker in workers {
+                let _ = worker.await;
+            }
+        };
+        if tokio::time::timeout(grace, all_done).await.is_err() {
+            for abort in aborts {
+                abort.abort();
+            }
+        }
+        self.pending.load(Ordering::Relaxed)
+    }
+    /// Whether there are no notifiers to send to
+    pub fn is_empty(&self) -> bool {
+        self.senders.lock().unwrap().is_empty()
+    }
+    pub fn is_spooled(&self) -> bool {
+        self.spool.is_some()
+    }
+}
+
+/// Sends everything queued for one notifier
+struct Worker {
+    notifier: Box<dyn Notifier>,
+    retry: RetryPolicy,
+    spool: Option<Arc<Spool>>,
+    dead_letters: Arc<Option<DeadLetters>>,
+    pending: Arc<AtomicUsize>,
+}
+impl Worker {
+    async fn run(self, mut receiver: mpsc::UnboundedReceiver<Delivery>) {
+        while let Some(mut delivery) = receiver.recv().await {
+            self.deliver(&mut delivery).await;
+            self.pending.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+    /// Tries until the notification is sent or given up on
+    async fn deliver(&self, delivery: &mut Delivery) {
+        let name = self.notifier.name();
+        loop {
+            let err = match self.notifier.notify(&delivery.notification).await {
+                Ok(()) => {
+                    if let Some(spool) = &self.spool {
+                        spool.remove(delivery);
+                    }
+                    return;
+                }
+                Err(err) => err,
+            };
+            delivery.attempts += 1;
+            let delay = match err.downcast_ref::<DeliveryError>() {
+                Some(DeliveryError::Rejected(_)) => None,
+                _ if delivery.attempts >= self.retry.max_attempts => None,
+                // a receiver asking for hours doesn't get to stall everything queued behind it
+                Some(DeliveryError::RetryAfter(after)) => {
+                    Some((*after).min(self.retry.max_backoff))
+                }
+                None => Some(self.retry.delay(delivery.attempts)),
+            };
+            let Some(delay) = delay else {
+                give_up(delivery, &name, &err, &self.spool, &self.dead_letters);
+                return;
+            };
+            eprintln!(
+                "{} Failed to notify {name} (attempt {}/{}), retrying in {:.1}s: {err}",
+                "::".yellow().bold(),
+                delivery.attempts,
+                self.retry.max_attempts,
+                delay.as_secs_f64()
+            );
+            if let Some(spool) = &self.spool {
+                // keep the attempt count across restarts
+                if let Err(err) = spool.save(delivery) {
+                    eprintln!("{} Failed to update spool: {err}", "::".yellow().bold());
+                }
+            }
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+/// Moves `delivery` from the spool to the dead letter log
+fn give_up(
+    delivery: &Delivery,
+    name: &str,
+    err: &anyhow::Error,
+    spool: &Option<Arc<Spool>>,
+    dead_letters: &Option<DeadLetters>,
+) {
+    eprintln!(
+        "{} Gave up notifying {name} after {} attempts: {err}",
+        "::".red().bold(),
+        delivery.attempts
+    );
+    if let Some(dead_letters) = dead_letters {
+        if let Err(err) = dead_letters.write(delivery, name, err) {
+            eprintln!(
+                "{} Failed to write to the dead letter log: {err}",
+                "::".red().bold()
+            );
+            // leave it in the spool rather than losing it
+            return;
+        }
+    }
+    if let Some(spool) = spool {
+        spool.remove(delivery);
+    }
+}
+
+/// Undelivered notifications on disk, one JSON file each, in the order they were queued
+struct Spool {
+    dir: PathBuf,
+    /// Keeps file names unique and ordered within a run
+    seq: AtomicU64,
+}
+impl Spool {
+    fn open(dir: &Path) -> anyhow::Result<Self> {
+        fs::create_dir_all(dir)
+            .map_err(|e| anyhow::anyhow!("failed to create spool {}: {e}", dir.display()))?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            seq: AtomicU64::new(0),
+        })
+    }
+    /// Everything left from previous runs, oldest first
+    fn load(&self) -> anyhow::Result<Vec<Delivery>> {
+        let mut files = fs::read_dir(&self.dir)?
+            .filter_map(|entry| Some(entry.ok()?.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+            .collect::<Vec<_>>();
+        files.sort();
+        let mut deliveries = vec![];
+        for file in files {
+            let delivery = fs::read(&file)
+                .map_err(anyhow::Error::from)
+                .and_then(|bytes| Ok(serde_json::from_slice::<Delivery>(&bytes)?));
+            match delivery {
+                Ok(mut delivery) => {
+                    delivery.file = Some(file);
+                    deliveries.push(delivery);
+                }
+                Err(err) => {
+                    eprintln!(
+                        "{} Skipping unreadable spool file {}: {err}",
+                        "::".yellow().bold(),
+                        file.display()
+                    );
+                    let _ = fs::rename(&file, file.with_extension("bad"));
+                }
+            }
+        }
+        Ok(deliveries)
+    }
+    /// Writes `delivery` to its file, replacing it atomically
+    fn save(&self, delivery: &mut Delivery) -> anyhow::Result<()> {
+        let file = match &delivery.file {
+            Some(file) => file.clone(),
+            None => {
+                let now = OffsetDateTime::now_utc().unix_timestamp_nanos();
+                let seq = self.seq.fetch_add(1, Ordering::Relaxed);
+                let file = self
+                    .dir
+                    .join(format!("{now:020}-{seq:06}-{}.json", delivery.notifier));
+                delivery.file = Some(file.clone());
+                file
+            }
+        };
+        let tmp = file.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec(delivery)?)?;
+        fs::rename(&tmp, &file)?;
+        Ok(())
+    }
+    fn remove(&self, delivery: &Delivery) {
+        if let Some(file) = &delivery.file {
+            if let Err(err) = fs::remove_file(file) {
+                eprintln!(
+                    "{} Failed to remove {} from the spool: {err}",
+                    "::".yellow().bold(),
+                    file.display()
+                );
+            }
+        }
+    }
+}
+
+/// Notifications that were given up on, one JSON object per line
+struct DeadLetters {
+    writer: Mutex<BufWriter<File>>,
+}
+impl DeadLetters {
+    fn open(path: &Path) -> anyhow::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            writer: Mutex::new(BufWriter::new(file)),
+        })
+    }
+    fn write(&self, delivery: &Delivery, name: &str, err: &anyhow::Error) -> anyhow::Result<()> {
+        #[derive(Serialize)]
+        struct DeadLetter<'a> {
+            /// Unix timestamp of when it was given up on
+            timestamp: usize,
+            notifier: &'a str,
+            attempts: u32,
+            queued_at: usize,
+            error: String,
+            notification: &'a Notification,
+        }
+        let dead_letter = DeadLetter {
+            timestamp: OffsetDateTime::now_utc().unix_timestamp() as usize,
+            notifier: name,
+            attempts: delivery.attempts,
+            queued_at: delivery.queued_at,
+            error: err.to_string(),
+            notification: &delivery.notification,
+        };
+        let mut writer = self.writer.lock().unwrap();
+        serde_json::to_writer(&mut *writer, &dead_letter)?;
+        writer.write_all(b"\n")?;
+        writer.flush()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,212 @@
+// CCheck: utility for scanning and probing minecraft servers
+// Copyright (C) 2022 cleonyc
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//! User supplied templates for the messages notifiers send
+use std::str::FromStr;
+
+use handlebars::{handlebars_helper, no_escape, Handlebars};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::Notification;
+use crate::{format::ccheck::Server, mode::events::Event};
+
+/// Templates for alert messages, unset ones keep each notifier's default
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AlertTemplates {
+    pub title: Option<String>,
+    pub body: Option<String>,
+    #[serde(default)]
+    pub fields: Vec<Field>,
+}
+
+/// A named field of a message, `<name>=<template>` on the command line
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Field {
+    pub name: String,
+    pub value: String,
+}
+impl FromStr for Field {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, value) = s
+            .split_once('=')
+            .ok_or_else(|| format!("invalid field `{s}`, expected <name>=<template>"))?;
+        Ok(Self {
+            name: name.trim().to_string(),
+            value: value.to_string(),
+        })
+    }
+}
+
+/// A message rendered from [`AlertTemplates`]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Message {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub body: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fields: Vec<Field>,
+}
+impl Message {
+    /// Whether any template applied, i.e. notifiers should use this instead of their defaults
+    pub fn is_empty(&self) -> bool {
+        self.title.is_none() && self.body.is_none() && self.fields.is_empty()
+    }
+    /// Title, or the event's summary
+    pub fn title(&self, notification: &Notification) -> String {
+        self.title
+            .clone()
+            .unwrap_or_else(|| notification.event.to_string())
+    }
+    /// Title (if there is one) and text, for notifiers without a separate title
+    pub fn full_text(&self, notification: &Notification) -> String {
+        match &self.title {
+            Some(title) => format!("{title}\n{}", self.text(notification)),
+            None => self.text(notification),
+        }
+    }
+    /// Body followed by the fields as `name: value` lines, for notifiers that only take text
+    pub fn text(&self, notification: &Notification) -> String {
+        let mut text = self
+            .body
+            .clone()
+            .unwrap_or_else(|| notification.event.to_string());
+        for field in &self.fields {
+            text.push_str(&format!("\n{}: {}", field.name, field.value));
+        }
+        text
+    }
+}
+
+handlebars_helper!(join: |values: Value, separator: str| {
+    values
+        .as_array()
+        .map(|values| {
+            values
+                .iter()
+                .map(|v| v.as_str().map(str::to_string).unwrap_or_else(|| v.to_string()))
+                .collect::<Vec<_>>()
+                .join(separator)
+        })
+        .unwrap_or_default()
+});
+
+/// Compiled [`AlertTemplates`]
+#[derive(Debug)]
+pub struct Templates {
+    registry: Handlebars<'static>,
+    fields: Vec<String>,
+}
+impl Templates {
+    pub fn new(templates: &AlertTemplates) -> anyhow::Result<Self> {
+        let mut registry = Handlebars::new();
+        // messages aren't HTML
+        registry.register_escape_fn(no_escape);
+        registry.register_helper("join", Box::new(join));
+        let mut register = |name: &str, template: &str| {
+            registry
+                .register_template_string(name, template)
+                .map_err(|e| anyhow::anyhow!("invalid {name} template: {e}"))
+        };
+        if let Some(title) = &templates.title {
+            register("title", title)?;
+        }
+        if let Some(body) = &templates.body {
+            register("body", body)?;
+        }
+        for field in &templates.fields {
+            register(&format!("field `{}`", field.name), &field.value)?;
+        }
+        Ok(Self {
+            registry,
+            fields: templates.fields.iter().map(|f| f.name.clone()).collect(),
+        })
+    }
+    /// Renders the templates for `notification`, leaving out the ones that weren't given
+    pub fn render(&self, notification: &Notification) -> anyhow::Result<Message> {
+        let context = Context::new(notification);
+        let render = |name: &str| -> anyhow::Result<Option<String>> {
+            match self.registry.has_template(name) {
+                true => Ok(Some(self.registry.render(name, &context)?)),
+                false => Ok(None),
+            }
+        };
+        let mut fields = vec![];
+        for name in &self.fields {
+            if let Some(value) = render(&format!("field `{name}`"))? {
+                fields.push(Field {
+                    name: name.clone(),
+                    value,
+                });
+            }
+        }
+        Ok(Message {
+            title: render("title")?,
+            body: render("body")?,
+            fields,
+        })
+    }
+}
+
+/// What templates can refer to
+#[derive(Serialize)]
+struct Context<'a> {
+    /// The default one line description of the event
+    summary: String,
+    event: &'a Event,
+    ip: String,
+    port: u16,
+    /// The response that triggered the event, `null` if the server didn't answer
+    response: Option<ServerContext<'a>>,
+    /// The response from the cycle before
+    previous: Option<ServerContext<'a>>,
+}
+impl<'a> Context<'a> {
+    fn new(notification: &'a Notification) -> Self {
+        let event = &notification.event;
+        Self {
+            summary: event.to_string(),
+            event,
+            ip: event.server.0.to_string(),
+            port: event.server.1,
+            response: notification.response.as_ref().map(ServerContext::new),
+            previous: notification.previous.as_ref().map(ServerContext::new),
+        }
+    }
+}
+
+/// A [`Server`] with some of its fields in a more convenient form
+#[derive(Serialize)]
+struct ServerContext<'a> {
+    #[serde(flatten)]
+    server: &'a Server,
+    /// MOTD as plain text
+    motd_text: String,
+    /// Names of the sampled players
+    player_names: Vec<&'a str>,
+    favicon_hash: String,
+}
+impl<'a> ServerContext<'a> {
+    fn new(server: &'a Server) -> Self {
+        Self {
+            server,
+            motd_text: server.motd.to_plain(),
+            player_names: server.players.iter().map(|p| p.username.as_str()).collect(),
+            favicon_hash: server.favicon_hash(),
+        }
+    }
+}
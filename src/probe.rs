@@ -0,0 +1,486 @@
+// CCheck: utility for scanning and probing minecraft servers
+// Copyright (C) 2022 cleonyc
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    future::Future,
+    io,
+    net::IpAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use strum_macros::{Display as StrumDisplay, EnumString};
+use time::OffsetDateTime;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    time::Sleep,
+};
+
+use crate::{
+    adapters::CCheckResponse,
+    dial::{proxy_protocol::ProxyProtocol, Dialer},
+};
+
+// errno values for running out of file descriptors and buffer space
+const ENFILE: i32 = 23;
+const EMFILE: i32 = 24;
+#[cfg(target_os = "linux")]
+const ENOBUFS: i32 = 105;
+#[cfg(not(target_os = "linux"))]
+const ENOBUFS: i32 = 55;
+
+/// Why probing a server failed
+#[derive(Debug)]
+pub enum ProbeError {
+    /// TCP connect did not complete in time, usually a firewall silently dropping packets
+    ConnectTimeout,
+    /// The host actively refused the connection
+    Refused,
+    /// The connection was reset by the peer
+    Reset,
+    /// No route to the host or network
+    Unreachable(io::Error),
+    /// Connected, but the status exchange did not complete in time
+    StatusTimeout,
+    /// Something answered, but it does not speak the minecraft protocol
+    NotMinecraft,
+    /// The server looked like minecraft but its status response could not be parsed
+    Protocol,
+    /// Any other I/O error
+    Io(io::Error),
+}
+impl ProbeError {
+    /// Whether the probe failed because this machine ran out of something
+    /// (file descriptors, ephemeral ports, buffers) rather than because of the target
+    pub fn is_local(&self) -> bool {
+        match self {
+            ProbeError::Unreachable(err) | ProbeError::Io(err) => {
+                err.kind() == io::ErrorKind::AddrNotAvailable
+                    || matches!(err.raw_os_error(), Some(EMFILE | ENFILE | ENOBUFS))
+            }
+            _ => false,
+        }
+    }
+    pub fn kind(&self) -> ProbeErrorKind {
+        match self {
+            ProbeError::ConnectTimeout => ProbeErrorKind::ConnectTimeout,
+            ProbeError::Refused => ProbeErrorKind::Refused,
+            ProbeError::Reset => ProbeErrorKind::Reset,
+            ProbeError::Unreachable(_) => ProbeErrorKind::Unreachable,
+            ProbeError::StatusTimeout => ProbeErrorKind::StatusTimeout,
+            ProbeError::NotMinecraft => ProbeErrorKind::NotMinecraft,
+            ProbeError::Protocol => ProbeErrorKind::Protocol,
+            ProbeError::Io(_) => ProbeErrorKind::Io,
+        }
+    }
+}
+impl From<io::Error> for ProbeError {
+    fn from(err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::ConnectionRefused => ProbeError::Refused,
+            io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe => ProbeError::Reset,
+            io::ErrorKind::HostUnreachable | io::ErrorKind::NetworkUnreachable => {
+                ProbeError::Unreachable(err)
+            }
+            _ => ProbeError::Io(err),
+        }
+    }
+}
+impl Display for ProbeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProbeError::ConnectTimeout => write!(f, "connect timed out"),
+            ProbeError::Refused => write!(f, "connection refused"),
+            ProbeError::Reset => write!(f, "connection reset"),
+            ProbeError::Unreachable(err) => write!(f, "unreachable: {err}"),
+            ProbeError::StatusTimeout => write!(f, "status request timed out"),
+            ProbeError::NotMinecraft => write!(f, "not a minecraft server"),
+            ProbeError::Protocol => write!(f, "invalid status response"),
+            ProbeError::Io(err) => write!(f, "{err}"),
+        }
+    }
+}
+impl std::error::Error for ProbeError {}
+
+/// Category of a [`ProbeError`], used for summaries and failure output
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, EnumString, StrumDisplay,
+)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case", ascii_case_insensitive)]
+pub enum ProbeErrorKind {
+    ConnectTimeout,
+    Refused,
+    Reset,
+    Unreachable,
+    StatusTimeout,
+    NotMinecraft,
+    Protocol,
+    Io,
+}
+impl ProbeErrorKind {
+    pub const ALL: [ProbeErrorKind; 8] = [
+        ProbeErrorKind::ConnectTimeout,
+        ProbeErrorKind::Refused,
+        ProbeErrorKind::Reset,
+        ProbeErrorKind::Unreachable,
+        ProbeErrorKind::StatusTimeout,
+        ProbeErrorKind::NotMinecraft,
+        ProbeErrorKind::Protocol,
+        ProbeErrorKind::Io,
+    ];
+}
+
+/// Failed probe counts per category, shared between workers
+#[derive(Debug, Default)]
+pub struct FailureCounts([AtomicUsize; ProbeErrorKind::ALL.len()]);
+impl FailureCounts {
+    pub fn add(&self, kind: ProbeErrorKind) {
+        self.0[kind as usize].fetch_add(1, Ordering::Relaxed);
+    }
+    pub fn snapshot(&self) -> HashMap<ProbeErrorKind, usize> {
+        ProbeErrorKind::ALL
+            .iter()
+            .map(|kind| (*kind, self.0[*kind as usize].load(Ordering::Relaxed)))
+            .filter(|(_, count)| *count > 0)
+            .collect()
+    }
+}
+impl From<&HashMap<ProbeErrorKind, usize>> for FailureCounts {
+    fn from(counts: &HashMap<ProbeErrorKind, usize>) -> Self {
+        let failures = FailureCounts::default();
+        for (kind, count) in counts {
+            failures.0[*kind as usize].store(*count, Ordering::Relaxed);
+        }
+        failures
+    }
+}
+
+/// Formats a per-category failure count, e.g. `3 connect_timeout, 1 refused`
+pub fn summarize(failures: &HashMap<ProbeErrorKind, usize>) -> String {
+    let mut counts = failures.iter().collect::<Vec<_>>();
+    counts.sort_by(|a, b| b.1.cmp(a.1));
+    counts
+        .iter()
+        .map(|(kind, count)| format!("{count} {kind}"))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+/// When a server was probed and how long each step took
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct ProbeTiming {
+    /// Unix timestamp of when the probe started
+    pub probed_at: usize,
+    /// TCP connect time in milliseconds
+    pub connect_ms: f64,
+    /// Status request round trip in milliseconds, including the server building its response
+    pub status_ms: f64,
+    /// Ping/pong round trip in milliseconds.
+    /// `None` if the server did not answer the ping packet (e.g. legacy servers)
+    pub latency_ms: Option<f64>,
+}
+
+/// Deadlines for the steps of a probe
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Timeouts {
+    /// For the TCP connect
+    #[serde(rename = "connect_ms", with = "millis")]
+    pub connect: Duration,
+    /// From connecting until the server sends its first byte
+    #[serde(rename = "first_byte_ms", with = "millis")]
+    pub first_byte: Duration,
+    /// Longest gap between two reads once the server started answering
+    #[serde(rename = "read_idle_ms", with = "millis")]
+    pub read_idle: Duration,
+    /// For the whole probe, connect included
+    #[serde(rename = "total_ms", with = "millis")]
+    pub total: Duration,
+}
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            connect: Duration::from_millis(1000),
+            first_byte: Duration::from_millis(1000),
+            read_idle: Duration::from_millis(500),
+            total: Duration::from_millis(3000),
+        }
+    }
+}
+mod millis {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::time::Duration;
+
+    pub fn serialize<S: Serializer>(d: &Duration, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_u64(d.as_millis() as u64)
+    }
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Duration, D::Error> {
+        Ok(Duration::from_millis(u64::deserialize(d)?))
+    }
+}
+
+/// When probes start with a PROXY protocol header
+#[derive(Debug, Clone, Copy, Default)]
+pub enum ProxyHeader {
+    #[default]
+    Never,
+    /// Sent to every server
+    Always(ProxyProtocol),
+    /// Only sent to servers that hang up on a probe without one, which are probed again with it
+    Auto(ProxyProtocol),
+}
+
+/// Everything that decides how servers are probed
+#[derive(Debug, Clone)]
+pub struct Prober {
+    pub timeouts: Timeouts,
+    pub dialer: Arc<Dialer>,
+    pub proxy_header: ProxyHeader,
+}
+impl Prober {
+    /// Pings a single server, categorizing anything that goes wrong
+    pub async fn probe(&self, addr: (IpAddr, u16)) -> Result<CCheckResponse, ProbeError> {
+        match self.proxy_header {
+            ProxyHeader::Never => self.probe_with(addr, None).await.map_err(|f| f.err),
+            ProxyHeader::Always(version) => self
+                .probe_with(addr, Some(version))
+                .await
+                .map_err(|f| f.err),
+            ProxyHeader::Auto(version) => match self.probe_with(addr, None).await {
+                Err(ProbeFailure { hung_up: true, .. }) => self
+                    .probe_with(addr, Some(version))
+                    .await
+                    .map_err(|f| f.err),
+                res => res.map_err(|f| f.err),
+            },
+        }
+    }
+    /// Pings a server over a single connection, starting it with a PROXY protocol header if given
+    async fn probe_with(
+        &self,
+        addr: (IpAddr, u16),
+        proxy_header: Option<ProxyProtocol>,
+    ) -> Result<CCheckResponse, ProbeFailure> {
+        let timeouts = self.timeouts;
+        let probed_at = OffsetDateTime::now_utc().unix_timestamp() as usize;
+        let start = Instant::now();
+        let deadline = tokio::time::Instant::from_std(start + timeouts.total);
+        let connect_deadline =
+            deadline.min(tokio::time::Instant::from_std(start + timeouts.connect));
+        let mut stream =
+            match tokio::time::timeout_at(connect_deadline, self.dialer.connect(addr.into())).await
+            {
+                Ok(s) => s.map_err(ProbeError::from)?,
+                Err(_) => return Err(ProbeError::ConnectTimeout.into()),
+            };
+        let connected = Instant::now();
+        if let Some(version) = proxy_header {
+            let local = stream.local_addr().map_err(ProbeError::from)?;
+            let header = version.header(local, addr.into());
+            stream.write_all(&header).await.map_err(ProbeError::from)?;
+        }
+        let mut stream = ProbeStream::new(stream, timeouts.first_byte, timeouts.read_idle);
+        match tokio::time::timeout_at(
+            deadline,
+            craftping::tokio::ping(&mut stream, &addr.0.to_string(), addr.1),
+        )
+        .await
+        {
+            Ok(Ok(res)) => {
+                let status_ms = as_ms(connected.elapsed());
+                let latency_ms = tokio::time::timeout_at(deadline, ping_pong(&mut stream))
+                    .await
+                    .ok()
+                    .and_then(|r| r.ok())
+                    .map(as_ms);
+                let mut resp: CCheckResponse = res.into();
+                resp.timing = Some(ProbeTiming {
+                    probed_at,
+                    connect_ms: as_ms(connected - start),
+                    status_ms,
+                    latency_ms,
+                });
+                resp.proxy_protocol = proxy_header.is_some();
+                Ok(resp)
+            }
+            // craftping falls back to the legacy ping on the same stream, so a
+            // broken modern response usually surfaces as an I/O error from the retry
+            Ok(Err(craftping::Error::Io(err))) => {
+                let hung_up = stream.head.is_empty()
+                    && matches!(
+                        err.kind(),
+                        io::ErrorKind::UnexpectedEof
+                            | io::ErrorKind::ConnectionReset
+                            | io::ErrorKind::ConnectionAborted
+                            | io::ErrorKind::BrokenPipe
+                    );
+                let err = if err.kind() == io::ErrorKind::TimedOut {
+                    ProbeError::StatusTimeout
+                } else if !stream.head.is_empty() || err.kind() == io::ErrorKind::UnexpectedEof {
+                    stream.classify()
+                } else {
+                    err.into()
+                };
+                Err(ProbeFailure { err, hung_up })
+            }
+            Ok(Err(craftping::Error::UnsupportedProtocol)) => Err(stream.classify().into()),
+            Err(_) => Err(ProbeError::StatusTimeout.into()),
+        }
+    }
+}
+
+/// A failed connection to a server
+struct ProbeFailure {
+    err: ProbeError,
+    /// The server closed the connection without sending anything,
+    /// which is what servers expecting a PROXY protocol header do
+    hung_up: bool,
+}
+impl From<ProbeError> for ProbeFailure {
+    fn from(err: ProbeError) -> Self {
+        Self {
+            err,
+            hung_up: false,
+        }
+    }
+}
+
+fn as_ms(d: Duration) -> f64 {
+    d.as_secs_f64() * 1000.0
+}
+
+/// Sends the status protocol's Ping packet and times the Pong.
+/// Must be called right after a successful (modern) status request.
+async fn ping_pong<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S) -> io::Result<Duration> {
+    let payload = OffsetDateTime::now_utc().unix_timestamp_nanos() as i64;
+    // packet length 9, packet id 0x01, i64 payload
+    let mut packet = vec![9, 0x01];
+    packet.extend_from_slice(&payload.to_be_bytes());
+    let sent = Instant::now();
+    stream.write_all(&packet).await?;
+    stream.flush().await?;
+    let len = read_varint(stream).await?;
+    let id = read_varint(stream).await?;
+    let mut pong = [0u8; 8];
+    stream.read_exact(&mut pong).await?;
+    let elapsed = sent.elapsed();
+    if len != 9 || id != 0x01 || i64::from_be_bytes(pong) != payload {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid pong"));
+    }
+    Ok(elapsed)
+}
+async fn read_varint<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<i32> {
+    let mut result = 0;
+    for i in 0..5 {
+        let byte = stream.read_u8().await?;
+        result |= ((byte & 0x7f) as i32) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok(result);
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "varint too long",
+    ))
+}
+
+const HEAD_LEN: usize = 6;
+
+/// Wraps a stream, remembering the first bytes the server sent so failed pings
+/// can be told apart as "not minecraft" or "broken minecraft".
+/// Reads fail with `TimedOut` if the server takes longer than `first_byte` to start
+/// answering, or goes quiet for longer than `read_idle` after that.
+struct ProbeStream<S> {
+    inner: S,
+    head: Vec<u8>,
+    read_idle: Duration,
+    idle: Pin<Box<Sleep>>,
+}
+impl<S> ProbeStream<S> {
+    fn new(inner: S, first_byte: Duration, read_idle: Duration) -> Self {
+        Self {
+            inner,
+            head: Vec::with_capacity(HEAD_LEN),
+            read_idle,
+            idle: Box::pin(tokio::time::sleep(first_byte)),
+        }
+    }
+    fn classify(&self) -> ProbeError {
+        // legacy kick packet
+        if self.head.first() == Some(&0xff) {
+            return ProbeError::Protocol;
+        }
+        // modern status response: varint packet length followed by packet id 0
+        match self.head.iter().position(|b| b & 0x80 == 0) {
+            Some(i) if i < 5 && self.head.get(i + 1) == Some(&0x00) => ProbeError::Protocol,
+            _ => ProbeError::NotMinecraft,
+        }
+    }
+}
+impl<S: AsyncRead + Unpin> AsyncRead for ProbeStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        match Pin::new(&mut self.inner).poll_read(cx, buf) {
+            Poll::Ready(Ok(())) if buf.filled().len() > before => {
+                let wanted = HEAD_LEN - self.head.len();
+                if wanted > 0 {
+                    let read = &buf.filled()[before..];
+                    let read = &read[..read.len().min(wanted)];
+                    self.head.extend_from_slice(read);
+                }
+                let idle_until = tokio::time::Instant::now() + self.read_idle;
+                self.idle.as_mut().reset(idle_until);
+                Poll::Ready(Ok(()))
+            }
+            Poll::Pending => match self.idle.as_mut().poll(cx) {
+                Poll::Ready(()) => Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "server stopped answering",
+                ))),
+                Poll::Pending => Poll::Pending,
+            },
+            res => res,
+        }
+    }
+}
+impl<S: AsyncWrite + Unpin> AsyncWrite for ProbeStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,426 @@
+// CCheck: utility for scanning and probing minecraft servers
+// Copyright (C) 2022 cleonyc
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//! Uptime summaries from the checks `monitor --stats` keeps
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufWriter, Write},
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use owo_colors::OwoColorize;
+use rusqlite::{params, Connection, OpenFlags};
+use serde::Serialize;
+use strum_macros::EnumString;
+use time::OffsetDateTime;
+
+use crate::mode::events::{format_duration, format_timestamp};
+
+#[derive(Debug, Clone, Copy, EnumString)]
+pub enum ReportFormat {
+    #[strum(ascii_case_insensitive)]
+    Table,
+    #[strum(ascii_case_insensitive)]
+    Json,
+    #[strum(ascii_case_insensitive)]
+    Csv,
+}
+
+/// What the report lists
+#[derive(Debug, Clone, Copy)]
+pub enum ReportKind {
+    /// Uptime, outages, latency and players per server
+    Summary,
+    /// Every outage
+    Outages,
+    /// Checks per server summed up in buckets of this many seconds
+    Series(usize),
+}
+
+/// A server or all ports of a host, `ip` or `ip:port` on the command line
+#[derive(Debug, Clone, Copy)]
+pub struct TargetFilter {
+    ip: IpAddr,
+    port: Option<u16>,
+}
+impl TargetFilter {
+    fn matches(&self, ip: IpAddr, port: u16) -> bool {
+        self.ip == ip && self.port.is_none_or(|p| p == port)
+    }
+}
+impl FromStr for TargetFilter {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(addr) = s.parse::<SocketAddr>() {
+            return Ok(Self {
+                ip: addr.ip(),
+                port: Some(addr.port()),
+            });
+        }
+        let ip = s
+            .parse()
+            .map_err(|_| format!("invalid server `{s}`, expected <ip> or <ip>:<port>"))?;
+        Ok(Self { ip, port: None })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ReportOptions {
+    pub kind: ReportKind,
+    /// Seconds before `end` the report covers
+    pub window: usize,
+    /// Unix timestamp the report ends at, now if `None`
+    pub end: Option<usize>,
+    /// Servers to report on, all of them if empty
+    pub targets: Vec<TargetFilter>,
+    pub format: ReportFormat,
+    /// File to write the report to instead of stdout
+    pub output: Option<PathBuf>,
+}
+
+/// Availability of one server over the window
+#[derive(Debug, Serialize)]
+struct Uptime {
+    ip: IpAddr,
+    port: u16,
+    /// Percentage of the monitored time the server wasn't in an outage
+    uptime: f64,
+    checks: usize,
+    online_checks: usize,
+    /// Outages overlapping the window
+    outages: usize,
+    /// Seconds spent in outages within the window
+    downtime: usize,
+    /// Mean ping round trip
+    latency_ms: Option<f64>,
+    avg_players: Option<f64>,
+    max_players: Option<usize>,
+    last_check: usize,
+    /// Whether the last check succeeded
+    online: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct Outage {
+    ip: IpAddr,
+    port: u16,
+    started_at: usize,
+    /// `None` while the server is still down
+    ended_at: Option<usize>,
+    /// Seconds, up to the end of the window for outages still going on
+    duration: usize,
+    error: String,
+}
+
+/// Checks of one server within one bucket
+#[derive(Debug, Serialize)]
+struct SeriesPoint {
+    ip: IpAddr,
+    port: u16,
+    /// Unix timestamp the bucket starts at
+    timestamp: usize,
+    checks: usize,
+    online_checks: usize,
+    latency_ms: Option<f64>,
+    avg_players: Option<f64>,
+    max_players: Option<usize>,
+}
+
+/// Reads the statistics in the state database at `path` and writes the report
+pub fn run(path: &Path, options: &ReportOptions) -> anyhow::Result<()> {
+    let unreadable = |e| anyhow::anyhow!("failed to read {}: {e}", path.display());
+    let conn =
+        Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY).map_err(unreadable)?;
+    let has_stats: bool = conn
+        .query_row(
+            "SELECT COUNT(*) = 2 FROM sqlite_master WHERE type = 'table' AND name IN ('monitor_checks', 'monitor_outages')",
+            [],
+            |row| row.get(0),
+        )
+        .map_err(unreadable)?;
+    if !has_stats {
+        anyhow::bail!(
+            "{} has no uptime statistics, run `monitor --state {0} --stats` first",
+            path.display()
+        );
+    }
+    let end = options
+        .end
+        .unwrap_or_else(|| OffsetDateTime::now_utc().unix_timestamp() as usize);
+    let start = end.saturating_sub(options.window);
+    let wanted = |ip: IpAddr, port: u16| {
+        options.targets.is_empty() || options.targets.iter().any(|t| t.matches(ip, port))
+    };
+    let outages = outages(&conn, start, end)?
+        .into_iter()
+        .filter(|o| wanted(o.ip, o.port))
+        .collect::<Vec<_>>();
+    let mut out: Box<dyn Write> = match &options.output {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(io::stdout().lock()),
+    };
+    let title = format!(
+        "{} to {} ({})",
+        format_timestamp(start),
+        format_timestamp(end),
+        format_duration(options.window)
+    );
+    match options.kind {
+        ReportKind::Summary => {
+            let mut rows = summary(&conn, start, end, &outages)?;
+            rows.retain(|row| wanted(row.ip, row.port));
+            rows.sort_by(|a, b| a.uptime.total_cmp(&b.uptime).then(a.ip.cmp(&b.ip)));
+            write(&mut out, options.format, &rows, |out| {
+                writeln!(out, "{} Uptime from {title}", "::".blue().bold())?;
+                writeln!(
+                    out,
+                    "   {:<47} {:>8} {:>8} {:>9} {:>9} {:>9}  now",
+                    "server", "uptime", "outages", "downtime", "latency", "players"
+                )?;
+                for row in &rows {
+                    let players = match (row.avg_players, row.max_players) {
+                        (Some(avg), Some(max)) => format!("{avg:.1}/{max}"),
+                        _ => "-".into(),
+                    };
+                    writeln!(
+                        out,
+                        "   {:<47} {:>7.2}% {:>8} {:>9} {:>9} {:>9}  {}",
+                        format!("{}:{}", row.ip, row.port),
+                        row.uptime,
+                        row.outages,
+                        format_duration(row.downtime),
+                        row.latency_ms.map_or("-".into(), |ms| format!("{ms:.1}ms")),
+                        players,
+                        match row.online {
+                            true => "online".green().to_string(),
+                            false => "offline".red().to_string(),
+                        }
+                    )?;
+                }
+                Ok(())
+            })?;
+        }
+        ReportKind::Outages => {
+            let rows = outages
+                .into_iter()
+                .map(|o| Outage {
+                    duration: o.ended_at.unwrap_or(end).saturating_sub(o.started_at),
+                    ..o
+                })
+                .collect::<Vec<_>>();
+            write(&mut out, options.format, &rows, |out| {
+                writeln!(out, "{} Outages from {title}", "::".blue().bold())?;
+                for row in &rows {
+                    writeln!(
+                        out,
+                        "   {:<47} {} - {} ({}, {})",
+                        format!("{}:{}", row.ip, row.port),
+                        format_timestamp(row.started_at),
+                        row.ended_at.map_or("ongoing".into(), format_timestamp),
+                        format_duration(row.duration),
+                        row.error
+                    )?;
+                }
+                Ok(())
+            })?;
+        }
+        ReportKind::Series(bucket) => {
+            let mut rows = series(&conn, start, end, bucket.max(1))?;
+            rows.retain(|row| wanted(row.ip, row.port));
+            write(&mut out, options.format, &rows, |out| {
+                writeln!(out, "{} Checks from {title}", "::".blue().bold())?;
+                for row in &rows {
+                    writeln!(
+                        out,
+                        "   {:<47} {} {:>5}/{:<5} {:>9} {:>9}",
+                        format!("{}:{}", row.ip, row.port),
+                        format_timestamp(row.timestamp),
+                        row.online_checks,
+                        row.checks,
+                        row.latency_ms.map_or("-".into(), |ms| format!("{ms:.1}ms")),
+                        row.avg_players
+                            .map_or("-".into(), |avg| format!("{avg:.1}"))
+                    )?;
+                }
+                Ok(())
+            })?;
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Writes `rows` as JSON or CSV, or as a table with `table`
+fn write<T: Serialize>(
+    out: &mut Box<dyn Write>,
+    format: ReportFormat,
+    rows: &[T],
+    table: impl FnOnce(&mut Box<dyn Write>) -> io::Result<()>,
+) -> anyhow::Result<()> {
+    match format {
+        ReportFormat::Table => table(out)?,
+        ReportFormat::Json => {
+            serde_json::to_writer_pretty(&mut *out, rows)?;
+            writeln!(out)?;
+        }
+        ReportFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(out);
+            for row in rows {
+                writer.serialize(row)?;
+            }
+            writer.flush()?;
+        }
+    }
+    Ok(())
+}
+
+fn server(ip: String, port: u16) -> rusqlite::Result<(IpAddr, u16)> {
+    let ip = ip.parse().map_err(|e| {
+        rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(e))
+    })?;
+    Ok((ip, port))
+}
+
+/// Outages overlapping `start..=end`, oldest first
+fn outages(conn: &Connection, start: usize, end: usize) -> anyhow::Result<Vec<Outage>> {
+    let mut stmt = conn.prepare(
+        "SELECT ip, port, started_at, ended_at, error FROM monitor_outages
+         WHERE started_at <= ?2 AND (ended_at IS NULL OR ended_at >= ?1)
+         ORDER BY started_at",
+    )?;
+    let outages = stmt
+        .query_map(params![start, end], |row| {
+            let (ip, port) = server(row.get(0)?, row.get(1)?)?;
+            Ok(Outage {
+                ip,
+                port,
+                started_at: row.get(2)?,
+                ended_at: row.get(3)?,
+                duration: 0,
+                error: row.get(4)?,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(outages)
+}
+
+/// Uptime of every server checked within `start..=end`.
+/// The monitored time runs from the window's start (or the first check ever) to the last check in the window
+fn summary(
+    conn: &Connection,
+    start: usize,
+    end: usize,
+    outages: &[Outage],
+) -> anyhow::Result<Vec<Uptime>> {
+    let mut by_server: HashMap<(IpAddr, u16), Vec<&Outage>> = HashMap::new();
+    for outage in outages {
+        by_server
+            .entry((outage.ip, outage.port))
+            .or_default()
+            .push(outage);
+    }
+    let mut stmt = conn.prepare(
+        "SELECT ip, port, MIN(timestamp),
+            SUM(timestamp >= ?1), SUM(online AND timestamp >= ?1),
+            AVG(CASE WHEN timestamp >= ?1 THEN latency_ms END),
+            AVG(CASE WHEN timestamp >= ?1 THEN players END),
+            MAX(CASE WHEN timestamp >= ?1 THEN players END),
+            MAX(timestamp)
+         FROM monitor_checks WHERE timestamp <= ?2
+         GROUP BY ip, port HAVING SUM(timestamp >= ?1) > 0",
+    )?;
+    let mut last_stmt = conn.prepare(
+        // several checks can share a second, the one written last is the latest
+        "SELECT online FROM monitor_checks WHERE ip = ?1 AND port = ?2 AND timestamp = ?3
+         ORDER BY rowid DESC LIMIT 1",
+    )?;
+    let mut rows = vec![];
+    let mut query = stmt.query(params![start, end])?;
+    while let Some(row) = query.next()? {
+        let ip: String = row.get(0)?;
+        let (ip, port) = server(ip, row.get(1)?)?;
+        let first: usize = row.get(2)?;
+        let checks: usize = row.get(3)?;
+        let online_checks: usize = row.get(4)?;
+        let last_check: usize = row.get(8)?;
+        let (from, to) = (start.max(first), last_check);
+        let server_outages = by_server
+            .get(&(ip, port))
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        let downtime = server_outages
+            .iter()
+            .map(|o| {
+                let ended = o.ended_at.unwrap_or(to).min(to);
+                ended.saturating_sub(o.started_at.max(from))
+            })
+            .sum::<usize>();
+        let uptime = match to > from {
+            true => 100. * (1. - downtime as f64 / (to - from) as f64),
+            false => 100. * online_checks as f64 / checks as f64,
+        };
+        let online =
+            last_stmt.query_row(params![ip.to_string(), port, last_check], |row| row.get(0))?;
+        rows.push(Uptime {
+            ip,
+            port,
+            uptime,
+            checks,
+            online_checks,
+            outages: server_outages.len(),
+            downtime,
+            latency_ms: row.get(5)?,
+            avg_players: row.get(6)?,
+            max_players: row.get(7)?,
+            last_check,
+            online,
+        });
+    }
+    Ok(rows)
+}
+
+/// Checks within `start..=end` summed up per server in buckets of `bucket` seconds
+fn series(
+    conn: &Connection,
+    start: usize,
+    end: usize,
+    bucket: usize,
+) -> anyhow::Result<Vec<SeriesPoint>> {
+    let mut stmt = conn.prepare(
+        "SELECT ip, port, timestamp / ?3 * ?3 AS bucket, COUNT(*), SUM(online),
+            AVG(latency_ms), AVG(players), MAX(players)
+         FROM monitor_checks WHERE timestamp BETWEEN ?1 AND ?2
+         GROUP BY ip, port, bucket ORDER BY ip, port, bucket",
+    )?;
+    let points = stmt
+        .query_map(params![start, end, bucket], |row| {
+            let (ip, port) = server(row.get(0)?, row.get(1)?)?;
+            Ok(SeriesPoint {
+                ip,
+                port,
+                timestamp: row.get(2)?,
+                checks: row.get(3)?,
+                online_checks: row.get(4)?,
+                latency_ms: row.get(5)?,
+                avg_players: row.get(6)?,
+                max_players: row.get(7)?,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(points)
+}
@@ -0,0 +1,73 @@
+// CCheck: utility for scanning and probing minecraft servers
+// Copyright (C) 2022 cleonyc
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    time::Duration,
+};
+
+use crate::probe::ProbeErrorKind;
+
+/// When and how often failed probes are tried again
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Total attempts per target, including the first one
+    pub max_attempts: u32,
+    /// Failure categories worth trying again, the rest are final
+    pub retry_on: Vec<ProbeErrorKind>,
+    /// Delay before the first retry, doubled for every retry after it
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+}
+impl Default for RetryPolicy {
+    /// A single attempt, i.e. no retries
+    fn default() -> Self {
+        Self {
+            max_attempts: 1,
+            retry_on: Self::DEFAULT_RETRY_ON.to_vec(),
+            backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(10),
+        }
+    }
+}
+impl RetryPolicy {
+    /// Failures that are commonly caused by packet loss rather than the target
+    pub const DEFAULT_RETRY_ON: [ProbeErrorKind; 4] = [
+        ProbeErrorKind::ConnectTimeout,
+        ProbeErrorKind::StatusTimeout,
+        ProbeErrorKind::Reset,
+        ProbeErrorKind::Refused,
+    ];
+    /// Whether a probe that failed with `kind` on attempt number `attempt` (starting at 1) should be retried
+    pub fn should_retry(&self, kind: ProbeErrorKind, attempt: u32) -> bool {
+        attempt < self.max_attempts && self.retry_on.contains(&kind)
+    }
+    /// How long to wait before retrying after attempt number `attempt` failed.
+    /// Exponential, with "equal jitter" so retries of targets that failed together spread out.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let exp = self
+            .backoff
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_backoff);
+        exp / 2 + exp.mul_f64(jitter() / 2.)
+    }
+}
+/// Random number in `[0, 1)`, good enough for spreading out retries
+pub fn jitter() -> f64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(0);
+    (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64
+}
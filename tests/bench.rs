@@ -0,0 +1,164 @@
+// CCheck: utility for scanning and probing minecraft servers
+// Copyright (C) 2022 cleonyc
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//! Scan throughput against a fake server, run with `cargo test --release --test bench -- --ignored --nocapture`.
+//! `CCHECK_BENCH_TARGETS`, `CCHECK_BENCH_WORKERS` (comma separated) and `CCHECK_BENCH_LATENCY` (milliseconds)
+//! change the defaults of 10000 targets, 10,100,1000 workers and 50ms of latency.
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    process::Stdio,
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    process::Command,
+};
+
+const STATUS: &str = r#"{"version":{"name":"ccheck bench","protocol":763},"players":{"max":20,"online":1,"sample":[{"name":"CCheck","id":"00000000-0000-0000-0000-000000000000"}]},"description":{"text":"ccheck bench"}}"#;
+
+fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    std::env::var(name)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
+
+/// Scans copies of a fake server once per worker count, printing how many servers per second each run managed.
+/// The fake server waits before answering, like a real server across the internet would.
+#[tokio::test(flavor = "multi_thread")]
+#[ignore]
+async fn scan_throughput() {
+    let targets: usize = env_or("CCHECK_BENCH_TARGETS", 10000);
+    let latency = Duration::from_millis(env_or("CCHECK_BENCH_LATENCY", 50));
+    let workers: Vec<usize> = std::env::var("CCHECK_BENCH_WORKERS")
+        .unwrap_or_else(|_| "10,100,1000".to_string())
+        .split(',')
+        .map(|w| w.trim().parse().expect("invalid CCHECK_BENCH_WORKERS"))
+        .collect();
+
+    let listener = TcpListener::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)))
+        .await
+        .unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let server = tokio::spawn(serve(listener, latency));
+
+    let dir = std::env::temp_dir();
+    let input = dir.join(format!("ccheck-bench-{}.json", std::process::id()));
+    let output = dir.join(format!("ccheck-bench-{}.out.json", std::process::id()));
+    let hosts = vec![
+        serde_json::json!({"ip": "127.0.0.1", "timestamp": "0",
+            "ports": [{"port": port, "proto": "tcp", "status": "open"}]});
+        targets
+    ];
+    std::fs::write(&input, serde_json::to_vec(&hosts).unwrap()).unwrap();
+
+    let mut results = vec![];
+    for &count in &workers {
+        let _ = std::fs::remove_file(&output);
+        let started = Instant::now();
+        let status = Command::new(env!("CARGO_BIN_EXE_ccheck"))
+            .arg("scan")
+            .arg(&input)
+            .arg(&output)
+            .args(["--no-default-excludes", "-w", &count.to_string()])
+            .args(["--timeout", &(3000 + latency.as_millis()).to_string()])
+            .args([
+                "--first-byte-timeout",
+                &(1000 + latency.as_millis()).to_string(),
+            ])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .await
+            .unwrap();
+        assert!(status.success(), "scan with {count} workers failed");
+        results.push((count, started.elapsed()));
+    }
+    server.abort();
+    let _ = std::fs::remove_file(&input);
+    let _ = std::fs::remove_file(&output);
+
+    println!(
+        ":: {} targets, {}ms server latency",
+        targets,
+        latency.as_millis()
+    );
+    for (count, elapsed) in results {
+        println!(
+            "{:>8} workers: {:>8.2}s {:>10.0} servers/s",
+            count,
+            elapsed.as_secs_f64(),
+            targets as f64 / elapsed.as_secs_f64()
+        );
+    }
+}
+
+async fn serve(listener: TcpListener, latency: Duration) {
+    loop {
+        let (stream, _) = listener.accept().await.unwrap();
+        tokio::spawn(async move {
+            // errors just mean the scanner gave up on this connection
+            let _ = handle(stream, latency).await;
+        });
+    }
+}
+
+/// Answers a status request and a ping, the same exchange `probe` performs
+async fn handle(mut stream: TcpStream, latency: Duration) -> std::io::Result<()> {
+    // handshake, then status request
+    read_packet(&mut stream).await?;
+    read_packet(&mut stream).await?;
+    tokio::time::sleep(latency).await;
+    let mut body = vec![0x00];
+    write_varint(&mut body, STATUS.len() as i32);
+    body.extend_from_slice(STATUS.as_bytes());
+    let mut packet = vec![];
+    write_varint(&mut packet, body.len() as i32);
+    packet.extend(body);
+    stream.write_all(&packet).await?;
+    // ping, echoed back as pong
+    let ping = read_packet(&mut stream).await?;
+    let mut packet = vec![];
+    write_varint(&mut packet, ping.len() as i32);
+    packet.extend(ping);
+    stream.write_all(&packet).await?;
+    Ok(())
+}
+async fn read_packet<S: AsyncRead + Unpin>(stream: &mut S) -> std::io::Result<Vec<u8>> {
+    let mut len = 0;
+    for i in 0..5 {
+        let byte = stream.read_u8().await?;
+        len |= ((byte & 0x7f) as usize) << (7 * i);
+        if byte & 0x80 == 0 {
+            break;
+        }
+    }
+    let mut packet = vec![0; len];
+    stream.read_exact(&mut packet).await?;
+    Ok(packet)
+}
+fn write_varint(buf: &mut Vec<u8>, value: i32) {
+    let mut value = value as u32;
+    loop {
+        if value & !0x7f == 0 {
+            buf.push(value as u8);
+            return;
+        }
+        buf.push((value & 0x7f | 0x80) as u8);
+        value >>= 7;
+    }
+}